@@ -1,8 +1,7 @@
-use std::collections::HashMap;
-
+use quote::ToTokens;
 use syn::Result;
 
-use crate::parse;
+use crate::{parse, pretty};
 
 pub struct Model {
     pub items: Vec<Machine>,
@@ -10,44 +9,127 @@ pub struct Model {
 
 pub struct Machine {
     pub vis: syn::Visibility,
+    pub is_async: bool,
+    /// Whether the machine was declared `async machine Name: Send`.
+    pub is_send: bool,
     pub ident: syn::Ident,
     pub methods: Vec<syn::TraitItemMethod>,
+    /// `queue_capacity = N;`, if the machine sets one.
+    pub queue_capacity: Option<syn::Expr>,
     pub state: State,
 }
 
 pub struct State {
     pub ident: syn::Ident,
-    pub states: HashMap<syn::Ident, State>,
+    pub kind: StateKind,
+    pub states: Vec<State>,
+    pub pseudostates: Vec<Pseudostate>,
+    pub exit_points: Vec<ExitPoint>,
     pub regions: Vec<State>,
     pub entry: Option<Box<syn::Expr>>,
     pub exit: Option<Box<syn::Expr>>,
+    pub activity: Option<Activity>,
+    /// Machine embedded by a submachine state, `state S: Machine;`.
+    pub submachine: Option<syn::Path>,
+    /// Events handled by the submachine only, declared with `forward`.
+    pub forwarded_events: Vec<syn::Path>,
     pub initial_transition: Option<Transition>,
+    pub shallow_history: Option<Transition>,
+    pub deep_history: Option<Transition>,
     pub internal_transitions: Vec<Transition>,
+    pub deferred_events: Vec<DeferredEvent>,
     pub out_transitions: Vec<Transition>,
+    /// Transitions leaving the exit points of the state or its sub-states,
+    /// owned like `out_transitions`, with the exit point ending the source
+    /// path.  Only diagrams draw them, as the transitions reaching the exit
+    /// point are joined with them.
+    pub exit_transitions: Vec<Transition>,
+}
+
+/// A do-activity, started once the state is entered and cancelled when it
+/// is left.
+pub struct Activity {
+    pub start: Box<syn::Expr>,
+    pub cancel: Option<Box<syn::Expr>>,
+}
+
+pub struct DeferredEvent {
+    pub event_path: syn::Path,
+    pub event_pat: Option<syn::Pat>,
 }
 
+pub struct Pseudostate {
+    pub ident: syn::Ident,
+    pub kind: PseudostateKind,
+    /// Whether the choice was declared `choice(partial)`, needing no `else`
+    /// branch.
+    pub partial: bool,
+    pub transitions: Vec<Transition>,
+    pub else_transition: Option<Transition>,
+}
+
+/// An exit point, `exit_point Ident;`.  It only gathers the transitions
+/// reaching it until the parent state connects it to a target, which turns
+/// them into transitions leaving the state.
+pub struct ExitPoint {
+    pub ident: syn::Ident,
+    pub incoming: Vec<Transition>,
+    /// Whether the parent state connects the exit point to a target.
+    pub wired: bool,
+}
+
+#[derive(Clone)]
 pub struct Transition {
     pub event_path: Option<syn::Path>,
     pub event_pat: Option<syn::Pat>,
+    /// Delay of a timed transition, taken on the `umlstate::TimerId` event
+    /// of its timer.
+    pub after: Option<Box<syn::Expr>>,
+    pub source_path: Vec<syn::Ident>,
     pub target: Option<syn::Ident>,
+    pub target_path: Vec<syn::Ident>,
+    pub target_history: Option<History>,
     pub action: Option<Box<syn::Expr>>,
     pub guard: Option<Box<syn::Expr>>,
+    /// Whether the transition was joined from the ones reaching and leaving
+    /// an exit point, which diagrams draw apart.
+    pub through_exit_point: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StateKind {
+    Normal,
+    Final,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PseudostateKind {
+    Choice,
+    Junction,
+    /// Entered from outside its state, `Outer + Event => State.EntryPoint;`,
+    /// entering the state at the target of its single transition.
+    EntryPoint,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum History {
+    Shallow,
+    Deep,
 }
 
 pub fn analyze(ast: parse::UmlState) -> Result<Model> {
-    Ok(Model {
-        items: {
-            let mut items = Vec::new();
-            for item in ast.items {
-                items.push(analyze_machine(&item)?);
-            }
-            items
-        },
-    })
+    let mut items = Vec::new();
+    for item in ast.items {
+        items.push(analyze_machine(&item)?);
+    }
+    for machine in &items {
+        check_submachines(&machine.state, &items)?;
+    }
+    Ok(Model { items })
 }
 
 fn analyze_machine(machine: &parse::Machine) -> Result<Machine> {
-    let methods = machine
+    let methods: Vec<_> = machine
         .items
         .iter()
         .filter_map(|i| match i {
@@ -56,7 +138,41 @@ fn analyze_machine(machine: &parse::Machine) -> Result<Machine> {
         })
         .collect();
 
-    let items = machine
+    if let Some((_, send)) = &machine.send {
+        if send != "Send" {
+            return Err(syn::Error::new_spanned(send, "expected `Send`"));
+        }
+        if machine.async_token.is_none() {
+            return Err(syn::Error::new_spanned(
+                send,
+                "only an `async machine` can be `Send`",
+            ));
+        }
+    }
+
+    let mut queue_capacity = None;
+    for item in &machine.items {
+        if let parse::MachineItem::QueueCapacity(q) = item {
+            if queue_capacity.is_some() {
+                return Err(syn::Error::new_spanned(
+                    q,
+                    "duplicate declaration of queue capacity",
+                ));
+            }
+            queue_capacity = Some(q.capacity.clone());
+        }
+    }
+
+    if machine.async_token.is_none() {
+        if let Some(m) = methods.iter().find(|m| m.sig.asyncness.is_some()) {
+            return Err(syn::Error::new_spanned(
+                &m.sig,
+                "async methods need an `async machine`",
+            ));
+        }
+    }
+
+    let items: Vec<_> = machine
         .items
         .iter()
         .filter_map(|i| match i {
@@ -65,42 +181,117 @@ fn analyze_machine(machine: &parse::Machine) -> Result<Machine> {
         })
         .collect();
 
+    if let Some(activity) = items.iter().find_map(|i| match i {
+        parse::StateItem::Activity(a) => Some(a),
+        _ => None,
+    }) {
+        return Err(syn::Error::new_spanned(
+            activity,
+            "do-activity must be declared in a state",
+        ));
+    }
+
+    check_no_points(&items, NO_POINTS_OUTSIDE_STATE)?;
+
+    let mut_methods: Vec<_> = methods
+        .iter()
+        .filter(|m| {
+            matches!(m.sig.inputs.first(), Some(syn::FnArg::Receiver(r)) if r.mutability.is_some())
+        })
+        .map(|m| &m.sig.ident)
+        .collect();
+    check_guards(&items, &mut_methods)?;
+
+    let state = analyze_state(
+        machine.ident.clone(),
+        StateKind::Normal,
+        None,
+        &items,
+        &machine,
+    )?;
+    check_path_variants(&state, &mut vec![], &mut vec![])?;
+
     Ok(Machine {
         vis: machine.vis.clone(),
+        is_async: machine.async_token.is_some(),
+        is_send: machine.send.is_some(),
         ident: machine.ident.clone(),
         methods,
-        state: analyze_state(machine.ident.clone(), &items, &machine)?,
+        queue_capacity,
+        state,
     })
 }
 
 fn analyze_state(
     ident: syn::Ident,
+    kind: StateKind,
+    submachine: Option<&syn::Path>,
     items: &Vec<parse::StateItem>,
     range: &dyn quote::ToTokens,
 ) -> Result<State> {
-    let mut states = HashMap::new();
-    let mut regions = HashMap::new();
-    let mut initial_transition = None;
-    let mut entry = None;
-    let mut exit = None;
-    let mut internal_transitions = vec![];
+    let mut states: Vec<State> = vec![];
+    let mut pseudostates: Vec<Pseudostate> = vec![];
+    let mut regions: Vec<State> = vec![];
+
+    if submachine.is_some() {
+        check_no_points(
+            items,
+            "submachine state cannot have entry or exit points, as its embedded machine cannot reach them",
+        )?;
+    }
 
     for it in items {
         match it {
             parse::StateItem::Region(region) => {
-                let old = regions.insert(
-                    region.ident.clone(),
-                    analyze_state(region.ident.clone(), &region.items, &region)?,
-                );
-                if old.is_some() {
+                if regions.iter().any(|r| r.ident == region.ident) {
                     return Err(syn::Error::new_spanned(
                         &region.ident,
                         "duplicate declaration of region",
                     ));
                 }
+                check_no_points(&region.items, NO_POINTS_OUTSIDE_STATE)?;
+                regions.push(analyze_state(
+                    region.ident.clone(),
+                    StateKind::Normal,
+                    None,
+                    &region.items,
+                    &region,
+                )?);
             }
             parse::StateItem::State(_) => (),
             parse::StateItem::Transition(_) => (),
+            parse::StateItem::Defer(_) => (),
+            parse::StateItem::Activity(_) => (),
+            parse::StateItem::Forward(_) => (),
+            parse::StateItem::EntryPoint(_) => (),
+            parse::StateItem::ExitPoint(_) => (),
+        }
+    }
+
+    let mut exit_points: Vec<ExitPoint> = vec![];
+    for it in items {
+        let ident = match it {
+            parse::StateItem::EntryPoint(entry_point) => &entry_point.ident,
+            parse::StateItem::ExitPoint(exit_point) => &exit_point.ident,
+            _ => continue,
+        };
+        let duplicate = pseudostates.iter().any(|p| p.ident == *ident)
+            || exit_points.iter().any(|p| p.ident == *ident);
+        if duplicate {
+            return Err(syn::Error::new_spanned(
+                ident,
+                "duplicate declaration of entry or exit point",
+            ));
+        }
+        match it {
+            parse::StateItem::EntryPoint(_) => {
+                pseudostates.push(Pseudostate::new(ident.clone(), PseudostateKind::EntryPoint));
+            }
+            _ => exit_points.push(ExitPoint {
+                ident: ident.clone(),
+                incoming: vec![],
+                wired: false,
+            }),
         }
     }
 
@@ -113,22 +304,118 @@ fn analyze_state(
                         "sub-state not allowed in state with regions",
                     ));
                 }
-                let old = states.insert(
-                    sub_state.ident.clone(),
-                    analyze_state(sub_state.ident.clone(), &sub_state.items, &sub_state)?,
-                );
-                if old.is_some() {
+                let duplicate = states.iter().any(|s| s.ident == sub_state.ident)
+                    || pseudostates.iter().any(|p| p.ident == sub_state.ident)
+                    || exit_points.iter().any(|p| p.ident == sub_state.ident);
+                if duplicate {
                     return Err(syn::Error::new_spanned(
                         &sub_state.ident,
                         "duplicate declaration of state",
                     ));
                 }
+                let kind = match sub_state.kind {
+                    None => StateKind::Normal,
+                    Some(parse::StateKind::Final(_)) => StateKind::Final,
+                    Some(parse::StateKind::Choice(_, ref partial)) => {
+                        let mut choice =
+                            Pseudostate::new(sub_state.ident.clone(), PseudostateKind::Choice);
+                        choice.partial = partial.is_some();
+                        pseudostates.push(choice);
+                        continue;
+                    }
+                    Some(parse::StateKind::Junction(_)) => {
+                        pseudostates.push(Pseudostate::new(
+                            sub_state.ident.clone(),
+                            PseudostateKind::Junction,
+                        ));
+                        continue;
+                    }
+                };
+                states.push(analyze_state(
+                    sub_state.ident.clone(),
+                    kind,
+                    sub_state.submachine.as_ref().map(|(_, path)| path),
+                    &sub_state.items,
+                    &sub_state,
+                )?);
             }
             parse::StateItem::Region(_) => (),
             parse::StateItem::Transition(_) => (),
+            parse::StateItem::Defer(_) => (),
+            parse::StateItem::Activity(_) => (),
+            parse::StateItem::Forward(_) => (),
+            parse::StateItem::EntryPoint(_) => (),
+            parse::StateItem::ExitPoint(_) => (),
+        }
+    }
+
+    // Final pseudostate, which implicitly declares the final state `Final`
+    // ```rust
+    // Source + Event => <X>;
+    // ```
+    for it in items {
+        if let parse::StateItem::Transition(parse::ItemTransition {
+            target: Some((_, parse::TransitionTarget::Final(target))),
+            ..
+        }) = it
+        {
+            if regions.len() > 0 {
+                return Err(syn::Error::new_spanned(
+                    target,
+                    "final state not allowed in state with regions",
+                ));
+            }
+            let ident = final_state_ident(target);
+            let conflict = pseudostates.iter().any(|p| p.ident == ident)
+                || exit_points.iter().any(|p| p.ident == ident);
+            if conflict {
+                return Err(syn::Error::new_spanned(
+                    target,
+                    "final pseudostate conflicts with non-final state `Final`",
+                ));
+            }
+            match states.iter().find(|s| s.ident == ident) {
+                Some(s) if s.kind != StateKind::Final => {
+                    return Err(syn::Error::new_spanned(
+                        target,
+                        "final pseudostate conflicts with non-final state `Final`",
+                    ));
+                }
+                Some(_) => (),
+                None => {
+                    states.push(analyze_state(
+                        ident,
+                        StateKind::Final,
+                        None,
+                        &vec![],
+                        target,
+                    )?);
+                }
+            }
         }
     }
 
+    let mut state = State {
+        ident,
+        kind,
+        states,
+        pseudostates,
+        exit_points,
+        regions,
+        entry: None,
+        exit: None,
+        activity: None,
+        submachine: submachine.cloned(),
+        forwarded_events: vec![],
+        initial_transition: None,
+        shallow_history: None,
+        deep_history: None,
+        internal_transitions: vec![],
+        deferred_events: vec![],
+        out_transitions: vec![],
+        exit_transitions: vec![],
+    };
+
     for it in items {
         match it {
             parse::StateItem::Transition(
@@ -137,7 +424,7 @@ fn analyze_state(
                     ..
                 },
             ) => {
-                if initial_transition.is_some() {
+                if state.initial_transition.is_some() {
                     return Err(syn::Error::new_spanned(
                         transition,
                         "duplicate initial transition",
@@ -154,20 +441,79 @@ fn analyze_state(
                         )
                     })?
                     .1;
-                if !states.contains_key(target) {
+                let target_path = target_path(target);
+                let target_history = analyze_target(&state, &target_path, target)?;
+
+                state.initial_transition = Some(Transition {
+                    event_path: None,
+                    event_pat: None,
+                    after: None,
+                    source_path: vec![],
+                    target: Some(target_path[0].clone()),
+                    target_path: target_path[1..].to_vec(),
+                    target_history,
+                    action: transition.action.as_ref().map(|(_, a)| a.expr.clone()),
+                    guard: None,
+                    through_exit_point: false,
+                })
+            }
+            // History pseudostate with its default transition
+            // ```rust
+            // <H> => Target;
+            // <H*> => Target / Action;
+            // ```
+            parse::StateItem::Transition(
+                transition @ parse::ItemTransition {
+                    source: parse::TransitionSource::History(history),
+                    ..
+                },
+            ) => {
+                let exists = match history.asterisk_token {
+                    None => state.shallow_history.is_some(),
+                    Some(_) => state.deep_history.is_some(),
+                };
+                if exists {
                     return Err(syn::Error::new_spanned(
-                        target,
-                        "transition target is not a declared state",
+                        transition,
+                        "duplicate history pseudostate",
+                    ));
+                }
+                if transition.event.is_some() || transition.guard.is_some() {
+                    return Err(syn::Error::new_spanned(
+                        transition,
+                        "history default transition cannot have an event or guard",
                     ));
                 }
 
-                initial_transition = Some(Transition {
+                let target = &transition
+                    .target
+                    .as_ref()
+                    .ok_or_else(|| {
+                        syn::Error::new_spanned(
+                            transition,
+                            "history pseudostate needs a default target state",
+                        )
+                    })?
+                    .1;
+                let target_path = target_path(target);
+                let target_history = analyze_target(&state, &target_path, target)?;
+
+                let default_transition = Some(Transition {
                     event_path: None,
                     event_pat: None,
-                    target: Some(target.clone()),
+                    after: None,
+                    source_path: vec![],
+                    target: Some(target_path[0].clone()),
+                    target_path: target_path[1..].to_vec(),
+                    target_history,
                     action: transition.action.as_ref().map(|(_, a)| a.expr.clone()),
                     guard: None,
-                })
+                    through_exit_point: false,
+                });
+                match history.asterisk_token {
+                    None => state.shallow_history = default_transition,
+                    Some(_) => state.deep_history = default_transition,
+                }
             }
             // Entry behavior
             // ```rust
@@ -186,13 +532,13 @@ fn analyze_state(
                     ..
                 },
             ) if i == "entry" => {
-                if entry.is_some() {
+                if state.entry.is_some() {
                     return Err(syn::Error::new_spanned(
                         transition,
                         "duplicate entry behavior",
                     ));
                 }
-                entry = Some(action.expr.clone());
+                state.entry = Some(action.expr.clone());
             }
             // Exit behavior
             // ```rust
@@ -211,13 +557,13 @@ fn analyze_state(
                     ..
                 },
             ) if i == "exit" => {
-                if exit.is_some() {
+                if state.exit.is_some() {
                     return Err(syn::Error::new_spanned(
                         transition,
                         "duplicate exit behavior",
                     ));
                 }
-                exit = Some(action.expr.clone());
+                state.exit = Some(action.expr.clone());
             }
             // An internal transition
             // ```rust
@@ -236,12 +582,17 @@ fn analyze_state(
                 let event = source;
                 let (event_path, event_pat) = analyze_event(event);
 
-                internal_transitions.push(Transition {
+                state.internal_transitions.push(Transition {
                     target: None,
+                    target_path: vec![],
+                    target_history: None,
+                    source_path: vec![],
                     event_path: Some(event_path),
                     event_pat,
+                    after: None,
                     action: Some(action.expr.clone()),
-                    guard: transition.guard.as_ref().map(|(_, g)| g.expr.clone()),
+                    guard: analyze_guard(transition)?,
+                    through_exit_point: false,
                 })
             }
             // A normal transition
@@ -251,78 +602,844 @@ fn analyze_state(
             // Source + Event => Target if Guard;
             // Source + Event => Target / Action if Guard;
             // ```
+            //
+            // A completion transition, taken when the source state completes
+            // ```rust
+            // Source => Target;
+            // Source => Target / Action if Guard;
+            // ```
+            //
+            // Source and target may be qualified paths like `A.B`; the
+            // transition is then owned by their least common ancestor.
+            //
+            // An outgoing transition of a choice or junction pseudostate
+            // ```rust
+            // Choice => Target / Action if Guard;
+            // Choice => Target / Action if else;
+            // ```
             parse::StateItem::Transition(
                 transition @ parse::ItemTransition {
-                    source: parse::TransitionSource::State(source),
+                    source: parse::TransitionSource::State(_) | parse::TransitionSource::Path(_),
                     ..
                 },
             ) => {
-                let target = &transition.target.as_ref().unwrap().1;
-                if !states.contains_key(target) {
-                    return Err(syn::Error::new_spanned(
-                        target,
-                        "transition target is not a declared state",
-                    ));
-                }
-
-                let source = match source {
-                    syn::Pat::Ident(syn::PatIdent {
-                        attrs,
-                        by_ref: None,
-                        mutability: None,
-                        ident: i,
-                        subpat: None,
-                    }) if attrs.is_empty() => i,
-                    _ => {
-                        return Err(syn::Error::new_spanned(
-                            source,
-                            "transition source must be a simple ident",
-                        ));
-                    }
-                };
-                let sub_state = states.get_mut(source).ok_or_else(|| {
-                    syn::Error::new_spanned(source, "transition source is not a declared state")
-                })?;
-
-                let event = &transition
-                    .event
+                let target = &transition
+                    .target
                     .as_ref()
                     .ok_or_else(|| {
-                        syn::Error::new_spanned(&transition.source, "transition requires event")
+                        syn::Error::new_spanned(transition, "transition needs a target state")
                     })?
                     .1;
-                let (event_path, event_pat) = analyze_event(&event.pat);
+                let target_path = target_path(target);
+                let source_path = source_path(&transition.source)?;
 
-                sub_state.out_transitions.push(Transition {
-                    target: Some(target.clone()),
-                    event_path: Some(event_path),
+                if source_path.len() == 1 && state.pseudostate(&source_path[0]).is_some() {
+                    analyze_branch(&mut state, &source_path[0], transition, target)?;
+                    continue;
+                }
+
+                let (event_path, event_pat, after) = match &transition.event {
+                    Some((_, parse::Trigger::Event(event))) => {
+                        let (event_path, event_pat) = analyze_event(&event.pat);
+                        (Some(event_path), event_pat, None)
+                    }
+                    Some((_, parse::Trigger::After(after))) => {
+                        let timer_id = syn::parse_quote! { ::umlstate::TimerId };
+                        (Some(timer_id), None, Some(after.duration.clone()))
+                    }
+                    None => (None, None, None),
+                };
+                let t = Transition {
+                    event_path,
                     event_pat,
+                    after,
+                    source_path: vec![],
+                    target: None,
+                    target_path: vec![],
+                    target_history: None,
                     action: transition.action.as_ref().map(|(_, a)| a.expr.clone()),
-                    guard: transition.guard.as_ref().map(|(_, g)| g.expr.clone()),
-                })
+                    guard: analyze_guard(transition)?,
+                    through_exit_point: false,
+                };
+
+                // A transition leaving an exit point, `State.ExitPoint =>
+                // Target;`, is taken in place of each transition reaching
+                // the exit point.  Their actions both run once the state
+                // was left.
+                if let Some(incoming) = wire_exit_point(&mut state, &source_path) {
+                    if let Some((_, event)) = &transition.event {
+                        return Err(syn::Error::new_spanned(
+                            event,
+                            "exit point transitions cannot have an event",
+                        ));
+                    }
+                    let owner_path = &source_path[..source_path.len() - 1];
+                    for inner in incoming {
+                        let source_path: Vec<_> = owner_path
+                            .iter()
+                            .chain(inner.source_path.iter())
+                            .cloned()
+                            .collect();
+                        let t = Transition {
+                            action: chain_actions(inner.action, t.action.clone()),
+                            guard: chain_guards(inner.guard, t.guard.clone()),
+                            through_exit_point: true,
+                            ..inner
+                        };
+                        add_transition(
+                            &mut state,
+                            &source_path,
+                            &target_path,
+                            target,
+                            &transition.source,
+                            t,
+                        )?;
+                    }
+                    add_exit_transition(&mut state, &source_path, &target_path, target, t)?;
+                    continue;
+                }
+
+                add_transition(
+                    &mut state,
+                    &source_path,
+                    &target_path,
+                    target,
+                    &transition.source,
+                    t,
+                )?;
+            }
+            // A deferred event, kept until the state is left
+            // ```rust
+            // defer Event;
+            // ```
+            parse::StateItem::Defer(defer) => {
+                let (event_path, event_pat) = analyze_event(&defer.event.pat);
+                state.deferred_events.push(DeferredEvent {
+                    event_path,
+                    event_pat,
+                });
+            }
+            // A do-activity with its optional cancellation hook
+            // ```rust
+            // do / Activity;
+            // do / Activity cancel Action;
+            // ```
+            parse::StateItem::Activity(activity) => {
+                if state.activity.is_some() {
+                    return Err(syn::Error::new_spanned(activity, "duplicate do-activity"));
+                }
+                state.activity = Some(Activity {
+                    start: activity.activity.clone(),
+                    cancel: activity.cancel.as_ref().map(|(_, a)| a.expr.clone()),
+                });
+            }
+            // An event of the embedded machine, forwarded by the submachine
+            // state
+            // ```rust
+            // forward Event;
+            // ```
+            parse::StateItem::Forward(forward) => {
+                if state.submachine.is_none() {
+                    return Err(syn::Error::new_spanned(
+                        forward,
+                        "events can only be forwarded by a submachine state",
+                    ));
+                }
+                let (event_path, _) = analyze_event(&forward.event.pat);
+                state.forwarded_events.push(event_path);
             }
             parse::StateItem::State(_) => (),
             parse::StateItem::Region(_) => (),
+            parse::StateItem::EntryPoint(_) => (),
+            parse::StateItem::ExitPoint(_) => (),
+        }
+    }
+
+    if state.submachine.is_some() && (!state.states.is_empty() || !state.regions.is_empty()) {
+        return Err(syn::Error::new_spanned(
+            range,
+            "submachine state cannot have sub-states or regions",
+        ));
+    }
+
+    for sub_state in &state.states {
+        if let Some(exit_point) = sub_state.exit_points.iter().find(|p| !p.wired) {
+            return Err(syn::Error::new_spanned(
+                &exit_point.ident,
+                format!(
+                    "exit point `{0}` is not connected to a target. help: add a `{1}.{0} => Target;` transition to the parent state",
+                    exit_point.ident, sub_state.ident
+                ),
+            ));
         }
     }
 
-    if states.len() > 0 && initial_transition.is_none() {
+    for pseudostate in &state.pseudostates {
+        if pseudostate.transitions.is_empty() && pseudostate.else_transition.is_none() {
+            return Err(syn::Error::new_spanned(
+                &pseudostate.ident,
+                "pseudostate needs an outgoing transition",
+            ));
+        }
+        // Its source is exited by then, so a choice without an enabled
+        // branch can only panic.
+        if pseudostate.kind == PseudostateKind::Choice
+            && !pseudostate.partial
+            && pseudostate.else_transition.is_none()
+        {
+            return Err(syn::Error::new_spanned(
+                &pseudostate.ident,
+                "choice pseudostate needs an `else` branch. help: add an `if else` branch, or declare it `choice(partial)` to panic when no guard holds",
+            ));
+        }
+        check_pseudostate_cycle(&state, pseudostate, &mut vec![])?;
+    }
+
+    check_completion_cycles(&state)?;
+
+    if state.states.len() > 0 && state.initial_transition.is_none() {
         return Err(syn::Error::new_spanned(
             range,
             "missing initial transition. help: you need one `<*> =>` transition",
         ));
     }
 
-    Ok(State {
-        ident,
-        states,
-        regions: regions.into_values().collect(),
-        entry,
-        exit,
-        initial_transition,
-        internal_transitions,
-        out_transitions: vec![],
-    })
+    Ok(state)
+}
+
+impl Pseudostate {
+    fn new(ident: syn::Ident, kind: PseudostateKind) -> Self {
+        Pseudostate {
+            ident,
+            kind,
+            partial: false,
+            transitions: vec![],
+            else_transition: None,
+        }
+    }
+
+    /// Outgoing transitions in evaluation order, the `else` branch last.
+    pub fn branches(&self) -> impl Iterator<Item = &Transition> {
+        self.transitions.iter().chain(self.else_transition.iter())
+    }
+}
+
+impl State {
+    fn child(&self, ident: &syn::Ident) -> Option<&State> {
+        self.states
+            .iter()
+            .chain(self.regions.iter())
+            .find(|s| s.ident == *ident)
+    }
+
+    fn child_mut(&mut self, ident: &syn::Ident) -> Option<&mut State> {
+        self.states
+            .iter_mut()
+            .chain(self.regions.iter_mut())
+            .find(|s| s.ident == *ident)
+    }
+
+    fn pseudostate(&self, ident: &syn::Ident) -> Option<&Pseudostate> {
+        self.pseudostates.iter().find(|p| p.ident == *ident)
+    }
+}
+
+fn analyze_guard(transition: &parse::ItemTransition) -> Result<Option<Box<syn::Expr>>> {
+    match &transition.guard {
+        None => Ok(None),
+        Some((_, parse::Guard::Expr(expr))) => Ok(Some(expr.clone())),
+        Some((_, guard @ parse::Guard::Else(_))) => Err(syn::Error::new_spanned(
+            guard,
+            "`else` guard is only allowed on choice and junction transitions",
+        )),
+    }
+}
+
+/// Adds the transition `t` from `source_path` to `target_path`, owned by
+/// the least common ancestor of both ends below `state`.  A transition
+/// reaching an exit point of `state` waits for the parent to wire it.
+fn add_transition(
+    state: &mut State,
+    source_path: &[syn::Ident],
+    target_path: &[syn::Ident],
+    target: &parse::TransitionTarget,
+    source: &parse::TransitionSource,
+    mut t: Transition,
+) -> Result<()> {
+    let exit_point = match target_path {
+        [ident] => state.exit_points.iter().position(|p| p.ident == *ident),
+        _ => None,
+    };
+    if let Some(exit_point) = exit_point {
+        if let parse::TransitionTarget::State(parse::TargetState {
+            history: Some(_), ..
+        }) = target
+        {
+            return Err(syn::Error::new_spanned(
+                target,
+                "history target requires a composite state",
+            ));
+        }
+        check_source(state, source_path, source)?;
+        t.source_path = source_path.to_vec();
+        t.target = Some(target_path[0].clone());
+        state.exit_points[exit_point].incoming.push(t);
+        return Ok(());
+    }
+
+    // Descend into the least common ancestor. The transition stays
+    // external, so neither end may be the ancestor itself.
+    let common = source_path
+        .iter()
+        .zip(target_path.iter())
+        .take_while(|(s, t)| s == t)
+        .count()
+        .min(source_path.len() - 1)
+        .min(target_path.len() - 1);
+    let mut scope = state;
+    for ident in &source_path[..common] {
+        scope = scope.child_mut(ident).ok_or_else(|| {
+            syn::Error::new_spanned(ident, "transition source is not a declared state")
+        })?;
+    }
+    let source_path = &source_path[common..];
+    let target_path = &target_path[common..];
+
+    let target_history = analyze_target(scope, target_path, target)?;
+    check_source(scope, source_path, source)?;
+
+    let sub_state = scope.child_mut(&source_path[0]).unwrap();
+    sub_state.out_transitions.push(Transition {
+        source_path: source_path[1..].to_vec(),
+        target: Some(target_path[0].clone()),
+        target_path: target_path[1..].to_vec(),
+        target_history,
+        ..t
+    });
+    Ok(())
+}
+
+/// Keeps the transition `t` leaving the exit point at `source_path` for the
+/// diagrams, owned like the transitions it was joined with.  A target that
+/// is an exit point of `state` itself is resolved in `state`.
+fn add_exit_transition(
+    state: &mut State,
+    source_path: &[syn::Ident],
+    target_path: &[syn::Ident],
+    target: &parse::TransitionTarget,
+    t: Transition,
+) -> Result<()> {
+    let to_exit_point = matches!(
+        target_path,
+        [ident] if state.exit_points.iter().any(|p| p.ident == *ident)
+    );
+    // The state declaring the exit point stays in the source path.
+    let common = if to_exit_point {
+        0
+    } else {
+        source_path
+            .iter()
+            .zip(target_path.iter())
+            .take_while(|(s, t)| s == t)
+            .count()
+            .min(source_path.len() - 2)
+            .min(target_path.len() - 1)
+    };
+    let mut scope = state;
+    for ident in &source_path[..common] {
+        scope = scope.child_mut(ident).unwrap();
+    }
+    let source_path = &source_path[common..];
+    let target_path = &target_path[common..];
+
+    let target_history = if to_exit_point {
+        None
+    } else {
+        analyze_target(scope, target_path, target)?
+    };
+    let sub_state = scope.child_mut(&source_path[0]).unwrap();
+    sub_state.exit_transitions.push(Transition {
+        source_path: source_path[1..].to_vec(),
+        target: Some(target_path[0].clone()),
+        target_path: target_path[1..].to_vec(),
+        target_history,
+        ..t
+    });
+    Ok(())
+}
+
+fn check_source(
+    scope: &State,
+    path: &[syn::Ident],
+    source: &parse::TransitionSource,
+) -> Result<()> {
+    let state = resolve_path(scope, path, "transition source")?;
+    if state.kind == StateKind::Final {
+        return Err(syn::Error::new_spanned(
+            source,
+            "final state cannot have outgoing transitions",
+        ));
+    }
+    Ok(())
+}
+
+/// Marks the exit point at `path` as wired, returning the transitions
+/// reaching it, or `None` if `path` is no exit point.
+fn wire_exit_point(state: &mut State, path: &[syn::Ident]) -> Option<Vec<Transition>> {
+    let (ident, owner_path) = path.split_last()?;
+    if owner_path.is_empty() {
+        return None;
+    }
+    let mut owner = state;
+    for ident in owner_path {
+        owner = owner.states.iter_mut().find(|s| s.ident == *ident)?;
+    }
+    let exit_point = owner.exit_points.iter_mut().find(|p| p.ident == *ident)?;
+    exit_point.wired = true;
+    Some(exit_point.incoming.clone())
+}
+
+/// Runs the action `first`, then `second`.
+fn chain_actions(
+    first: Option<Box<syn::Expr>>,
+    second: Option<Box<syn::Expr>>,
+) -> Option<Box<syn::Expr>> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(Box::new(syn::parse_quote! {
+            {
+                #first;
+                #second;
+            }
+        })),
+        (first, second) => first.or(second),
+    }
+}
+
+fn chain_guards(
+    first: Option<Box<syn::Expr>>,
+    second: Option<Box<syn::Expr>>,
+) -> Option<Box<syn::Expr>> {
+    match (first, second) {
+        (Some(first), Some(second)) => Some(Box::new(syn::parse_quote! {
+            (#first) && (#second)
+        })),
+        (first, second) => first.or(second),
+    }
+}
+
+/// Rejects unguarded completion transitions between sibling states that
+/// always complete, like `A => B; B => A;`, which would never stop firing.
+fn check_completion_cycles(scope: &State) -> Result<()> {
+    let always_complete = |s: &State| {
+        s.kind == StateKind::Normal
+            && s.states.is_empty()
+            && s.regions.is_empty()
+            && s.activity.is_none()
+            && s.submachine.is_none()
+    };
+    // The sibling targeted by the unguarded completion transition of `s`.
+    fn next(s: &State) -> Option<&syn::Ident> {
+        s.out_transitions
+            .iter()
+            .filter(|t| t.event_path.is_none() && t.guard.is_none())
+            .filter(|t| t.source_path.is_empty() && t.target_path.is_empty())
+            .filter(|t| t.target_history.is_none())
+            .find_map(|t| t.target.as_ref())
+    }
+
+    for start in scope.states.iter().filter(|s| always_complete(s)) {
+        let mut path = vec![&start.ident];
+        let mut state = start;
+        while let Some(target) = next(state) {
+            let target_state = match scope.states.iter().find(|s| s.ident == *target) {
+                Some(s) if always_complete(s) => s,
+                _ => break,
+            };
+            if target_state.ident == start.ident {
+                let cycle: Vec<_> = path
+                    .iter()
+                    .chain([&&start.ident])
+                    .map(|i| i.to_string())
+                    .collect();
+                return Err(syn::Error::new_spanned(
+                    target,
+                    format!(
+                        "completion transitions `{}` form a cycle that never stops. help: add a guard or an event to one of them",
+                        cycle.join(" => ")
+                    ),
+                ));
+            }
+            if path.contains(&&target_state.ident) {
+                break;
+            }
+            path.push(&target_state.ident);
+            state = target_state;
+        }
+    }
+    Ok(())
+}
+
+/// Rejects guards calling `&mut self` methods of the context, as guards
+/// only get shared access to it.
+fn check_guards(items: &[parse::StateItem], mut_methods: &[&syn::Ident]) -> Result<()> {
+    for it in items {
+        match it {
+            parse::StateItem::Transition(parse::ItemTransition {
+                guard: Some((_, parse::Guard::Expr(guard))),
+                ..
+            }) => check_guard(guard.to_token_stream(), mut_methods)?,
+            parse::StateItem::State(state) => check_guards(&state.items, mut_methods)?,
+            parse::StateItem::Region(region) => check_guards(&region.items, mut_methods)?,
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+fn check_guard(tokens: proc_macro2::TokenStream, mut_methods: &[&syn::Ident]) -> Result<()> {
+    let tokens: Vec<_> = tokens.into_iter().collect();
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            proc_macro2::TokenTree::Group(group) => check_guard(group.stream(), mut_methods)?,
+            proc_macro2::TokenTree::Ident(ctx) if ctx == "ctx" => {
+                if let [proc_macro2::TokenTree::Punct(dot), proc_macro2::TokenTree::Ident(method), ..] =
+                    &tokens[i + 1..]
+                {
+                    if dot.as_char() == '.' && mut_methods.contains(&method) {
+                        return Err(syn::Error::new_spanned(
+                            method,
+                            format!(
+                                "guard cannot call `{}`, which takes `&mut self`. help: guards only get shared access to the context",
+                                method
+                            ),
+                        ));
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+    Ok(())
+}
+
+/// Rejects submachine states embedding a machine of the same invocation
+/// that has timed transitions or do-activities, as their timer and
+/// spawner would be shared with the embedding machine.  Machines declared
+/// elsewhere are checked by the bounds of the generated code.
+fn check_submachines(state: &State, machines: &[Machine]) -> Result<()> {
+    if let Some(path) = &state.submachine {
+        let embedded = machines.iter().find(|m| path.is_ident(&m.ident));
+        if let Some(embedded) = embedded {
+            if has_timers_or_activities(&embedded.state, embedded.is_async) {
+                return Err(syn::Error::new_spanned(
+                    path,
+                    format!(
+                        "machine `{}` has timed transitions or do-activities, so it cannot be embedded by a submachine state",
+                        embedded.ident
+                    ),
+                ));
+            }
+        }
+    }
+    for s in state.states.iter().chain(state.regions.iter()) {
+        check_submachines(s, machines)?;
+    }
+    Ok(())
+}
+
+fn has_timers_or_activities(state: &State, is_async: bool) -> bool {
+    let mut transitions = state
+        .internal_transitions
+        .iter()
+        .chain(state.out_transitions.iter());
+    (is_async && state.activity.is_some())
+        || transitions.any(|t| t.after.is_some())
+        || state
+            .states
+            .iter()
+            .chain(state.regions.iter())
+            .any(|s| has_timers_or_activities(s, is_async))
+}
+
+/// Rejects states and regions whose paths would name the same variant of
+/// the state path enum, like `AB` and `A.B`.
+fn check_path_variants<'a>(
+    state: &'a State,
+    prefix: &mut Vec<&'a syn::Ident>,
+    variants: &mut Vec<(syn::Ident, String)>,
+) -> Result<()> {
+    for sub_state in state.states.iter().chain(state.regions.iter()) {
+        prefix.push(&sub_state.ident);
+        let variant = pretty::path_variant(prefix);
+        let name = prefix
+            .iter()
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(".");
+        if let Some((_, other)) = variants.iter().find(|(v, _)| *v == variant) {
+            return Err(syn::Error::new_spanned(
+                &sub_state.ident,
+                format!(
+                    "`{}` and `{}` would both be named `{}` in the state path enum",
+                    other, name, variant
+                ),
+            ));
+        }
+        variants.push((variant, name));
+        check_path_variants(sub_state, prefix, variants)?;
+        prefix.pop();
+    }
+    Ok(())
+}
+
+/// Neither the top of a machine nor a region has a parent state to connect
+/// its entry and exit points.
+const NO_POINTS_OUTSIDE_STATE: &str = "entry and exit points must be declared in a state";
+
+/// Rejects entry and exit points declared where they could not be connected,
+/// with `msg` telling why.
+fn check_no_points(items: &[parse::StateItem], msg: &str) -> Result<()> {
+    for it in items {
+        let point: &dyn quote::ToTokens = match it {
+            parse::StateItem::EntryPoint(p) => p,
+            parse::StateItem::ExitPoint(p) => p,
+            _ => continue,
+        };
+        return Err(syn::Error::new_spanned(point, msg));
+    }
+    Ok(())
+}
+
+/// Adds an outgoing transition of the choice, junction or entry point
+/// `source`.  Its target is resolved in the scope declaring the
+/// pseudostate.
+fn analyze_branch(
+    state: &mut State,
+    source: &syn::Ident,
+    transition: &parse::ItemTransition,
+    target: &parse::TransitionTarget,
+) -> Result<()> {
+    let is_entry_point = state.pseudostate(source).unwrap().kind == PseudostateKind::EntryPoint;
+    if is_entry_point {
+        if let Some((_, event)) = &transition.event {
+            return Err(syn::Error::new_spanned(
+                event,
+                "entry point transitions cannot have an event",
+            ));
+        }
+        if let Some((_, guard)) = &transition.guard {
+            return Err(syn::Error::new_spanned(
+                guard,
+                "entry point transitions cannot have a guard",
+            ));
+        }
+        if !state.pseudostate(source).unwrap().transitions.is_empty() {
+            return Err(syn::Error::new_spanned(
+                transition,
+                "entry point needs a single outgoing transition",
+            ));
+        }
+    }
+    if let Some((_, event)) = &transition.event {
+        return Err(syn::Error::new_spanned(
+            event,
+            "choice and junction transitions cannot have an event",
+        ));
+    }
+
+    let target_path = target_path(target);
+    let target_history = analyze_target(state, &target_path, target)?;
+    let (guard, is_else) = match &transition.guard {
+        None if !is_entry_point => {
+            return Err(syn::Error::new_spanned(
+                transition,
+                "choice and junction transitions need a guard. help: use `if else` for the branch taken otherwise",
+            ));
+        }
+        None => (None, false),
+        Some((_, parse::Guard::Else(_))) => (None, true),
+        Some((_, parse::Guard::Expr(expr))) => (Some(expr.clone()), false),
+    };
+    let branch = Transition {
+        event_path: None,
+        event_pat: None,
+        after: None,
+        source_path: vec![],
+        target: Some(target_path[0].clone()),
+        target_path: target_path[1..].to_vec(),
+        target_history,
+        action: transition.action.as_ref().map(|(_, a)| a.expr.clone()),
+        guard,
+        through_exit_point: false,
+    };
+
+    let pseudostate = state
+        .pseudostates
+        .iter_mut()
+        .find(|p| p.ident == *source)
+        .unwrap();
+    if !is_else {
+        pseudostate.transitions.push(branch);
+    } else if pseudostate.else_transition.is_none() {
+        pseudostate.else_transition = Some(branch);
+    } else {
+        return Err(syn::Error::new_spanned(
+            transition,
+            "duplicate `else` branch",
+        ));
+    }
+    Ok(())
+}
+
+fn check_pseudostate_cycle<'a>(
+    scope: &'a State,
+    pseudostate: &'a Pseudostate,
+    visiting: &mut Vec<&'a syn::Ident>,
+) -> Result<()> {
+    if visiting.contains(&&pseudostate.ident) {
+        return Err(syn::Error::new_spanned(
+            &pseudostate.ident,
+            "cycle between choice and junction pseudostates",
+        ));
+    }
+    visiting.push(&pseudostate.ident);
+    for branch in pseudostate.branches() {
+        let next = branch.target.as_ref().and_then(|t| scope.pseudostate(t));
+        if let Some(next) = next {
+            check_pseudostate_cycle(scope, next, visiting)?;
+        }
+    }
+    visiting.pop();
+    Ok(())
+}
+
+fn final_state_ident(target: &parse::TargetFinal) -> syn::Ident {
+    syn::Ident::new("Final", target.x_token.span)
+}
+
+fn source_path(source: &parse::TransitionSource) -> Result<Vec<syn::Ident>> {
+    match source {
+        parse::TransitionSource::Path(path) => Ok(path.segments.iter().cloned().collect()),
+        parse::TransitionSource::State(syn::Pat::Ident(syn::PatIdent {
+            attrs,
+            by_ref: None,
+            mutability: None,
+            ident,
+            subpat: None,
+        })) if attrs.is_empty() => Ok(vec![ident.clone()]),
+        _ => Err(syn::Error::new_spanned(
+            source,
+            "transition source must be a simple ident or path",
+        )),
+    }
+}
+
+fn target_path(target: &parse::TransitionTarget) -> Vec<syn::Ident> {
+    match target {
+        parse::TransitionTarget::State(target) => target.path.segments.iter().cloned().collect(),
+        parse::TransitionTarget::Final(target) => vec![final_state_ident(target)],
+    }
+}
+
+/// Resolves a path of sub-states and regions, starting with a direct
+/// sub-state of `scope`.  The path has to end in a state.
+fn resolve_path<'a>(scope: &'a State, path: &[syn::Ident], what: &str) -> Result<&'a State> {
+    let mut state = scope
+        .states
+        .iter()
+        .find(|s| s.ident == path[0])
+        .ok_or_else(|| {
+            let msg = if scope.regions.iter().any(|r| r.ident == path[0]) {
+                "transition cannot cross orthogonal regions".to_string()
+            } else {
+                format!("{} is not a declared state", what)
+            };
+            syn::Error::new_spanned(&path[0], msg)
+        })?;
+    let mut is_region = false;
+
+    for ident in &path[1..] {
+        is_region = state.states.is_empty();
+        state = state.child(ident).ok_or_else(|| {
+            syn::Error::new_spanned(ident, format!("{} is not a declared state", what))
+        })?;
+    }
+
+    if is_region {
+        return Err(syn::Error::new_spanned(
+            path.last().unwrap(),
+            format!("{} must be a state, not a region", what),
+        ));
+    }
+
+    Ok(state)
+}
+
+fn analyze_target(
+    scope: &State,
+    path: &[syn::Ident],
+    target: &parse::TransitionTarget,
+) -> Result<Option<History>> {
+    let target = match target {
+        parse::TransitionTarget::State(target) => target,
+        parse::TransitionTarget::Final(_) => return Ok(None),
+    };
+
+    if path.len() == 1
+        && scope.pseudostate(&path[0]).map(|p| p.kind) == Some(PseudostateKind::EntryPoint)
+    {
+        return Err(syn::Error::new_spanned(
+            target,
+            "entry point can only be targeted from outside its state",
+        ));
+    }
+
+    // An entry point of a nested state, `State.EntryPoint`.  Other paths
+    // have to resolve to a state.
+    if let Some((ident, owner_path)) = path.split_last().filter(|(_, p)| !p.is_empty()) {
+        let entry_point = resolve_path(scope, owner_path, "transition target")
+            .ok()
+            .and_then(|owner| owner.pseudostate(ident));
+        if entry_point.map(|p| p.kind) == Some(PseudostateKind::EntryPoint) {
+            if target.history.is_some() {
+                return Err(syn::Error::new_spanned(
+                    target,
+                    "history target requires a composite state",
+                ));
+            }
+            return Ok(None);
+        }
+    }
+
+    if path.len() == 1 && scope.pseudostate(&path[0]).is_some() {
+        if target.history.is_some() {
+            return Err(syn::Error::new_spanned(
+                target,
+                "history target requires a composite state",
+            ));
+        }
+        return Ok(None);
+    }
+
+    let state = resolve_path(scope, path, "transition target")?;
+
+    let history = match &target.history {
+        None => None,
+        Some((_, history)) => {
+            if state.states.is_empty() && state.regions.is_empty() {
+                return Err(syn::Error::new_spanned(
+                    target,
+                    "history target requires a composite state",
+                ));
+            }
+            match history.asterisk_token {
+                None => Some(History::Shallow),
+                Some(_) => Some(History::Deep),
+            }
+        }
+    };
+
+    Ok(history)
 }
 
 fn analyze_event(event: &syn::Pat) -> (syn::Path, Option<syn::Pat>) {
@@ -381,7 +1498,7 @@ mod tests {
         assert_eq!(m.ident, "Foo");
         assert_eq!(m.state.states.len(), 2);
 
-        let s = m.state.states.values().find(|s| s.ident == "A").unwrap();
+        let s = m.state.states.iter().find(|s| s.ident == "A").unwrap();
         assert_eq!(s.out_transitions.len(), 3);
 
         let t = &s.out_transitions[0];
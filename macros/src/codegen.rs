@@ -1,6 +1,7 @@
-use quote::quote;
+use quote::{quote, quote_spanned};
+use syn::spanned::Spanned;
 
-use crate::lower;
+use crate::{analyze, dot, lower, mermaid, plantuml, pretty, scxml};
 
 pub fn generate(model: &lower::Model) -> proc_macro2::TokenStream {
     let mut tt = proc_macro2::TokenStream::default();
@@ -26,6 +27,25 @@ fn generate_machine(machine: &lower::TopMachine) -> proc_macro2::TokenStream {
         }
     });
 
+    let event_names = machine.events.iter().map(|(path, ident)| {
+        let name = pretty::tokens(path);
+        quote! {
+            Event::#ident(_) => #name
+        }
+    });
+
+    // Machines are built without an observer, and get one through
+    // `with_observer`.
+    let unobserved_params = generics.type_params().map(|p| &p.ident);
+    let unobserved_ty = quote! { <#(#unobserved_params,)* ::umlstate::NoObserver> };
+    let observed_params = generics.type_params().map(|p| &p.ident);
+    let observed_ty = quote! { <#(#observed_params,)* O> };
+    let mut generics = generics.clone();
+    generics.params.push(syn::parse_quote! {
+        Observer: ::umlstate::Observer = ::umlstate::NoObserver
+    });
+    let generics = &generics;
+    let (unobserved_impl_generics, _, _) = machine.generics.split_for_impl();
     let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
 
     let context_use;
@@ -34,85 +54,796 @@ fn generate_machine(machine: &lower::TopMachine) -> proc_macro2::TokenStream {
     let context_arg_sig;
     let context_field_init;
     let context_arg;
+    let context_access;
+    let restore_sig;
 
     let context_ident = &machine.context.ident;
-    let context_methods = &machine.context.methods;
+    // The futures of an `async fn` in a trait are not known to be `Send`,
+    // which only executors moving tasks between threads need.  A `Send`
+    // machine promises it in the signatures of its methods instead.
+    let context_methods: Vec<_> = match machine.is_send {
+        false => machine.context.methods.clone(),
+        true => machine.context.methods.iter().map(send_method).collect(),
+    };
+    let allow_async_fn =
+        (machine.is_async && !machine.is_send).then(|| quote! { #[allow(async_fn_in_trait)] });
+    // Machines embedding others act on their context as well.
+    let supertraits = &machine.context.supertraits;
+    // The context of the embedded machines will do for a machine without
+    // methods of its own.
+    let context_blanket = (context_methods.is_empty() && !supertraits.is_empty()).then(|| {
+        quote! {
+            impl<T: ?::core::marker::Sized #(+ #supertraits)*> #context_ident for T {}
+        }
+    });
+    let supertraits = (!supertraits.is_empty()).then(|| quote! { : #(#supertraits)+* });
     let context_decl = quote! {
-        pub trait #context_ident {
+        #allow_async_fn
+        pub trait #context_ident #supertraits {
             #(#context_methods)*
         }
+
+        #context_blanket
+    };
+    context_use = quote! {
+        #vis use #mod_name::#context_ident;
     };
 
     if let Some(zst) = &machine.context.zst {
-        context_use = None;
+        // Any context will do for machines embedding this one.
         context_zst = Some(quote! {
             struct #zst;
-            impl #context_ident for #zst {}
+            impl<T: ?::core::marker::Sized> #context_ident for T {}
         });
         context_field = quote! { #zst };
         context_arg_sig = quote! {};
         context_field_init = quote! { #zst };
-        context_arg = quote! { &self.context };
+        restore_sig = quote! {};
+        context_arg = quote! { &mut self.context };
+        context_access = None;
     } else {
-        context_use = Some(quote! {
-            #vis use #mod_name::#context_ident;
-        });
         context_zst = None;
-        context_arg_sig = quote! { context: Context };
+        context_arg_sig = quote! { context: Context, };
+        restore_sig = quote! { context: Context, };
         context_field = quote! { Context };
-        context_arg = quote! { &self.context };
+        context_arg = quote! { &mut self.context };
+        context_access = Some(quote! {
+            pub fn context(&self) -> &Context {
+                &self.context
+            }
+
+            pub fn context_mut(&mut self) -> &mut Context {
+                &mut self.context
+            }
+        });
         context_field_init = quote! { context };
     }
 
+    let timer_field;
+    let timer_arg_sig;
+    let timer_field_init;
+    let timer_access;
+    if machine.has_timers {
+        timer_field = quote! { Timer };
+        timer_arg_sig = Some(quote! { timer: Timer, });
+        timer_field_init = quote! { timer };
+        timer_access = Some(quote! {
+            pub fn timer(&self) -> &Timer {
+                &self.timer
+            }
+
+            pub fn timer_mut(&mut self) -> &mut Timer {
+                &mut self.timer
+            }
+        });
+    } else {
+        timer_field = quote! { ::umlstate::NoTimer };
+        timer_arg_sig = None;
+        timer_field_init = quote! { ::umlstate::NoTimer };
+        timer_access = None;
+    }
+
+    let spawner_field;
+    let spawner_arg_sig;
+    let spawner_field_init;
+    let spawner_access;
+    if machine.has_activities {
+        spawner_field = quote! { Spawner };
+        spawner_arg_sig = Some(quote! { spawner: Spawner, });
+        spawner_field_init = quote! { spawner };
+        spawner_access = Some(quote! {
+            pub fn spawner(&self) -> &Spawner {
+                &self.spawner
+            }
+
+            pub fn spawner_mut(&mut self) -> &mut Spawner {
+                &mut self.spawner
+            }
+        });
+    } else {
+        spawner_field = quote! { ::umlstate::NoSpawner };
+        spawner_arg_sig = None;
+        spawner_field_init = quote! { ::umlstate::NoSpawner };
+        spawner_access = None;
+    }
+
+    let machine_name = ident.to_string();
+    let observer_arg = if cfg!(feature = "tracing") {
+        quote! {
+            &mut ::umlstate::Traced {
+                machine: #machine_name,
+                observer: &mut self.observer,
+            }
+        }
+    } else {
+        quote! { &mut self.observer }
+    };
+    // Arguments of the methods of the embedded machine, and of the methods
+    // of states in turn.
+    let machine_args = quote! {
+        #context_arg, #observer_arg, &mut self.timer, &mut self.spawner
+    };
+    let embedded_args = quote! { ctx, observer, timer, spawner };
+    let state_args = quote! { ctx, &mut self.queue, observer, timer, spawner };
+    let embedded_params = quote! {
+        ctx: &mut impl #context_ident,
+        observer: &mut impl ::umlstate::Observer,
+        timer: &mut impl ::umlstate::Timer,
+        spawner: &mut impl ::umlstate::Spawner
+    };
+
+    let shared_events = machine.shared_events.iter().map(|event_ident| {
+        // Blames the event type, should it not be `Clone`.
+        let (path, _) = machine
+            .events
+            .iter()
+            .find(|(_, e)| e == event_ident)
+            .unwrap();
+        let share = quote_spanned! {path.span()=>
+            <#path as ::umlstate::SharedEvent>::share(event)
+        };
+        quote! {
+            Event::#event_ident(event) => {
+                ::core::option::Option::Some(Event::#event_ident(#share))
+            }
+        }
+    });
+
+    let event_from_impls = machine.events.iter().map(|(path, event_ident)| {
+        quote! {
+            impl ::core::convert::From<#path> for Event {
+                fn from(event: #path) -> Self {
+                    Event::#event_ident(event)
+                }
+            }
+        }
+    });
+
+    let asyncness = machine.is_async.then(|| quote! { async });
+    let await_ = generate_await(&machine.state);
+    let processor_trait = if machine.is_async {
+        quote! { ::umlstate::AsyncEventProcessor }
+    } else {
+        quote! { ::umlstate::EventProcessor }
+    };
+
+    let send_params = generics.type_params().map(|p| &p.ident);
+    let send_where_clause = quote! {
+        where #(#send_params: ::core::marker::Send,)*
+    };
+
     let process_impls = machine.events.iter().map(|(path, event_ident)| {
+        let mut process = quote! {
+            if let ::core::result::Result::Err(error) = self.check_running() {
+                return ::umlstate::ProcessResult::Error(error);
+            }
+            let result = self.inner.dispatch(#machine_args, Event::#event_ident(event))#await_;
+            self.inner.process_queue(#machine_args)#await_;
+            if self.inner.overflowed() {
+                return ::umlstate::ProcessResult::Error(::umlstate::Error::QueueFull);
+            }
+            result
+        };
+        if cfg!(feature = "tracing") {
+            let event_name = pretty::tokens(path);
+            let span = quote! {
+                ::umlstate::tracing::debug_span!(
+                    target: "umlstate",
+                    "process",
+                    machine = #machine_name,
+                    event = #event_name,
+                )
+            };
+            // A span entered across an `.await` would cover whatever else
+            // the executor runs meanwhile.
+            process = if machine.is_async {
+                quote! {
+                    ::umlstate::tracing::Instrument::instrument(async { #process }, #span).await
+                }
+            } else {
+                quote! {
+                    let _span = #span.entered();
+                    #process
+                }
+            };
+        }
+        let send_impl = machine.is_send.then(|| {
+            quote! {
+                impl #impl_generics ::umlstate::SendAsyncEventProcessor<#path> for #ident #ty_generics #send_where_clause {
+                    async fn process(&mut self, event: #path) -> ::umlstate::ProcessResult {
+                        ::umlstate::AsyncEventProcessor::process(self, event).await
+                    }
+                }
+            }
+        });
+        quote! {
+            impl #impl_generics #processor_trait<#path> for #ident #ty_generics #where_clause {
+                #asyncness fn process(&mut self, event: #path) -> ::umlstate::ProcessResult {
+                    #process
+                }
+            }
+
+            #send_impl
+        }
+    });
+
+    // Both processor traits apply to a `Send` machine, so `process` is also
+    // an inherent method to spare callers the ambiguity.
+    let send_process = machine.is_send.then(|| {
+        quote! {
+            pub async fn process<E>(&mut self, event: E) -> ::umlstate::ProcessResult
+            where
+                Self: ::umlstate::AsyncEventProcessor<E>,
+            {
+                ::umlstate::AsyncEventProcessor::process(self, event).await
+            }
+        }
+    });
+
+    let has_deferred_events = has_deferred_events(&machine.state);
+    let queue_capacity = &machine.queue_capacity;
+
+    let reset_changed;
+    let handle_result;
+    let deferred_field;
+    let deferred_init;
+    let deferred_clear;
+    let deferred_method;
+    let mark_changed;
+    let defer_method;
+    if has_deferred_events {
+        reset_changed = Some(quote! { self.queue.changed = false; });
+        handle_result = Some(quote! {
+            match result {
+                ::umlstate::ProcessResult::Handled => self.process_deferred(#embedded_args)#await_,
+                ::umlstate::ProcessResult::Deferred => {
+                    if let ::core::option::Option::Some(e) = event.take() {
+                        self.queue.defer(::core::option::Option::None, e);
+                    }
+                }
+                ::umlstate::ProcessResult::Unhandled | ::umlstate::ProcessResult::Error(_) => (),
+            }
+        });
+        // Events are deferred by every region or by some regions only, the
+        // others having processed them already.
+        deferred_field = Some(quote! {
+            deferred: ::umlstate::Queue<(::core::option::Option<usize>, Event), { #queue_capacity }>,
+            changed: bool,
+        });
+        deferred_init = Some(quote! {
+            deferred: ::umlstate::Queue::new(),
+            changed: false,
+        });
+        deferred_clear = Some(quote! { self.queue.deferred.clear(); });
+        mark_changed = Some(quote! { self.changed = true; });
+        defer_method = Some(quote! {
+            /// Keeps `event` until the state configuration changed,
+            /// dropping it if the queue is full.
+            fn defer(&mut self, region: ::core::option::Option<usize>, event: Event) {
+                if self.deferred.push_back((region, event)).is_err() {
+                    self.overflowed = true;
+                }
+            }
+        });
+        let process_region = if has_deferring_regions(&machine.state) {
+            quote! { self.state.process_region_event(region, #state_args, &mut event)#await_ }
+        } else {
+            quote! { ::umlstate::ProcessResult::Unhandled }
+        };
+        deferred_method = Some(quote! {
+            /// Re-dispatches deferred events in their original order once
+            /// the state configuration changed, starting over whenever one
+            /// of them changes it again.
+            #asyncness fn process_deferred(&mut self, #embedded_params) {
+                while ::core::mem::take(&mut self.queue.changed) {
+                    let mut pending = ::core::mem::take(&mut self.queue.deferred);
+                    while let ::core::option::Option::Some((region, event)) = pending.pop_front() {
+                        let mut event = ::core::option::Option::Some(event);
+                        let result = match region {
+                            ::core::option::Option::None => {
+                                self.state.process_event(#state_args, &mut event)#await_
+                            }
+                            ::core::option::Option::Some(region) => #process_region,
+                        };
+                        if result == ::umlstate::ProcessResult::Deferred {
+                            if let ::core::option::Option::Some(e) = event {
+                                self.queue.defer(region, e);
+                            }
+                        }
+                        if self.queue.changed {
+                            break;
+                        }
+                    }
+                    for (region, event) in pending.drain() {
+                        self.queue.defer(region, event);
+                    }
+                }
+            }
+        });
+    } else {
+        reset_changed = None;
+        handle_result = None;
+        deferred_field = None;
+        deferred_init = None;
+        deferred_clear = None;
+        deferred_method = None;
+        mark_changed = None;
+        defer_method = None;
+    }
+
+    let embedded_type = &machine.embedded_type;
+    // Timers and activities of an embedded machine would share their ids
+    // with those of the embedding machine, so it gets none.
+    let embedding_impls = (!machine.has_timers && !machine.has_activities).then(|| {
+        let (submachine_trait, forward_trait) = match machine.is_async {
+            false => (quote! { Submachine }, quote! { Forward }),
+            true => (quote! { AsyncSubmachine }, quote! { AsyncForward }),
+        };
+        let forward_impls = machine.events.iter().map(|(path, event_ident)| {
+            quote! {
+                impl<C: #context_ident> ::umlstate::#forward_trait<#path, C> for #embedded_type {
+                    #asyncness fn forward(
+                        &mut self,
+                        ctx: &mut C,
+                        observer: &mut impl ::umlstate::Observer,
+                        event: &mut ::core::option::Option<#path>,
+                    ) -> ::umlstate::ProcessResult {
+                        let mut offered = match event.take() {
+                            ::core::option::Option::Some(e) => {
+                                ::core::option::Option::Some(Event::#event_ident(e))
+                            }
+                            ::core::option::Option::None => {
+                                return ::umlstate::ProcessResult::Unhandled;
+                            }
+                        };
+                        let timer = &mut ::umlstate::NoTimer;
+                        let spawner = &mut ::umlstate::NoSpawner;
+                        let result = self.handle(#embedded_args, &mut offered)#await_;
+                        #[allow(irrefutable_let_patterns)]
+                        if let ::core::option::Option::Some(Event::#event_ident(e)) = offered {
+                            *event = ::core::option::Option::Some(e);
+                        }
+                        self.process_queue(#embedded_args)#await_;
+                        if self.overflowed() {
+                            return ::umlstate::ProcessResult::Error(::umlstate::Error::QueueFull);
+                        }
+                        result
+                    }
+                }
+            }
+        });
         quote! {
-            impl #impl_generics ::umlstate::EventProcessor<#path> for #ident #ty_generics #where_clause {
-                fn process(&mut self, event: #path) -> ::umlstate::ProcessResult {
-                    self.state.process_event(#context_arg, Event::#event_ident(event))
+            impl<C: #context_ident> ::umlstate::#submachine_trait<C> for #embedded_type {
+                #asyncness fn enter_in(
+                    &mut self,
+                    ctx: &mut C,
+                    observer: &mut impl ::umlstate::Observer,
+                ) -> ::core::result::Result<(), ::umlstate::Error> {
+                    self.enter(ctx, observer, &mut ::umlstate::NoTimer, &mut ::umlstate::NoSpawner)#await_;
+                    if self.overflowed() {
+                        return ::core::result::Result::Err(::umlstate::Error::QueueFull);
+                    }
+                    ::core::result::Result::Ok(())
+                }
+
+                #asyncness fn exit_in(&mut self, ctx: &mut C, observer: &mut impl ::umlstate::Observer) {
+                    self.exit(ctx, observer, &mut ::umlstate::NoTimer, &mut ::umlstate::NoSpawner)#await_;
                 }
             }
+
+            #(#forward_impls)*
         }
     });
 
+    let dot = dot::generate(machine);
+    let plantuml = plantuml::generate(machine);
+    let mermaid = mermaid::generate(machine);
+    let scxml = scxml::generate(machine);
+
+    let path_type = &machine.path_type;
+    let mut state_paths = vec![];
+    let active_states = generate_state_paths(
+        path_type,
+        &machine.state,
+        quote! { self.inner.state },
+        quote! { #state_mod_name },
+        &[],
+        None,
+        &mut state_paths,
+    );
+    let path_count = state_paths.len();
+    let path_decl = state_paths.iter().map(|(variant, _)| variant);
+    let path_arms = state_paths.iter().map(|(variant, is_in)| {
+        quote! { #path_type::#variant => #is_in }
+    });
+
     let state_decl = generate_state(&machine.state);
     let topmachine_state = &machine.state.state_type;
+    let topmachine_snapshot = &machine.state.snapshot_type;
 
     quote! {
         mod #mod_name {
             use super::*;
-            use ::std::ops::DerefMut;
+            use ::core::ops::DerefMut;
 
-            #[derive(Clone)]
             enum Event {
                 #(#event_decl),*
             }
 
+            impl Event {
+                /// Copies events handled in several orthogonal regions, so
+                /// that each region can take ownership of its own.
+                #[allow(dead_code, unreachable_patterns)]
+                fn share(&self) -> ::core::option::Option<Event> {
+                    match self {
+                        #(#shared_events,)*
+                        _ => ::core::option::Option::None,
+                    }
+                }
+
+                /// Name of the event type, as shown to observers.
+                fn name(&self) -> &'static str {
+                    match *self {
+                        #(#event_names),*
+                    }
+                }
+            }
+
+            #(#event_from_impls)*
+
+            /// Events posted by actions, dispatched once the current
+            /// run-to-completion step is done.
+            struct EventQueue {
+                events: ::umlstate::Queue<Event, { #queue_capacity }>,
+                #deferred_field
+                /// Whether an event was dropped, its queue being full.
+                overflowed: bool,
+            }
+
+            impl EventQueue {
+                /// Tells that a state was entered, so that the deferred
+                /// events are dispatched again.
+                #[allow(dead_code)]
+                fn entered(&mut self) {
+                    #mark_changed
+                }
+
+                /// Dispatches `event` once the current step is done.  The
+                /// event is dropped if the queue is full, and processing
+                /// fails with `Error::QueueFull`.
+                #[allow(dead_code)]
+                fn post<E>(&mut self, event: E)
+                where
+                    Event: ::core::convert::From<E>,
+                {
+                    if self.events.push_back(event.into()).is_err() {
+                        self.overflowed = true;
+                    }
+                }
+
+                /// Like `post`, failing instead of dropping the event when
+                /// the queue is full.
+                #[allow(dead_code)]
+                fn try_post<E>(&mut self, event: E) -> ::core::result::Result<(), ::umlstate::Error>
+                where
+                    Event: ::core::convert::From<E>,
+                {
+                    self.events
+                        .push_back(event.into())
+                        .map_err(|_| ::umlstate::Error::QueueFull)
+                }
+
+                #defer_method
+            }
+
+            /// Every state and region of the machine, named by its path.
+            #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+            pub enum #path_type {
+                #(#path_decl),*
+            }
+
             #context_decl
             #context_zst
 
-            pub struct #ident #impl_generics #where_clause {
+            pub struct #ident #generics #where_clause {
                 context: #context_field,
+                inner: #embedded_type,
+                observer: Observer,
+                timer: #timer_field,
+                spawner: #spawner_field,
+            }
+
+            /// The machine without its context, observer, timer and
+            /// spawner, which the submachine states of other machines
+            /// embed.
+            #[doc(hidden)]
+            pub struct #embedded_type {
                 state: #state_mod_name::#state_ident,
+                queue: EventQueue,
             }
 
-            impl #impl_generics #ident #ty_generics #where_clause {
-                pub fn new(#context_arg_sig) -> Self {
+            #embedding_impls
+
+            #[allow(dead_code)]
+            impl #embedded_type {
+                pub fn new() -> Self {
                     Self {
-                        context: #context_field_init,
                         state: #state_mod_name::#state_ident::new(),
+                        queue: EventQueue {
+                            events: ::umlstate::Queue::new(),
+                            #deferred_init
+                            overflowed: false,
+                        },
+                    }
+                }
+
+                pub fn restore(snapshot: #state_mod_name::#topmachine_snapshot) -> Self {
+                    Self {
+                        state: #state_mod_name::#state_ident::restore(snapshot),
+                        queue: EventQueue {
+                            events: ::umlstate::Queue::new(),
+                            #deferred_init
+                            overflowed: false,
+                        },
+                    }
+                }
+
+                pub fn snapshot(&self) -> #state_mod_name::#topmachine_snapshot {
+                    self.state.snapshot()
+                }
+
+                pub fn is_complete(&self) -> bool {
+                    self.state.is_complete()
+                }
+
+                pub fn is_consistent(&self, active: bool) -> bool {
+                    self.state.is_consistent(active)
+                }
+
+                fn resume(&mut self, #embedded_params) {
+                    self.state.resume(#state_args);
+                }
+
+                #asyncness fn enter(&mut self, #embedded_params) {
+                    self.state.enter(#state_args)#await_;
+                    self.process_queue(#embedded_args)#await_;
+                }
+
+                #asyncness fn exit(&mut self, #embedded_params) {
+                    self.state.exit(#state_args)#await_;
+                    self.queue.events.clear();
+                    #deferred_clear
+                    self.queue.overflowed = false;
+                }
+
+                /// Whether an event was dropped since the last call, its
+                /// queue being full.
+                fn overflowed(&mut self) -> bool {
+                    ::core::mem::take(&mut self.queue.overflowed)
+                }
+
+                #asyncness fn dispatch(&mut self, #embedded_params, event: Event) -> ::umlstate::ProcessResult {
+                    let name = event.name();
+                    observer.on_event_received(name);
+                    let result = self
+                        .handle(#embedded_args, &mut ::core::option::Option::Some(event))
+                        #await_;
+                    if result == ::umlstate::ProcessResult::Unhandled {
+                        observer.on_unhandled(name);
+                    }
+                    result
+                }
+
+                /// Processes `event`, keeping it if it is deferred and
+                /// leaving it in place if it is not handled.
+                #asyncness fn handle(
+                    &mut self,
+                    #embedded_params,
+                    event: &mut ::core::option::Option<Event>,
+                ) -> ::umlstate::ProcessResult {
+                    #reset_changed
+                    let result = self.state.process_event(#state_args, event)#await_;
+                    #handle_result
+                    result
+                }
+
+                #asyncness fn process_queue(&mut self, #embedded_params) {
+                    while let ::core::option::Option::Some(event) = self.queue.events.pop_front() {
+                        self.dispatch(#embedded_args, event)#await_;
+                    }
+                }
+
+                #deferred_method
+            }
+
+            impl #unobserved_impl_generics #ident #unobserved_ty #where_clause {
+                /// Graphviz DOT rendering of the machine.
+                pub const DOT: &'static str = #dot;
+
+                /// PlantUML state diagram of the machine.
+                pub const PLANTUML: &'static str = #plantuml;
+
+                /// Mermaid state diagram of the machine.
+                pub const MERMAID: &'static str = #mermaid;
+
+                /// SCXML document of the machine.
+                pub const SCXML: &'static str = #scxml;
+
+                pub fn new(#context_arg_sig #timer_arg_sig #spawner_arg_sig) -> Self {
+                    Self {
+                        context: #context_field_init,
+                        inner: #embedded_type::new(),
+                        observer: ::umlstate::NoObserver,
+                        timer: #timer_field_init,
+                        spawner: #spawner_field_init,
+                    }
+                }
+
+                /// Rebuilds a machine in the configuration captured by
+                /// `snapshot`, without running any entry behavior.  The
+                /// timers of the active states are armed again for their
+                /// whole duration, and their unfinished do-activities are
+                /// started again.
+                ///
+                /// Fails with [`Error::InvalidSnapshot`](::umlstate::Error)
+                /// if the snapshot leaves an active composite state, region
+                /// or submachine without an active sub-state, or an inactive
+                /// one with an active sub-state.
+                pub fn restore(#restore_sig #timer_arg_sig #spawner_arg_sig snapshot: #state_mod_name::#topmachine_snapshot) -> ::core::result::Result<Self, ::umlstate::Error> {
+                    let inner = #embedded_type::restore(snapshot);
+                    if !inner.is_consistent(inner.state.state.is_some()) {
+                        return ::core::result::Result::Err(::umlstate::Error::InvalidSnapshot);
+                    }
+                    let mut machine = Self {
+                        context: #context_field_init,
+                        inner,
+                        observer: ::umlstate::NoObserver,
+                        timer: #timer_field_init,
+                        spawner: #spawner_field_init,
+                    };
+                    machine.resume();
+                    ::core::result::Result::Ok(machine)
+                }
+            }
+
+            impl #impl_generics #ident #ty_generics #where_clause {
+                /// Hands everything the machine does to `observer`.
+                pub fn with_observer<O: ::umlstate::Observer>(self, observer: O) -> #ident #observed_ty {
+                    #ident {
+                        context: self.context,
+                        inner: self.inner,
+                        observer,
+                        timer: self.timer,
+                        spawner: self.spawner,
+                    }
+                }
+
+                pub fn observer(&self) -> &Observer {
+                    &self.observer
+                }
+
+                pub fn observer_mut(&mut self) -> &mut Observer {
+                    &mut self.observer
+                }
+
+                pub fn state(&self) -> ::core::option::Option<#state_mod_name::#topmachine_state> {
+                    self.inner.state.state()
+                }
+
+                /// Whether the state or region at `path` is active.
+                pub fn is_in(&self, path: #path_type) -> bool {
+                    match path {
+                        #(#path_arms),*
+                    }
+                }
+
+                /// The active states and regions, each one before the state
+                /// containing it.
+                pub fn active_states(&self) -> impl ::core::iter::Iterator<Item = #path_type> {
+                    // Has room for every path, so pushing never fails.
+                    let mut states = ::umlstate::heapless::Vec::<#path_type, #path_count>::new();
+                    #active_states
+                    states.into_iter()
+                }
+
+                /// Captures the active state configuration, including the
+                /// history of every composite state.  Queued and deferred
+                /// events are not part of a snapshot.
+                pub fn snapshot(&self) -> #state_mod_name::#topmachine_snapshot {
+                    self.inner.snapshot()
+                }
+
+                #context_access
+
+                #timer_access
+
+                #spawner_access
+
+                /// Enters the machine.  Only `try_enter` reports the events
+                /// posted on entry that were dropped, the queue being full.
+                pub #asyncness fn enter(&mut self) {
+                    self.inner.enter(#machine_args)#await_;
+                    self.inner.queue.overflowed = false;
+                }
+
+                fn resume(&mut self) {
+                    self.inner.resume(#machine_args);
+                }
+
+                pub #asyncness fn exit(&mut self) {
+                    self.inner.exit(#machine_args)#await_;
+                }
+
+                /// Like `enter`, failing instead of panicking when the
+                /// machine is active already.
+                pub #asyncness fn try_enter(&mut self) -> ::core::result::Result<(), ::umlstate::Error> {
+                    if self.state().is_some() {
+                        return ::core::result::Result::Err(::umlstate::Error::AlreadyActive);
                     }
+                    self.inner.enter(#machine_args)#await_;
+                    if self.inner.overflowed() {
+                        return ::core::result::Result::Err(::umlstate::Error::QueueFull);
+                    }
+                    ::core::result::Result::Ok(())
                 }
 
-                pub fn state(&self) -> ::std::option::Option<#state_mod_name::#topmachine_state> {
-                    self.state.state()
+                /// Like `exit`, failing instead of panicking when the
+                /// machine is not active.
+                pub #asyncness fn try_exit(&mut self) -> ::core::result::Result<(), ::umlstate::Error> {
+                    if self.state().is_none() {
+                        return ::core::result::Result::Err(::umlstate::Error::NotActive);
+                    }
+                    self.exit()#await_;
+                    ::core::result::Result::Ok(())
                 }
 
-                pub fn enter(&mut self) {
-                    self.state.enter(#context_arg);
+                #send_process
+
+                /// Like `process`, with the reason the machine could not
+                /// process the event as an error.
+                pub #asyncness fn try_process<E>(
+                    &mut self,
+                    event: E,
+                ) -> ::core::result::Result<::umlstate::ProcessResult, ::umlstate::Error>
+                where
+                    Self: #processor_trait<E>,
+                {
+                    match #processor_trait::process(self, event)#await_ {
+                        ::umlstate::ProcessResult::Error(error) => ::core::result::Result::Err(error),
+                        result => ::core::result::Result::Ok(result),
+                    }
                 }
 
-                pub fn exit(&mut self) {
-                    self.state.exit(#context_arg);
+                /// Events are only processed between `enter` and `exit`,
+                /// until a final state of the machine is reached.
+                fn check_running(&self) -> ::core::result::Result<(), ::umlstate::Error> {
+                    if self.state().is_none() {
+                        ::core::result::Result::Err(::umlstate::Error::NotStarted)
+                    } else if self.inner.is_complete() {
+                        ::core::result::Result::Err(::umlstate::Error::Terminated)
+                    } else {
+                        ::core::result::Result::Ok(())
+                    }
                 }
             }
 
@@ -122,17 +853,26 @@ fn generate_machine(machine: &lower::TopMachine) -> proc_macro2::TokenStream {
         }
 
         #vis use #mod_name::#state_mod_name::#topmachine_state;
+        #vis use #mod_name::#state_mod_name::#topmachine_snapshot;
+        #vis use #mod_name::#path_type;
         #vis use #mod_name::#ident;
+        #[doc(hidden)]
+        #vis use #mod_name::#embedded_type;
         #context_use
     }
 }
 
 fn generate_state(state: &lower::State) -> proc_macro2::TokenStream {
+    let await_ = generate_await(state);
+    let asyncness = state.is_async.then(|| quote! { async });
     let state_name = &state.ident;
     let root_path = &state.root_path;
     let mod_name = &state.mod_name;
     let state_type = &state.state_type;
+    let snapshot_type = &state.snapshot_type;
     let context_type = &state.context_type;
+    let internal_vis = quote! { pub(in #root_path) };
+    let params = generate_params(context_type);
 
     let invalid_event_state_str = format!("{} received event while in invalid state", state_name);
 
@@ -149,12 +889,53 @@ fn generate_state(state: &lower::State) -> proc_macro2::TokenStream {
         let state_ident = &s.ident;
         let field_ident = &s.field_ident;
         quote! {
-            #field_ident: #state_mod::#state_ident
+            #internal_vis #field_ident: #state_mod::#state_ident
         }
     });
 
     let states = states_or_regions.iter().map(|m| generate_state(m));
 
+    let snapshot_fields = states_or_regions.iter().map(|s| {
+        let state_mod = &s.mod_name;
+        let snapshot_type = &s.snapshot_type;
+        let field_ident = &s.field_ident;
+        quote! {
+            pub #field_ident: #state_mod::#snapshot_type
+        }
+    });
+
+    let snapshot_init = states_or_regions.iter().map(|s| {
+        let field_ident = &s.field_ident;
+        quote! {
+            #field_ident: self.#field_ident.snapshot()
+        }
+    });
+
+    let restore_init = states_or_regions.iter().map(|s| {
+        let type_ident = &s.ident;
+        let mod_name = &s.mod_name;
+        let field_ident = &s.field_ident;
+        quote! {
+            #field_ident: #mod_name::#type_ident::restore(snapshot.#field_ident)
+        }
+    });
+
+    // A sub-state is active exactly when it is the current one, and a
+    // region exactly when its state is.
+    let consistency = states_or_regions.iter().map(|s| {
+        let field_ident = &s.field_ident;
+        if state.states.is_empty() {
+            quote! { && self.#field_ident.is_consistent(active) }
+        } else {
+            let ident = &s.ident;
+            quote! {
+                && self.#field_ident.is_consistent(
+                    self.state == ::core::option::Option::Some(#state_type::#ident),
+                )
+            }
+        }
+    });
+
     let states_init = states_or_regions.iter().map(|s| {
         let type_ident = &s.ident;
         let mod_name = &s.mod_name;
@@ -168,46 +949,343 @@ fn generate_state(state: &lower::State) -> proc_macro2::TokenStream {
         let state_name = &sub_state.ident;
         let field_ident = &sub_state.field_ident;
 
-        let transitions = sub_state
+        let mut transitions: Vec<_> = sub_state
             .out_transitions
             .iter()
-            .map(|t| generate_transition(state, sub_state, t));
+            .filter(|t| t.event.is_some())
+            .collect();
+        // Transitions from deeper nested sources take priority.
+        transitions.sort_by_key(|t| std::cmp::Reverse(t.source_path.len()));
+        let transitions: Vec<_> = transitions
+            .into_iter()
+            .map(|t| generate_transition(state, sub_state, t))
+            .collect();
+
+        let process_transitions = if transitions.is_empty() {
+            quote! { ::umlstate::ProcessResult::Unhandled }
+        } else {
+            quote! {
+                match event.take() {
+                    #(#transitions,)*
+                    other => {
+                        *event = other;
+                        ::umlstate::ProcessResult::Unhandled
+                    }
+                }
+            }
+        };
 
         quote! {
             #state_type::#state_name => {
-                match self.#field_ident.process_event(ctx, event.clone()) {
-                    ::umlstate::ProcessResult::Handled => ::umlstate::ProcessResult::Handled,
-                    ::umlstate::ProcessResult::Unhandled => {
-                        match event.clone() {
-                            #(#transitions),*
-                            _ => ::umlstate::ProcessResult::Unhandled,
-                        }
-                    }
+                match self.#field_ident.process_event(ctx, queue, observer, timer, spawner, event)#await_ {
+                    ::umlstate::ProcessResult::Unhandled => #process_transitions,
+                    result => result,
                 }
             }
         }
     });
 
+    let last_region = state.regions.last().map(|r| &r.ident);
+    // Events deferred by some regions only, while others handled them.
+    let region_deferrals: Vec<_> = state
+        .regions
+        .iter()
+        .filter(|r| has_deferred_events(r))
+        .map(|r| (r, quote::format_ident!("deferred_{}", r.field_ident)))
+        .collect();
     let process_regions = state.regions.iter().map(|r| {
         let field_ident = &r.field_ident;
-
-        quote! {
-            {
-                let r = self.#field_ident.process_event(ctx, event.clone());
-                if r == ::umlstate::ProcessResult::Handled {
+        let deferral = region_deferrals
+            .iter()
+            .find(|(d, _)| d.ident == r.ident)
+            .map(|(_, var)| var);
+
+        // All but the last region get a copy of shared events and leave the
+        // original to the regions after them.
+        let process = if Some(&r.ident) == last_region {
+            let keep = deferral.map(|var| {
+                quote! {
+                    if r == ::umlstate::ProcessResult::Deferred
+                        && result == ::umlstate::ProcessResult::Handled
+                    {
+                        #var = event.take();
+                    }
+                }
+            });
+            quote! {
+                let r = self.#field_ident.process_event(ctx, queue, observer, timer, spawner, event)#await_;
+                #keep
+            }
+        } else {
+            let keep = deferral.map(|var| {
+                quote! {
+                    if r == ::umlstate::ProcessResult::Deferred && shared.is_some() {
+                        #var = shared;
+                    }
+                }
+            });
+            quote! {
+                let mut shared = event.as_ref().and_then(Event::share);
+                let r = if shared.is_some() {
+                    self.#field_ident.process_event(ctx, queue, observer, timer, spawner, &mut shared)#await_
+                } else {
+                    self.#field_ident.process_event(ctx, queue, observer, timer, spawner, event)#await_
+                };
+                #keep
+            }
+        };
+
+        quote! {
+            {
+                #process
+                if r == ::umlstate::ProcessResult::Handled || result == ::umlstate::ProcessResult::Unhandled {
                     result = r;
                 }
             }
         }
     });
+    let declare_deferrals = region_deferrals.iter().map(|(_, var)| {
+        quote! { let mut #var = ::core::option::Option::None; }
+    });
+    // Once another region handled it, the event is kept for those that
+    // deferred it alone.
+    let keep_deferrals = (!region_deferrals.is_empty()).then(|| {
+        let keep = region_deferrals.iter().map(|(r, var)| {
+            let index = r.index;
+            quote! {
+                if let ::core::option::Option::Some(e) = #var {
+                    queue.defer(::core::option::Option::Some(#index), e);
+                }
+            }
+        });
+        quote! {
+            if result == ::umlstate::ProcessResult::Handled {
+                #(#keep)*
+            }
+        }
+    });
 
     let internal_transitions = state
         .internal_transitions
         .iter()
         .map(|t| generate_internal_transition(state, t));
 
+    let deferred_events = state.deferred_events.iter().map(|d| {
+        let event = &d.event;
+        let event_pat = match &d.event_pat {
+            Some(p) => quote! { #p },
+            None => quote! { _ },
+        };
+        quote! {
+            #[allow(unused_variables)]
+            ::core::option::Option::Some(Event::#event(#event_pat)) => ::umlstate::ProcessResult::Deferred
+        }
+    });
+
     let enter_action = generate_entry(state, state.initial_transition.as_ref());
     let exit_action = generate_exit(state);
+    let resume_action = generate_resume(state);
+    let region_routing = generate_region_routing(state);
+    let is_complete = generate_is_complete(state);
+
+    let process_completion;
+    let completion_method;
+    if has_completion_transitions(state) {
+        let completion_action = generate_completion(state);
+        process_completion =
+            Some(quote! { self.process_completion(ctx, queue, observer, timer, spawner)#await_; });
+        completion_method = Some(quote! {
+            #internal_vis #asyncness fn process_completion(&mut self, #params) {
+                #completion_action
+            }
+        });
+    } else {
+        process_completion = None;
+        completion_method = None;
+    }
+
+    let history_field;
+    let history_init;
+    let history_snapshot_field;
+    let history_snapshot;
+    let history_restore;
+    if state.states.is_empty() {
+        history_field = None;
+        history_init = None;
+        history_snapshot_field = None;
+        history_snapshot = None;
+        history_restore = None;
+    } else {
+        history_field =
+            Some(quote! { #internal_vis history: ::core::option::Option<#state_type>, });
+        history_init = Some(quote! { history: ::core::option::Option::None, });
+        history_snapshot_field = Some(quote! { pub history: ::core::option::Option<#state_type>, });
+        history_snapshot = Some(quote! { history: self.history.clone(), });
+        history_restore = Some(quote! { history: snapshot.history, });
+    }
+
+    // The generation of the timers of the timed transitions leaving the
+    // state, counting its entries.
+    let timer_field;
+    let timer_init;
+    let timer_snapshot_field;
+    let timer_snapshot;
+    let timer_restore;
+    if state.timers.is_empty() {
+        timer_field = None;
+        timer_init = None;
+        timer_snapshot_field = None;
+        timer_snapshot = None;
+        timer_restore = None;
+    } else {
+        timer_field = Some(quote! { #internal_vis timer_generation: u32, });
+        timer_init = Some(quote! { timer_generation: 0, });
+        timer_snapshot_field = Some(quote! { pub timer_generation: u32, });
+        timer_snapshot = Some(quote! { timer_generation: self.timer_generation, });
+        timer_restore = Some(quote! { timer_generation: snapshot.timer_generation, });
+    }
+
+    // Whether the do-activity of an async state finished since the state was
+    // entered.
+    let activity_event = state
+        .activity
+        .as_ref()
+        .and_then(|a| Some((a.id, a.event.as_ref()?)));
+    let activity_field;
+    let activity_init;
+    let activity_snapshot_field;
+    let activity_snapshot;
+    let activity_restore;
+    let activity_done;
+    if let Some((id, event)) = activity_event {
+        activity_field = Some(quote! {
+            #internal_vis activity_done: bool,
+            #internal_vis activity_generation: u32,
+        });
+        activity_init = Some(quote! {
+            activity_done: false,
+            activity_generation: 0,
+        });
+        activity_snapshot_field = Some(quote! {
+            pub activity_done: bool,
+            pub activity_generation: u32,
+        });
+        activity_snapshot = Some(quote! {
+            activity_done: self.activity_done,
+            activity_generation: self.activity_generation,
+        });
+        activity_restore = Some(quote! {
+            activity_done: snapshot.activity_done,
+            activity_generation: snapshot.activity_generation,
+        });
+        // Only the run started by the last entry completes the state.
+        activity_done = Some(quote! {
+            ::core::option::Option::Some(Event::#event(::umlstate::ActivityId {
+                index: #id,
+                generation,
+            })) if generation == self.activity_generation => {
+                self.activity_done = true;
+                ::umlstate::ProcessResult::Handled
+            }
+        });
+    } else {
+        activity_field = None;
+        activity_init = None;
+        activity_snapshot_field = None;
+        activity_snapshot = None;
+        activity_restore = None;
+        activity_done = None;
+    }
+
+    // The machine embedded by a submachine state, which is offered every
+    // event reaching the state.
+    let submachine_field;
+    let submachine_init;
+    let submachine_snapshot_field;
+    let submachine_snapshot;
+    let submachine_restore;
+    let submachine_consistency;
+    let process_submachine;
+    if let Some(submachine) = &state.submachine {
+        let embedded_type = &submachine.embedded_type;
+        let snapshot_type = &submachine.snapshot_type;
+        submachine_field = Some(quote! { #internal_vis machine: #embedded_type, });
+        submachine_init = Some(quote! { machine: #embedded_type::new(), });
+        submachine_snapshot_field = Some(quote! { pub machine: #snapshot_type, });
+        submachine_snapshot = Some(quote! { machine: self.machine.snapshot(), });
+        submachine_restore = Some(quote! { machine: #embedded_type::restore(snapshot.machine), });
+        submachine_consistency = Some(quote! { && self.machine.is_consistent(active) });
+        let forward_trait = match state.is_async {
+            false => quote! { Forward },
+            true => quote! { AsyncForward },
+        };
+        let offers = submachine.events.iter().map(|(path, event_ident)| {
+            // Blames the `forward` of an event the embedded machine lacks.
+            let forward = quote_spanned! {path.span()=>
+                ::umlstate::#forward_trait::<#path, _>::forward(&mut self.machine, ctx, observer, &mut e)
+            };
+            quote! {
+                ::core::option::Option::Some(Event::#event_ident(e)) => {
+                    let mut e = ::core::option::Option::Some(e);
+                    let result = #forward #await_;
+                    *event = e.map(Event::#event_ident);
+                    result
+                }
+            }
+        });
+        process_submachine = Some(quote! {
+            result = match event.take() {
+                #(#offers)*
+                other => {
+                    *event = other;
+                    ::umlstate::ProcessResult::Unhandled
+                }
+            };
+        });
+    } else {
+        submachine_field = None;
+        submachine_init = None;
+        submachine_snapshot_field = None;
+        submachine_snapshot = None;
+        submachine_restore = None;
+        submachine_consistency = None;
+        process_submachine = None;
+    }
+
+    let serde_derive = generate_serde_derive();
+
+    let history_methods = if state.states.is_empty() && state.regions.is_empty() {
+        None
+    } else {
+        let shallow = generate_history_entry(state, analyze::History::Shallow);
+        let deep = generate_history_entry(state, analyze::History::Deep);
+        Some(quote! {
+            #[allow(dead_code)]
+            #internal_vis #asyncness fn enter_shallow_history(&mut self, #params) {
+                #shallow
+            }
+
+            #[allow(dead_code)]
+            #internal_vis #asyncness fn enter_deep_history(&mut self, #params) {
+                #deep
+            }
+        })
+    };
+
+    let entry_point_methods = state
+        .pseudostates
+        .iter()
+        .filter(|p| p.kind == analyze::PseudostateKind::EntryPoint)
+        .map(|p| {
+            let method = entry_point_method(&p.ident);
+            let enter = generate_entry_point(state, p);
+            quote! {
+                #[allow(dead_code)]
+                #internal_vis #asyncness fn #method(&mut self, #params) {
+                    #enter
+                }
+            }
+        });
 
     let active_state_decl = if state.states.is_empty() {
         Some(quote! { Active })
@@ -219,7 +1297,10 @@ fn generate_state(state: &lower::State) -> proc_macro2::TokenStream {
         quote! {
             #state_type::Active => {
                 let mut result = ::umlstate::ProcessResult::Unhandled;
+                #(#declare_deferrals)*
                 #(#process_regions)*
+                #keep_deferrals
+                #process_submachine
                 result
             }
         }
@@ -232,33 +1313,81 @@ fn generate_state(state: &lower::State) -> proc_macro2::TokenStream {
             use super::*;
 
             #[derive(Clone, Debug, PartialEq)]
+            #serde_derive
             pub enum #state_type {
                 #(#state_decl),*
                 #active_state_decl
             }
 
+            /// Active sub-state and history of the state, along with the
+            /// snapshots of its sub-states and regions.
+            #[derive(Clone, Debug, PartialEq)]
+            #serde_derive
+            pub struct #snapshot_type {
+                pub state: ::core::option::Option<#state_type>,
+                #history_snapshot_field
+                #timer_snapshot_field
+                #activity_snapshot_field
+                #submachine_snapshot_field
+                #(#snapshot_fields),*
+            }
+
             pub(in #root_path::super) struct #state_name {
-                state: ::std::option::Option<#state_type>,
+                #internal_vis state: ::core::option::Option<#state_type>,
+                #history_field
+                #timer_field
+                #activity_field
+                #submachine_field
                 #(#state_fields),*
             }
 
             impl #state_name {
                 pub fn new() -> Self {
                     Self {
-                        state: ::std::option::Option::None,
+                        state: ::core::option::Option::None,
+                        #history_init
+                        #timer_init
+                        #activity_init
+                        #submachine_init
                         #(#states_init),*
                     }
                 }
 
-                pub fn state(&self) -> ::std::option::Option<#state_type> {
+                pub fn restore(snapshot: #snapshot_type) -> Self {
+                    Self {
+                        state: snapshot.state,
+                        #history_restore
+                        #timer_restore
+                        #activity_restore
+                        #submachine_restore
+                        #(#restore_init),*
+                    }
+                }
+
+                pub fn state(&self) -> ::core::option::Option<#state_type> {
                     self.state.clone()
                 }
 
-                pub(super) fn process_event(&mut self, ctx: &impl #context_type, event: Event) -> ::umlstate::ProcessResult {
-                    let state = if let ::std::option::Option::Some(s) = &self.state {
+                pub fn snapshot(&self) -> #snapshot_type {
+                    #snapshot_type {
+                        state: self.state.clone(),
+                        #history_snapshot
+                        #timer_snapshot
+                        #activity_snapshot
+                        #submachine_snapshot
+                        #(#snapshot_init),*
+                    }
+                }
+
+                #internal_vis #asyncness fn process_event(
+                    &mut self,
+                    #params,
+                    event: &mut ::core::option::Option<Event>,
+                ) -> ::umlstate::ProcessResult {
+                    let state = if let ::core::option::Option::Some(s) = &self.state {
                         s
                     } else {
-                        panic!(#invalid_event_state_str);
+                        ::core::panic!(#invalid_event_state_str);
                     };
 
                     let result = match state {
@@ -266,23 +1395,63 @@ fn generate_state(state: &lower::State) -> proc_macro2::TokenStream {
                         #active_arm
                     };
 
-                    if result == ::umlstate::ProcessResult::Handled {
-                        return result
+                    match result {
+                        ::umlstate::ProcessResult::Handled => {
+                            #process_completion
+                            return result
+                        }
+                        ::umlstate::ProcessResult::Deferred | ::umlstate::ProcessResult::Error(_) => {
+                            return result
+                        }
+                        ::umlstate::ProcessResult::Unhandled => (),
                     }
 
-                    match event {
-                        #(#internal_transitions),*
-                        _ => ::umlstate::ProcessResult::Unhandled
+                    match event.take() {
+                        #(#internal_transitions,)*
+                        #activity_done
+                        other => {
+                            *event = other;
+                            match event {
+                                #(#deferred_events,)*
+                                _ => ::umlstate::ProcessResult::Unhandled,
+                            }
+                        }
                     }
                 }
 
-                pub(super) fn enter(&mut self, ctx: &impl #context_type) {
+                #region_routing
+
+                #internal_vis #asyncness fn enter(&mut self, #params) {
                     #enter_action
                 }
 
-                pub(super) fn exit(&mut self, ctx: &impl #context_type) {
+                #internal_vis #asyncness fn exit(&mut self, #params) {
                     #exit_action
                 }
+
+                /// Restarts the timers and do-activities of the active
+                /// states, as a restored machine runs none.
+                #[allow(unused_variables)]
+                #internal_vis fn resume(&mut self, #params) {
+                    #resume_action
+                }
+
+                #[allow(dead_code)]
+                #internal_vis fn is_complete(&self) -> bool {
+                    #is_complete
+                }
+
+                /// Whether the state is set up as `active` says, and so
+                /// are its sub-states, regions and submachine.
+                #internal_vis fn is_consistent(&self, active: bool) -> bool {
+                    self.state.is_some() == active
+                        #(#consistency)*
+                        #submachine_consistency
+                }
+
+                #history_methods
+                #(#entry_point_methods)*
+                #completion_method
             }
 
             #(#states)*
@@ -290,17 +1459,349 @@ fn generate_state(state: &lower::State) -> proc_macro2::TokenStream {
     }
 }
 
-fn generate_internal_transition(
-    _state: &lower::State,
+/// Collects the path variant of every state and region nested in `state`,
+/// with the condition for it to be active.  Returns the statements pushing
+/// the active ones to `states`, innermost first.
+fn generate_state_paths<'a>(
+    path_type: &syn::Ident,
+    state: &'a lower::State,
+    access: proc_macro2::TokenStream,
+    mod_path: proc_macro2::TokenStream,
+    prefix: &[&'a syn::Ident],
+    is_active: Option<&proc_macro2::TokenStream>,
+    paths: &mut Vec<(syn::Ident, proc_macro2::TokenStream)>,
+) -> proc_macro2::TokenStream {
+    let state_type = &state.state_type;
+
+    let mut children = vec![];
+    for sub_state in state.states.iter().chain(state.regions.iter()) {
+        let field_ident = &sub_state.field_ident;
+        let sub_mod = &sub_state.mod_name;
+        let mut sub_prefix = prefix.to_vec();
+        sub_prefix.push(&sub_state.ident);
+        let variant = pretty::path_variant(&sub_prefix);
+
+        let condition = if state.states.is_empty() {
+            quote! { #access.state.is_some() }
+        } else {
+            let ident = &sub_state.ident;
+            quote! {
+                #access.state == ::core::option::Option::Some(#mod_path::#state_type::#ident)
+            }
+        };
+        let is_in = match is_active {
+            Some(is_active) => quote! { #is_active && #condition },
+            None => condition,
+        };
+        paths.push((variant.clone(), is_in.clone()));
+
+        let inner = generate_state_paths(
+            path_type,
+            sub_state,
+            quote! { #access.#field_ident },
+            quote! { #mod_path::#sub_mod },
+            &sub_prefix,
+            Some(&is_in),
+            paths,
+        );
+        children.push((sub_state, variant, inner));
+    }
+
+    if !state.states.is_empty() {
+        let arms = children.iter().map(|(sub_state, variant, inner)| {
+            let ident = &sub_state.ident;
+            quote! {
+                ::core::option::Option::Some(#mod_path::#state_type::#ident) => {
+                    #inner
+                    let _ = states.push(#path_type::#variant);
+                }
+            }
+        });
+        quote! {
+            match &#access.state {
+                #(#arms)*
+                _ => (),
+            }
+        }
+    } else if !state.regions.is_empty() {
+        let regions = children.iter().map(|(_, variant, inner)| {
+            quote! {
+                #inner
+                let _ = states.push(#path_type::#variant);
+            }
+        });
+        quote! {
+            if #access.state.is_some() {
+                #(#regions)*
+            }
+        }
+    } else {
+        quote! {}
+    }
+}
+
+/// Serde derives for the plain data types of a machine, when the `serde`
+/// feature of `umlstate` is enabled.
+fn generate_serde_derive() -> Option<proc_macro2::TokenStream> {
+    cfg!(feature = "serde").then(|| {
+        quote! {
+            #[derive(::umlstate::serde::Serialize, ::umlstate::serde::Deserialize)]
+            #[serde(crate = "::umlstate::serde")]
+        }
+    })
+}
+
+/// The entry behavior of `state`, announced to the observer, after
+/// starting the timers of its timed transitions and before starting its
+/// do-activity.
+fn generate_entry_behavior(state: &lower::State) -> proc_macro2::TokenStream {
+    if state.path.is_empty() {
+        let entry = &state.entry;
+        return quote! { #entry };
+    }
+    let entry = state.entry.as_ref().map(|e| quote! { #e; });
+    let name = path_name(&state.path);
+    let arm_timers = generate_arm_timers(state);
+    let start_activity = generate_start_activity(state);
+    quote! {
+        {
+            observer.on_entry(#name);
+            queue.entered();
+            #arm_timers
+            #entry
+            #start_activity
+        }
+    }
+}
+
+/// Spells an async method of the context of a `Send` machine as a method
+/// returning a `Send` future, which an `async fn` cannot promise.
+fn send_method(method: &syn::TraitItemMethod) -> syn::TraitItemMethod {
+    let mut method = method.clone();
+    if method.sig.asyncness.take().is_some() {
+        let output = match &method.sig.output {
+            syn::ReturnType::Default => quote! { () },
+            syn::ReturnType::Type(_, ty) => quote! { #ty },
+        };
+        method.sig.output = syn::parse_quote! {
+            -> impl ::core::future::Future<Output = #output> + ::core::marker::Send
+        };
+        if let Some(block) = &method.default {
+            method.default = Some(syn::parse_quote! {{ async move #block }});
+        }
+    }
+    method
+}
+
+/// Starts the do-activity of `state`.  In an async machine, the activity
+/// is a future run by the spawner, in a new generation so that an earlier
+/// run finishing late is ignored.
+fn generate_start_activity(state: &lower::State) -> Option<proc_macro2::TokenStream> {
+    let a = state.activity.as_ref()?;
+    let id = a.id;
+    let start = &a.start;
+    Some(if state.is_async {
+        quote! {
+            self.activity_generation = self.activity_generation.wrapping_add(1);
+            spawner.spawn(
+                ::umlstate::ActivityId {
+                    index: #id,
+                    generation: self.activity_generation,
+                },
+                #start,
+            );
+        }
+    } else {
+        quote! { #start; }
+    })
+}
+
+/// Starts the timers of the timed transitions of `state`, in a new
+/// generation so that those armed before are ignored if they fire.
+fn generate_arm_timers(state: &lower::State) -> Option<proc_macro2::TokenStream> {
+    if state.timers.is_empty() {
+        return None;
+    }
+    let arm_timers = state.timers.iter().map(|t| {
+        let id = t.id;
+        let duration = &t.duration;
+        quote! {
+            timer.arm(
+                ::umlstate::TimerId {
+                    index: #id,
+                    generation: self.timer_generation,
+                },
+                #duration,
+            );
+        }
+    });
+    Some(quote! {
+        self.timer_generation = self.timer_generation.wrapping_add(1);
+        #(#arm_timers)*
+    })
+}
+
+/// The exit behavior of `state`, announced to the observer, after
+/// stopping the timers of its timed transitions and its do-activity.
+fn generate_exit_behavior(state: &lower::State) -> proc_macro2::TokenStream {
+    let exit = &state.exit;
+    if state.path.is_empty() {
+        return quote! { #exit };
+    }
+    let name = path_name(&state.path);
+    let cancel_timers = state.timers.iter().map(|t| {
+        let id = t.id;
+        quote! {
+            timer.cancel(::umlstate::TimerId {
+                index: #id,
+                generation: self.timer_generation,
+            });
+        }
+    });
+    let stop_activity = state.activity.as_ref().map(|a| {
+        let id = a.id;
+        let cancel = a.cancel.as_ref().map(|c| quote! { #c; });
+        let spawned = state.is_async.then(|| {
+            quote! {
+                spawner.cancel(::umlstate::ActivityId {
+                    index: #id,
+                    generation: self.activity_generation,
+                });
+                self.activity_done = false;
+            }
+        });
+        quote! {
+            #spawned
+            #cancel
+        }
+    });
+    quote! {
+        {
+            observer.on_exit(#name);
+            #(#cancel_timers)*
+            #stop_activity
+            #exit
+        }
+    }
+}
+
+/// Tells the observer about the transition `t` out of the state at
+/// `source`, with its target resolved in `scope`.
+fn generate_transition_notice(
+    scope: &lower::State,
+    source: &[syn::Ident],
+    t: &lower::Transition,
+) -> proc_macro2::TokenStream {
+    let source = path_name(source);
+    let mut target = scope.path.clone();
+    target.extend(t.target.iter().cloned());
+    target.extend(t.target_path.iter().cloned());
+    let target = match t.target_history {
+        None => path_name(&target),
+        Some(analyze::History::Shallow) => format!("{}.<H>", path_name(&target)),
+        Some(analyze::History::Deep) => format!("{}.<H*>", path_name(&target)),
+    };
+    let event = match &t.event_name {
+        Some(event) => quote! { ::core::option::Option::Some(#event) },
+        None => quote! { ::core::option::Option::None },
+    };
+    quote! {
+        observer.on_transition(#source, #target, #event);
+    }
+}
+
+/// Path of the source state of the transition `t` of `sub_state`.
+fn source_path(
+    scope: &lower::State,
+    sub_state: &lower::State,
     t: &lower::Transition,
+) -> Vec<syn::Ident> {
+    let mut path = scope.path.clone();
+    path.push(sub_state.ident.clone());
+    path.extend(t.source_path.iter().cloned());
+    path
+}
+
+/// Name of a state as shown to observers, like `Running.Work`.
+fn path_name(path: &[syn::Ident]) -> String {
+    path.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Parameters passed down to every state, available to actions and guards.
+fn generate_params(context_type: &syn::Ident) -> proc_macro2::TokenStream {
+    quote! {
+        ctx: &mut impl #context_type,
+        queue: &mut EventQueue,
+        observer: &mut impl ::umlstate::Observer,
+        timer: &mut impl ::umlstate::Timer,
+        spawner: &mut impl ::umlstate::Spawner
+    }
+}
+
+/// Awaits the calls to the methods of states, which are `async` in an
+/// `async machine`.
+fn generate_await(state: &lower::State) -> Option<proc_macro2::TokenStream> {
+    state.is_async.then(|| quote! { .await })
+}
+
+/// Guards only get shared access to the context, so they cannot call
+/// `&mut self` methods.
+fn generate_guard(guard: &syn::Expr) -> proc_macro2::TokenStream {
+    quote! {
+        {
+            let ctx = &*ctx;
+            #guard
+        }
+    }
+}
+
+/// A guard telling the observer when it rejects `event` in `source`.
+fn generate_observed_guard(
+    guard: &syn::Expr,
+    source: &str,
+    event: &str,
 ) -> proc_macro2::TokenStream {
+    let guard = generate_guard(guard);
+    quote! {
+        {
+            let accepted = #guard;
+            if !accepted {
+                observer.on_guard_rejected(#source, #event);
+            }
+            accepted
+        }
+    }
+}
+
+/// Matches the taken event of `t`, moving its payload into the bindings of
+/// the event pattern, or into `event` if there is no pattern.
+fn generate_event_pattern(t: &lower::Transition) -> proc_macro2::TokenStream {
     let event = &t.event;
-    let event_pat = &t.event_pat.as_ref().map(|p| quote! { @ #p });
-    let guard = t.guard.as_ref().map(|g| quote! { if #g });
+    match &t.event_pat {
+        Some(p) => quote! { ::core::option::Option::Some(Event::#event(#p)) },
+        None => quote! { ::core::option::Option::Some(Event::#event(event)) },
+    }
+}
+
+fn generate_internal_transition(
+    state: &lower::State,
+    t: &lower::Transition,
+) -> proc_macro2::TokenStream {
+    let event_pat = generate_event_pattern(t);
+    let name = path_name(&state.path);
+    let event = t.event_name.as_ref().unwrap();
+    let guard = t.guard.as_ref().map(|g| {
+        let guard = generate_observed_guard(g, &name, event);
+        quote! { if #guard }
+    });
     let action = &t.action;
 
     quote! {
-        Event::#event(event #event_pat) #guard => {
+        #event_pat #guard => {
+            observer.on_transition(#name, #name, ::core::option::Option::Some(#event));
             {
                 #action;
             }
@@ -314,25 +1815,385 @@ fn generate_transition(
     cur_state: &lower::State,
     t: &lower::Transition,
 ) -> proc_macro2::TokenStream {
-    let event = &t.event;
-    let event_pat = &t.event_pat.as_ref().map(|p| quote! { @ #p });
-    let guard = t.guard.as_ref().map(|g| quote! { if #g });
+    let await_ = generate_await(parent);
+    let event_pat = generate_event_pattern(t);
     let action = &t.action;
-    let state_type = &parent.state_type;
     let cur_state_field = &cur_state.field_ident;
-    let next_state_name = &t.target;
-    let next_state_field = &t.target_state_field;
+
+    let source = source_path(parent, cur_state, t);
+    let notice = generate_transition_notice(parent, &source, t);
+
+    let (mut source_conditions, source_access) = generate_source_path(cur_state, &t.source_path);
+    // A timer armed before the source was last entered is stale.
+    if t.timer.is_some() {
+        source_conditions.push(quote! { generation == #source_access.timer_generation });
+    }
+    if let Some(g) = &t.guard {
+        let event = t.event_name.as_ref().unwrap();
+        source_conditions.push(generate_observed_guard(g, &path_name(&source), event));
+    }
+
+    // Each path through a junction becomes its own arm, so that the
+    // junction's guards are evaluated before any state is exited.
+    let arms = expand_junctions(parent, t)
+        .into_iter()
+        .map(|(conditions, enter_next_state)| {
+            let conditions: Vec<_> = source_conditions
+                .iter()
+                .cloned()
+                .chain(conditions)
+                .collect();
+            let guard = (!conditions.is_empty()).then(|| {
+                let conditions = generate_conjunction(&conditions);
+                quote! { if #conditions }
+            });
+            quote! {
+                #event_pat #guard => {
+                    #notice
+                    self.#cur_state_field.exit(ctx, queue, observer, timer, spawner)#await_;
+                    {
+                        #action;
+                    }
+                    #enter_next_state
+                    ::umlstate::ProcessResult::Handled
+                }
+            }
+        });
+
+    quote! { #(#arms),* }
+}
+
+fn generate_conjunction(conditions: &[proc_macro2::TokenStream]) -> proc_macro2::TokenStream {
+    match conditions {
+        [condition] => condition.clone(),
+        _ => quote! { #((#conditions))&&* },
+    }
+}
+
+fn find_pseudostate<'a>(
+    state: &'a lower::State,
+    ident: &syn::Ident,
+) -> Option<&'a lower::Pseudostate> {
+    state.pseudostates.iter().find(|p| p.ident == *ident)
+}
+
+/// Expands the junctions targeted by `t` into the alternative paths through
+/// them.  Each path comes with the guards it requires and the code entering
+/// its target.
+fn expand_junctions(
+    scope: &lower::State,
+    t: &lower::Transition,
+) -> Vec<(Vec<proc_macro2::TokenStream>, proc_macro2::TokenStream)> {
+    let junction = find_pseudostate(scope, t.target.as_ref().unwrap())
+        .filter(|p| p.kind == analyze::PseudostateKind::Junction);
+    let junction = match junction {
+        Some(junction) => junction,
+        None => {
+            let conditions = generate_enter_condition(scope, t).into_iter().collect();
+            return vec![(conditions, generate_enter_target(scope, t))];
+        }
+    };
+
+    let mut source = scope.path.clone();
+    source.push(junction.ident.clone());
+
+    junction
+        .branches()
+        .flat_map(|branch| {
+            let notice = generate_transition_notice(scope, &source, branch);
+            let action = &branch.action;
+            let guard = branch.guard.as_ref().map(|g| generate_guard(g));
+            expand_junctions(scope, branch)
+                .into_iter()
+                .map(move |(conditions, enter)| {
+                    let conditions = guard.iter().cloned().chain(conditions).collect();
+                    let enter = quote! {
+                        #notice
+                        {
+                            #action;
+                        }
+                        #enter
+                    };
+                    (conditions, enter)
+                })
+        })
+        .collect()
+}
+
+fn find_child<'a>(state: &'a lower::State, ident: &syn::Ident) -> &'a lower::State {
+    state
+        .states
+        .iter()
+        .chain(state.regions.iter())
+        .find(|s| s.ident == *ident)
+        .unwrap()
+}
+
+/// Walks the nested source `path` below `cur_state`, returning the conditions
+/// for the path to be active and an expression accessing the source state.
+fn generate_source_path(
+    cur_state: &lower::State,
+    path: &[syn::Ident],
+) -> (Vec<proc_macro2::TokenStream>, proc_macro2::TokenStream) {
+    let field_ident = &cur_state.field_ident;
+    let mod_name = &cur_state.mod_name;
+    let mut access = quote! { self.#field_ident };
+    let mut mod_path = quote! { #mod_name };
+    let mut conditions = vec![];
+    let mut node = cur_state;
+
+    for ident in path {
+        // Regions are always active together with their state.
+        if !node.states.is_empty() {
+            let state_type = &node.state_type;
+            conditions.push(quote! {
+                matches!(#access.state, ::core::option::Option::Some(#mod_path::#state_type::#ident))
+            });
+        }
+        node = find_child(node, ident);
+        let field_ident = &node.field_ident;
+        let mod_name = &node.mod_name;
+        access = quote! { #access.#field_ident };
+        mod_path = quote! { #mod_path::#mod_name };
+    }
+
+    (conditions, access)
+}
+
+/// Sets the state of `scope` to the target of `t` and enters it.  A choice
+/// or junction target selects its branch when it is reached.
+fn generate_enter_target(scope: &lower::State, t: &lower::Transition) -> proc_macro2::TokenStream {
+    let target_ident = t.target.as_ref().unwrap();
+    if let Some(pseudostate) = find_pseudostate(scope, target_ident) {
+        return generate_branches(scope, pseudostate);
+    }
+
+    let state_type = &scope.state_type;
+    let target = find_child(scope, target_ident);
+    let field_ident = &target.field_ident;
+    let mod_name = &target.mod_name;
+    let enter_target = generate_enter_path(
+        target,
+        quote! { self.#field_ident },
+        quote! { #mod_name },
+        &t.target_path,
+        t.target_history,
+    );
 
     quote! {
-        Event::#event(event #event_pat) #guard => {
-            self.#cur_state_field.exit(ctx);
+        self.state = ::core::option::Option::Some(#state_type::#target_ident);
+        #enter_target
+    }
+}
+
+fn generate_branches(
+    scope: &lower::State,
+    pseudostate: &lower::Pseudostate,
+) -> proc_macro2::TokenStream {
+    let mut source = scope.path.clone();
+    source.push(pseudostate.ident.clone());
+
+    let mut paths: Vec<_> = pseudostate
+        .branches()
+        .flat_map(|t| {
+            let notice = generate_transition_notice(scope, &source, t);
+            let action = &t.action;
+            let guard = t.guard.as_ref().map(|g| generate_guard(g));
+            expand_junctions(scope, t)
+                .into_iter()
+                .map(move |(conditions, enter)| {
+                    let conditions: Vec<_> = guard.iter().cloned().chain(conditions).collect();
+                    let enter = quote! {
+                        #notice
+                        {
+                            #action;
+                        }
+                        #enter
+                    };
+                    (conditions, enter)
+                })
+        })
+        .collect();
+
+    // A junction is only reached once one of its paths was found enabled,
+    // so its last path is taken if actions on the way disabled them all.
+    // The guards of a choice are only known once it is reached.
+    let otherwise = match paths.last() {
+        Some((conditions, _))
+            if conditions.is_empty() || pseudostate.kind != analyze::PseudostateKind::Choice =>
+        {
+            paths.pop().unwrap().1
+        }
+        _ => {
+            let no_branch_str = format!(
+                "partial choice {} has no enabled outgoing transition",
+                &pseudostate.ident
+            );
+            quote! { ::core::panic!(#no_branch_str); }
+        }
+    };
+
+    paths
+        .into_iter()
+        .rev()
+        .fold(otherwise, |otherwise, (conditions, enter)| {
+            let conditions = generate_conjunction(&conditions);
+            quote! {
+                if #conditions {
+                    #enter
+                } else {
+                    #otherwise
+                }
+            }
+        })
+}
+
+/// The condition for entering the target of `t` from `scope`, which fails
+/// when no path through the junctions on the way is enabled, including the
+/// default entries of the states it enters.  `None` when it always holds.
+fn generate_enter_condition(
+    scope: &lower::State,
+    t: &lower::Transition,
+) -> Option<proc_macro2::TokenStream> {
+    let target_ident = t.target.as_ref().unwrap();
+    if let Some(pseudostate) = find_pseudostate(scope, target_ident) {
+        // The branches of a choice are only evaluated once it is reached.
+        if pseudostate.kind != analyze::PseudostateKind::Junction {
+            return None;
+        }
+        let mut alternatives = vec![];
+        for branch in pseudostate.branches() {
+            let conditions: Vec<_> = branch
+                .guard
+                .as_ref()
+                .map(|g| generate_guard(g))
+                .into_iter()
+                .chain(generate_enter_condition(scope, branch))
+                .collect();
+            if conditions.is_empty() {
+                return None;
+            }
+            alternatives.push(generate_conjunction(&conditions));
+        }
+        return Some(quote! { #((#alternatives))||* });
+    }
+    // The configuration a history restores is only known at runtime.
+    if t.target_history.is_some() {
+        return None;
+    }
+    generate_state_enter_condition(find_child(scope, target_ident), &t.target_path)
+}
+
+/// The condition for entering `state` and then the nested `path` below it.
+fn generate_state_enter_condition(
+    state: &lower::State,
+    path: &[syn::Ident],
+) -> Option<proc_macro2::TokenStream> {
+    if let [entry_point] = path {
+        if let Some(p) = find_pseudostate(state, entry_point) {
+            return generate_enter_condition(state, &p.transitions[0]);
+        }
+    }
+
+    let conditions: Vec<_> = if !state.states.is_empty() {
+        match path.split_first() {
+            Some((next, rest)) => generate_state_enter_condition(find_child(state, next), rest)
+                .into_iter()
+                .collect(),
+            None => state
+                .initial_transition
+                .iter()
+                .filter_map(|t| generate_enter_condition(state, t))
+                .collect(),
+        }
+    } else {
+        state
+            .regions
+            .iter()
+            .filter_map(|r| match path.split_first() {
+                Some((next, rest)) if *next == r.ident => generate_state_enter_condition(r, rest),
+                _ => generate_state_enter_condition(r, &[]),
+            })
+            .collect()
+    };
+    (!conditions.is_empty()).then(|| generate_conjunction(&conditions))
+}
+
+/// Enters `state` and then the nested `path` below it, entering all regions
+/// off the path with their default entry.
+fn generate_enter_path(
+    state: &lower::State,
+    access: proc_macro2::TokenStream,
+    mod_path: proc_macro2::TokenStream,
+    path: &[syn::Ident],
+    history: Option<analyze::History>,
+) -> proc_macro2::TokenStream {
+    let await_ = generate_await(state);
+    if let [entry_point] = path {
+        if let Some(p) = find_pseudostate(state, entry_point) {
+            let method = entry_point_method(&p.ident);
+            return quote! { #access.#method(ctx, queue, observer, timer, spawner)#await_; };
+        }
+    }
+    if path.is_empty() {
+        return match history {
+            None => quote! { #access.enter(ctx, queue, observer, timer, spawner)#await_; },
+            Some(analyze::History::Shallow) => {
+                quote! { #access.enter_shallow_history(ctx, queue, observer, timer, spawner)#await_; }
+            }
+            Some(analyze::History::Deep) => {
+                quote! { #access.enter_deep_history(ctx, queue, observer, timer, spawner)#await_; }
+            }
+        };
+    }
+
+    let state_type = &state.state_type;
+    let entry_action = generate_entry_behavior(state);
+    let process_completion = has_completion_transitions(state).then(|| {
+        quote! { #access.process_completion(ctx, queue, observer, timer, spawner)#await_; }
+    });
+
+    let enter_child = |child: &lower::State, path: &[syn::Ident]| {
+        let field_ident = &child.field_ident;
+        let mod_name = &child.mod_name;
+        generate_enter_path(
+            child,
+            quote! { #access.#field_ident },
+            quote! { #mod_path::#mod_name },
+            path,
+            history,
+        )
+    };
+
+    if state.states.is_empty() {
+        let enter_regions = state.regions.iter().map(|r| {
+            if r.ident == path[0] {
+                enter_child(r, &path[1..])
+            } else {
+                enter_child(r, &[])
+            }
+        });
+
+        return quote! {
             {
-                #action;
+                #access.state = ::core::option::Option::Some(#mod_path::#state_type::Active);
+                #entry_action;
             }
-            self.state = ::std::option::Option::Some(#state_type::#next_state_name);
-            self.#next_state_field.enter(ctx);
-            ::umlstate::ProcessResult::Handled
+            #(#enter_regions)*
+            #process_completion
+        };
+    }
+
+    let next_state_name = &path[0];
+    let enter_next_state = enter_child(find_child(state, next_state_name), &path[1..]);
+
+    quote! {
+        {
+            #access.state = ::core::option::Option::Some(#mod_path::#state_type::#next_state_name);
+            #entry_action;
         }
+        #enter_next_state
+        #process_completion
     }
 }
 
@@ -340,66 +2201,465 @@ fn generate_entry(
     state: &lower::State,
     transition: Option<&lower::Transition>,
 ) -> proc_macro2::TokenStream {
+    let await_ = generate_await(state);
     let state_type = &state.state_type;
-    let state_name;
+    let set_state;
     let action;
-    let entry_action = &state.entry;
+    let entry_action = generate_entry_behavior(state);
     let enter_substate;
 
     if let Some(t) = transition {
-        state_name = t.target.as_ref().unwrap().clone();
+        set_state = None;
         action = &t.action;
-        let field_ident = &t.target_state_field;
-        enter_substate = quote! {
-            self.#field_ident.enter(ctx);
-        };
+        enter_substate = generate_enter_target(state, t);
     } else {
-        state_name = quote::format_ident!("Active");
+        set_state = Some(quote! {
+            self.state = ::core::option::Option::Some(#state_type::Active);
+        });
         action = &None;
         let enter_regions = state.regions.iter().map(|s| {
             let field_ident = &s.field_ident;
             quote! {
-                self.#field_ident.enter(ctx);
+                self.#field_ident.enter(ctx, queue, observer, timer, spawner)#await_;
             }
         });
-        enter_substate = quote! { #(#enter_regions)* };
+        let enter_submachine = state
+            .submachine
+            .as_ref()
+            .map(|submachine| {
+                let embedded_type = &submachine.embedded_type;
+                let submachine_trait = match state.is_async {
+                    false => quote! { Submachine },
+                    true => quote! { AsyncSubmachine },
+                };
+                // The embedding machine reports the events the embedded one
+                // dropped on entry.
+                quote! {
+                    if <#embedded_type as ::umlstate::#submachine_trait<_>>::enter_in(&mut self.machine, ctx, observer)#await_.is_err() {
+                        queue.overflowed = true;
+                    }
+                }
+            });
+        enter_substate = quote! {
+            #(#enter_regions)*
+            #enter_submachine
+        };
     }
 
     let invalid_enter_state_str = format!("{}.enter() while in active state", &state.ident);
+    let process_completion = has_completion_transitions(state).then(|| {
+        quote! { self.process_completion(ctx, queue, observer, timer, spawner)#await_; }
+    });
 
     quote! {
         if self.state.is_some() {
-            panic!(#invalid_enter_state_str);
+            ::core::panic!(#invalid_enter_state_str);
         }
         {
             #action;
-            self.state = ::std::option::Option::Some(#state_type::#state_name);
+            #set_state
             #entry_action;
         }
         #enter_substate
+        #process_completion
+    }
+}
+
+fn entry_point_method(entry_point: &syn::Ident) -> syn::Ident {
+    quote::format_ident!(
+        "enter_via_{}",
+        convert_case::Casing::to_case(&entry_point.to_string(), convert_case::Case::Snake)
+    )
+}
+
+/// Enters the state through `entry_point`, taking the transition of the
+/// entry point once the entry behavior ran.
+fn generate_entry_point(
+    state: &lower::State,
+    entry_point: &lower::Pseudostate,
+) -> proc_macro2::TokenStream {
+    let await_ = generate_await(state);
+    let t = &entry_point.transitions[0];
+    let mut source = state.path.clone();
+    source.push(entry_point.ident.clone());
+    let notice = generate_transition_notice(state, &source, t);
+    let entry_action = generate_entry_behavior(state);
+    let action = &t.action;
+    let enter_target = generate_enter_target(state, t);
+    let invalid_enter_state_str = format!("{}.enter() while in active state", &state.ident);
+    let process_completion = has_completion_transitions(state).then(|| {
+        quote! { self.process_completion(ctx, queue, observer, timer, spawner)#await_; }
+    });
+
+    quote! {
+        if self.state.is_some() {
+            ::core::panic!(#invalid_enter_state_str);
+        }
+        {
+            #entry_action;
+        }
+        #notice
+        {
+            #action;
+        }
+        #enter_target
+        #process_completion
+    }
+}
+
+fn generate_history_entry(
+    state: &lower::State,
+    history: analyze::History,
+) -> proc_macro2::TokenStream {
+    let await_ = generate_await(state);
+    let state_type = &state.state_type;
+    let entry_action = generate_entry_behavior(state);
+    let invalid_enter_state_str = format!("{}.enter() while in active state", &state.ident);
+
+    let enter_method = match history {
+        analyze::History::Shallow => quote::format_ident!("enter_shallow_history"),
+        analyze::History::Deep => quote::format_ident!("enter_deep_history"),
+    };
+
+    if state.states.is_empty() {
+        let enter_regions = state.regions.iter().map(|s| {
+            let field_ident = &s.field_ident;
+            if s.states.is_empty() && s.regions.is_empty() {
+                quote! { self.#field_ident.enter(ctx, queue, observer, timer, spawner)#await_; }
+            } else {
+                quote! { self.#field_ident.#enter_method(ctx, queue, observer, timer, spawner)#await_; }
+            }
+        });
+
+        return quote! {
+            if self.state.is_some() {
+                ::core::panic!(#invalid_enter_state_str);
+            }
+            {
+                self.state = ::core::option::Option::Some(#state_type::Active);
+                #entry_action;
+            }
+            #(#enter_regions)*
+        };
+    }
+
+    let default_transition = match history {
+        analyze::History::Shallow => state.shallow_history.as_ref(),
+        analyze::History::Deep => state.deep_history.as_ref(),
+    };
+    let default_entry = match default_transition {
+        Some(t) => generate_entry(state, Some(t)),
+        None => quote! { self.enter(ctx, queue, observer, timer, spawner)#await_; },
+    };
+
+    let process_completion = has_completion_transitions(state).then(|| {
+        quote! { self.process_completion(ctx, queue, observer, timer, spawner)#await_; }
+    });
+
+    let restore_substates = state.states.iter().map(|s| {
+        let ident = &s.ident;
+        let field_ident = &s.field_ident;
+        if history == analyze::History::Deep && !(s.states.is_empty() && s.regions.is_empty()) {
+            quote! {
+                #state_type::#ident => self.#field_ident.enter_deep_history(ctx, queue, observer, timer, spawner)#await_
+            }
+        } else {
+            quote! {
+                #state_type::#ident => self.#field_ident.enter(ctx, queue, observer, timer, spawner)#await_
+            }
+        }
+    });
+
+    quote! {
+        let history = if let ::core::option::Option::Some(h) = &self.history {
+            h.clone()
+        } else {
+            #default_entry
+            return;
+        };
+
+        if self.state.is_some() {
+            ::core::panic!(#invalid_enter_state_str);
+        }
+        {
+            self.state = ::core::option::Option::Some(history.clone());
+            #entry_action;
+        }
+        match history {
+            #(#restore_substates),*
+        }
+        #process_completion
+    }
+}
+
+/// Whether some region within `state` defers events, which other regions
+/// may handle meanwhile.
+fn has_deferring_regions(state: &lower::State) -> bool {
+    state
+        .states
+        .iter()
+        .chain(state.regions.iter())
+        .any(has_deferring_regions)
+        || state.regions.iter().any(has_deferred_events)
+}
+
+/// Dispatches an event that the region numbered `region` deferred alone to
+/// this region, through the active states containing it.
+fn generate_region_routing(state: &lower::State) -> Option<proc_macro2::TokenStream> {
+    if !has_deferring_regions(state) {
+        return None;
+    }
+    let await_ = generate_await(state);
+    let state_type = &state.state_type;
+    let params = generate_params(&state.context_type);
+    let root_path = &state.root_path;
+    let internal_vis = quote! { pub(in #root_path) };
+    let asyncness = state.is_async.then(|| quote! { async });
+
+    let routed = if state.states.is_empty() {
+        let own = state.regions.iter().filter(|r| has_deferred_events(r)).map(|r| {
+            let index = r.index;
+            let field_ident = &r.field_ident;
+            quote! {
+                #index => self.#field_ident.process_event(ctx, queue, observer, timer, spawner, event)#await_
+            }
+        });
+        let nested = state.regions.iter().filter(|r| has_deferring_regions(r)).map(|r| {
+            let field_ident = &r.field_ident;
+            quote! {
+                if result == ::umlstate::ProcessResult::Unhandled {
+                    result = self.#field_ident.process_region_event(region, ctx, queue, observer, timer, spawner, event)#await_;
+                }
+            }
+        });
+        quote! {
+            match region {
+                #(#own,)*
+                _ => {
+                    let mut result = ::umlstate::ProcessResult::Unhandled;
+                    #(#nested)*
+                    result
+                }
+            }
+        }
+    } else {
+        let arms = state.states.iter().filter(|s| has_deferring_regions(s)).map(|s| {
+            let ident = &s.ident;
+            let field_ident = &s.field_ident;
+            quote! {
+                #state_type::#ident => self.#field_ident.process_region_event(region, ctx, queue, observer, timer, spawner, event)#await_
+            }
+        });
+        quote! {
+            match state {
+                #(#arms,)*
+                _ => ::umlstate::ProcessResult::Unhandled,
+            }
+        }
+    };
+    let process_completion = has_completion_transitions(state).then(|| {
+        quote! { self.process_completion(ctx, queue, observer, timer, spawner)#await_; }
+    });
+
+    Some(quote! {
+        #[allow(unused_variables, unused_mut, unreachable_patterns, clippy::too_many_arguments)]
+        #internal_vis #asyncness fn process_region_event(
+            &mut self,
+            region: usize,
+            #params,
+            event: &mut ::core::option::Option<Event>,
+        ) -> ::umlstate::ProcessResult {
+            let state = match &self.state {
+                ::core::option::Option::Some(s) => s,
+                ::core::option::Option::None => return ::umlstate::ProcessResult::Unhandled,
+            };
+            let result = #routed;
+            if result == ::umlstate::ProcessResult::Handled {
+                #process_completion
+            }
+            result
+        }
+    })
+}
+
+fn has_deferred_events(state: &lower::State) -> bool {
+    !state.deferred_events.is_empty()
+        || state
+            .states
+            .iter()
+            .chain(state.regions.iter())
+            .any(has_deferred_events)
+}
+
+fn has_completion_transitions(state: &lower::State) -> bool {
+    state
+        .states
+        .iter()
+        .any(|s| s.out_transitions.iter().any(|t| t.event.is_none()))
+}
+
+/// Whether `state` completed, its do-activity included in an async
+/// machine.
+fn generate_is_complete(state: &lower::State) -> proc_macro2::TokenStream {
+    let completed = generate_sub_states_complete(state);
+    match state.activity.as_ref().and_then(|a| a.event.as_ref()) {
+        Some(_) => quote! { self.activity_done && #completed },
+        None => completed,
+    }
+}
+
+fn generate_sub_states_complete(state: &lower::State) -> proc_macro2::TokenStream {
+    let state_type = &state.state_type;
+
+    if state.submachine.is_some() {
+        return quote! { self.machine.is_complete() };
+    }
+
+    if !state.states.is_empty() {
+        let final_states: Vec<_> = state
+            .states
+            .iter()
+            .filter(|s| s.kind == analyze::StateKind::Final)
+            .map(|s| &s.ident)
+            .collect();
+        if final_states.is_empty() {
+            return quote! { false };
+        }
+        return quote! {
+            matches!(self.state, #(::core::option::Option::Some(#state_type::#final_states))|*)
+        };
+    }
+
+    if !state.regions.is_empty() {
+        let regions = state.regions.iter().map(|r| {
+            let field_ident = &r.field_ident;
+            quote! { self.#field_ident.is_complete() }
+        });
+        return quote! { #(#regions)&&* };
+    }
+
+    quote! { true }
+}
+
+fn generate_completion(state: &lower::State) -> proc_macro2::TokenStream {
+    let await_ = generate_await(state);
+    let state_type = &state.state_type;
+
+    let completion_states = state.states.iter().filter_map(|sub_state| {
+        let state_name = &sub_state.ident;
+        let field_ident = &sub_state.field_ident;
+
+        let mut transitions: Vec<_> = sub_state
+            .out_transitions
+            .iter()
+            .filter(|t| t.event.is_none())
+            .collect();
+        if transitions.is_empty() {
+            return None;
+        }
+        transitions.sort_by_key(|t| std::cmp::Reverse(t.source_path.len()));
+
+        let paths: Vec<_> = transitions
+            .into_iter()
+            .flat_map(|t| {
+                expand_junctions(state, t)
+                    .into_iter()
+                    .map(move |path| (t, path))
+            })
+            .collect();
+
+        let fire = paths.into_iter().rev().fold(
+            quote! { false },
+            |otherwise, (t, (junction_conditions, enter_next_state))| {
+                let action = &t.action;
+                let (mut conditions, source) = generate_source_path(sub_state, &t.source_path);
+                conditions.push(quote! { #source.is_complete() });
+                if let Some(g) = &t.guard {
+                    conditions.push(generate_guard(g));
+                }
+                conditions.extend(junction_conditions);
+                let conditions = generate_conjunction(&conditions);
+                let notice =
+                    generate_transition_notice(state, &source_path(state, sub_state, t), t);
+                let body = quote! {
+                    #notice
+                    self.#field_ident.exit(ctx, queue, observer, timer, spawner)#await_;
+                    {
+                        #action;
+                    }
+                    #enter_next_state
+                    true
+                };
+                quote! {
+                    if #conditions {
+                        #body
+                    } else {
+                        #otherwise
+                    }
+                }
+            },
+        );
+
+        Some(quote! {
+            #state_type::#state_name => #fire,
+        })
+    });
+
+    quote! {
+        loop {
+            let state = if let ::core::option::Option::Some(s) = &self.state {
+                s.clone()
+            } else {
+                return;
+            };
+
+            let fired = match state {
+                #(#completion_states)*
+                _ => false,
+            };
+
+            if !fired {
+                return;
+            }
+        }
     }
 }
 
 fn generate_exit(state: &lower::State) -> proc_macro2::TokenStream {
+    let await_ = generate_await(state);
     let state_type = &state.state_type;
-    let exit_action = &state.exit;
+    let exit_action = generate_exit_behavior(state);
     let sub_state_exits = state.states.iter().map(|s| {
         let ident = &s.ident;
         let field_ident = &s.field_ident;
         quote! {
-            #state_type::#ident => self.#field_ident.exit(ctx)
+            #state_type::#ident => self.#field_ident.exit(ctx, queue, observer, timer, spawner)#await_
         }
     });
     let region_exits = state.regions.iter().map(|s| {
         let field_ident = &s.field_ident;
         quote! {
-            self.#field_ident.exit(ctx);
+            self.#field_ident.exit(ctx, queue, observer, timer, spawner)#await_;
         }
     });
+    let exit_submachine = state
+        .submachine
+        .as_ref()
+        .map(|submachine| {
+            let embedded_type = &submachine.embedded_type;
+            let submachine_trait = match state.is_async {
+                false => quote! { Submachine },
+                true => quote! { AsyncSubmachine },
+            };
+            quote! {
+                <#embedded_type as ::umlstate::#submachine_trait<_>>::exit_in(&mut self.machine, ctx, observer)#await_;
+            }
+        });
     let simple_active_arm = if state.states.is_empty() {
         quote! {
             _ => {
                 #(#region_exits)*
+                #exit_submachine
             }
         }
     } else {
@@ -408,11 +2668,17 @@ fn generate_exit(state: &lower::State) -> proc_macro2::TokenStream {
 
     let invalid_exit_state_str = format!("{}.exit() while in not in active state", &state.ident);
 
+    let clear_state = if state.states.is_empty() {
+        quote! { self.state = ::core::option::Option::None; }
+    } else {
+        quote! { self.history = self.state.take(); }
+    };
+
     quote! {
-        let state = if let ::std::option::Option::Some(s) = &self.state {
+        let state = if let ::core::option::Option::Some(s) = &self.state {
             s
         } else {
-            panic!(#invalid_exit_state_str);
+            ::core::panic!(#invalid_exit_state_str);
         };
 
         match state {
@@ -420,12 +2686,60 @@ fn generate_exit(state: &lower::State) -> proc_macro2::TokenStream {
             #simple_active_arm
         }
         {
-            self.state = ::std::option::Option::None;
+            #clear_state
             #exit_action;
         }
     }
 }
 
+fn generate_resume(state: &lower::State) -> proc_macro2::TokenStream {
+    let state_type = &state.state_type;
+    let arm_timers = generate_arm_timers(state);
+    // An activity that finished before the snapshot was taken is not run
+    // again.
+    let start_activity = generate_start_activity(state).map(|start| match state.is_async {
+        false => start,
+        true => quote! {
+            if !self.activity_done {
+                #start
+            }
+        },
+    });
+    let sub_state_resumes = state.states.iter().map(|s| {
+        let ident = &s.ident;
+        let field_ident = &s.field_ident;
+        quote! {
+            #state_type::#ident => self.#field_ident.resume(ctx, queue, observer, timer, spawner)
+        }
+    });
+    let region_resumes = state.regions.iter().map(|s| {
+        let field_ident = &s.field_ident;
+        quote! {
+            self.#field_ident.resume(ctx, queue, observer, timer, spawner);
+        }
+    });
+    let simple_active_arm = state.states.is_empty().then(|| {
+        quote! {
+            _ => {
+                #(#region_resumes)*
+            }
+        }
+    });
+
+    quote! {
+        let state = match &self.state {
+            ::core::option::Option::Some(s) => s.clone(),
+            ::core::option::Option::None => return,
+        };
+        #arm_timers
+        #start_activity
+        match state {
+            #(#sub_state_resumes,)*
+            #simple_active_arm
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
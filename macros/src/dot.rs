@@ -0,0 +1,311 @@
+use std::fmt::Write;
+
+use crate::{analyze, lower, pretty};
+
+/// Renders a machine as a Graphviz digraph.  Composite states become
+/// clusters with an invisible anchor node, so that transitions can end on
+/// the cluster border.
+pub fn generate(machine: &lower::TopMachine) -> String {
+    let mut w = Writer {
+        machine,
+        nodes: String::new(),
+        edges: String::new(),
+    };
+    w.write_contents(&machine.state, &[], 1);
+
+    let mut out = String::new();
+    writeln!(out, "digraph {} {{", machine.ident).unwrap();
+    writeln!(out, "    compound=true;").unwrap();
+    writeln!(out, "    node [shape=box, style=rounded];").unwrap();
+    out.push_str(&w.nodes);
+    out.push_str(&w.edges);
+    out.push_str("}\n");
+    out
+}
+
+struct Writer<'a> {
+    machine: &'a lower::TopMachine,
+    nodes: String,
+    edges: String,
+}
+
+impl<'a> Writer<'a> {
+    fn write_contents(&mut self, state: &lower::State, path: &[&syn::Ident], depth: usize) {
+        let indent = "    ".repeat(depth);
+
+        if let Some(t) = &state.initial_transition {
+            let id = node_id(path, "<*>");
+            writeln!(
+                self.nodes,
+                "{}\"{}\" [shape=point, width=0.15];",
+                indent, id
+            )
+            .unwrap();
+            let label = pretty::transition_label(self.machine, t);
+            self.write_edge(&id, false, path, t, label);
+        }
+
+        for (history, default) in [
+            (analyze::History::Shallow, &state.shallow_history),
+            (analyze::History::Deep, &state.deep_history),
+        ] {
+            if default.is_none() && !lower::is_history_target(self.machine, path, history) {
+                continue;
+            }
+            let id = history_id(path, history);
+            let label = history_label(history);
+            writeln!(
+                self.nodes,
+                "{}\"{}\" [shape=circle, label=\"{}\", width=0.3];",
+                indent, id, label
+            )
+            .unwrap();
+            if let Some(t) = default {
+                let label = pretty::transition_label(self.machine, t);
+                self.write_edge(&id, false, path, t, label);
+            }
+        }
+
+        for pseudostate in &state.pseudostates {
+            let id = node_id(path, &pseudostate.ident.to_string());
+            let shape = match pseudostate.kind {
+                analyze::PseudostateKind::Choice => {
+                    "shape=diamond, label=\"\", width=0.3, height=0.3"
+                }
+                analyze::PseudostateKind::Junction => "shape=point, width=0.15",
+                analyze::PseudostateKind::EntryPoint => {
+                    "shape=circle, label=\"\", width=0.15, height=0.15"
+                }
+            };
+            writeln!(self.nodes, "{}\"{}\" [{}];", indent, id, shape).unwrap();
+            for t in &pseudostate.transitions {
+                let label = pretty::transition_label(self.machine, t);
+                self.write_edge(&id, false, path, t, label);
+            }
+            if let Some(t) = &pseudostate.else_transition {
+                let label = pretty::else_label(self.machine, t);
+                self.write_edge(&id, false, path, t, label);
+            }
+        }
+
+        for exit_point in &state.exit_points {
+            let id = node_id(path, &exit_point.ident.to_string());
+            writeln!(
+                self.nodes,
+                "{}\"{}\" [shape=circle, label=\"×\", fixedsize=true, width=0.15, height=0.15];",
+                indent, id
+            )
+            .unwrap();
+            for t in exit_point.incoming.iter().filter(|t| !t.through_exit_point) {
+                let mut source = path.to_vec();
+                source.extend(&t.source_path);
+                let is_cluster = self.is_composite(&source);
+                let label = pretty::transition_label(self.machine, t);
+                self.write_edge(&path_id(&source), is_cluster, path, t, label);
+            }
+        }
+
+        for sub_state in &state.states {
+            let mut sub_path = path.to_vec();
+            sub_path.push(&sub_state.ident);
+            self.write_state(sub_state, &sub_path, depth);
+
+            for t in pretty::out_transitions(sub_state) {
+                let mut source = sub_path.clone();
+                source.extend(&t.source_path);
+                let is_cluster = self.is_composite(&source);
+                let label = pretty::transition_label(self.machine, t);
+                self.write_edge(&path_id(&source), is_cluster, path, t, label);
+            }
+        }
+
+        for region in &state.regions {
+            let mut region_path = path.to_vec();
+            region_path.push(&region.ident);
+            writeln!(
+                self.nodes,
+                "{}subgraph \"cluster_{}\" {{",
+                indent,
+                path_id(&region_path)
+            )
+            .unwrap();
+            writeln!(self.nodes, "{}    label=\"{}\";", indent, region.ident).unwrap();
+            writeln!(self.nodes, "{}    style=dashed;", indent).unwrap();
+            self.write_contents(region, &region_path, depth + 1);
+            writeln!(self.nodes, "{}}}", indent).unwrap();
+        }
+    }
+
+    fn write_state(&mut self, state: &lower::State, path: &[&syn::Ident], depth: usize) {
+        let indent = "    ".repeat(depth);
+        let id = path_id(path);
+
+        if state.kind == analyze::StateKind::Final {
+            writeln!(
+                self.nodes,
+                "{}\"{}\" [shape=doublecircle, label=\"\", style=filled, fillcolor=black, width=0.15];",
+                indent, id
+            )
+            .unwrap();
+            return;
+        }
+
+        let label = self.state_label(state);
+        if state.states.is_empty() && state.regions.is_empty() {
+            writeln!(self.nodes, "{}\"{}\" [label=\"{}\"];", indent, id, label).unwrap();
+            return;
+        }
+
+        writeln!(self.nodes, "{}subgraph \"cluster_{}\" {{", indent, id).unwrap();
+        writeln!(self.nodes, "{}    label=\"{}\";", indent, label).unwrap();
+        writeln!(self.nodes, "{}    style=rounded;", indent).unwrap();
+        writeln!(
+            self.nodes,
+            "{}    \"{}\" [shape=point, style=invis, width=0];",
+            indent, id
+        )
+        .unwrap();
+        self.write_contents(state, path, depth + 1);
+        writeln!(self.nodes, "{}}}", indent).unwrap();
+    }
+
+    /// The state name followed by its behaviors, one per line.
+    fn state_label(&self, state: &lower::State) -> String {
+        let mut lines = vec![state.ident.to_string()];
+        lines.extend(pretty::descriptions(self.machine, state));
+        lines
+            .iter()
+            .map(|l| escape(l))
+            .collect::<Vec<_>>()
+            .join("\\n")
+    }
+
+    /// Writes the edge of `t` from the node `source`, with the target
+    /// resolved in the state at `scope`.
+    fn write_edge(
+        &mut self,
+        source: &str,
+        source_is_cluster: bool,
+        scope: &[&syn::Ident],
+        t: &lower::Transition,
+        label: String,
+    ) {
+        let mut target = scope.to_vec();
+        target.extend(t.target.iter());
+        target.extend(&t.target_path);
+
+        let mut attrs = vec![];
+        let target_id = match t.target_history {
+            Some(history) => history_id(&target, history),
+            None => {
+                if self.is_composite(&target) {
+                    attrs.push(format!("lhead=\"cluster_{}\"", path_id(&target)));
+                }
+                path_id(&target)
+            }
+        };
+        if source_is_cluster {
+            attrs.push(format!("ltail=\"cluster_{}\"", source));
+        }
+
+        if !label.is_empty() {
+            attrs.insert(0, format!("label=\"{}\"", escape(&label)));
+        }
+
+        if attrs.is_empty() {
+            writeln!(self.edges, "    \"{}\" -> \"{}\";", source, target_id).unwrap();
+        } else {
+            writeln!(
+                self.edges,
+                "    \"{}\" -> \"{}\" [{}];",
+                source,
+                target_id,
+                attrs.join(", ")
+            )
+            .unwrap();
+        }
+    }
+
+    fn find(&self, path: &[&syn::Ident]) -> Option<&'a lower::State> {
+        let mut state = &self.machine.state;
+        for ident in path {
+            state = state
+                .states
+                .iter()
+                .chain(state.regions.iter())
+                .find(|s| s.ident == **ident)?;
+        }
+        Some(state)
+    }
+
+    fn is_composite(&self, path: &[&syn::Ident]) -> bool {
+        !path.is_empty()
+            && self
+                .find(path)
+                .is_some_and(|s| !s.states.is_empty() || !s.regions.is_empty())
+    }
+}
+
+fn path_id(path: &[&syn::Ident]) -> String {
+    path.iter()
+        .map(|i| i.to_string())
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+fn node_id(path: &[&syn::Ident], name: &str) -> String {
+    if path.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}.{}", path_id(path), name)
+    }
+}
+
+fn history_id(path: &[&syn::Ident], history: analyze::History) -> String {
+    node_id(path, &format!("<{}>", history_label(history)))
+}
+
+fn history_label(history: analyze::History) -> &'static str {
+    match history {
+        analyze::History::Shallow => "H",
+        analyze::History::Deep => "H*",
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parse;
+
+    #[test]
+    fn basic() {
+        let ast: parse::UmlState = syn::parse_quote! {
+            machine Foo {
+                state A {
+                    entry / ctx.hello();
+                }
+                state B {
+                    state X;
+                    <*> => X;
+                }
+                <*> => A;
+                A + E(n) => B / ctx.go(n) if n > 0;
+            }
+        };
+
+        let model = analyze::analyze(ast).unwrap();
+        let lowered = lower::lower(model);
+        let dot = generate(&lowered.machines[0]);
+
+        assert!(dot.starts_with("digraph Foo {"));
+        assert!(dot.contains("\"A\" [label=\"A\\nentry / ctx.hello()\"];"));
+        assert!(dot.contains("subgraph \"cluster_B\" {"));
+        assert!(dot
+            .contains("\"A\" -> \"B\" [label=\"E(n) [n > 0] / ctx.go(n)\", lhead=\"cluster_B\"];"));
+    }
+}
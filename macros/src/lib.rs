@@ -2,18 +2,75 @@ use syn::*;
 
 mod analyze;
 mod codegen;
+mod dot;
 mod lower;
+mod mermaid;
 mod parse;
+mod plantuml;
+mod pretty;
+mod scxml;
 
+/// Builds the machines declared in the block.
+///
+/// # Events
+///
+/// Events are moved into the action of the transition taking them, so they
+/// need not be `Clone`, but for those handled in several orthogonal
+/// regions: each region takes its own copy of these.
 #[proc_macro]
 pub fn umlstate(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
     let ast = parse_macro_input!(input as parse::UmlState);
+    expand(ast).into()
+}
+
+/// Builds the machine described by an SCXML document, at a path relative to
+/// the crate root.
+///
+/// # Supported subset
+///
+/// The document is translated into a machine declaration, so only what
+/// such a declaration can express is supported:
+///
+/// - `<state>`, `<parallel>`, `<final>`, `<history>` and `<initial>`, with
+///   ids and event names like `wait-charge` or `usb.connected` turned into
+///   the type names `WaitCharge` and `UsbConnected`.  `<datamodel>` is
+///   ignored, the context of the machine taking its place.
+/// - The children of a `<parallel>` are its regions: `<state>` elements
+///   with sub-states of their own, which cannot have `<onentry>`,
+///   `<onexit>` or `<transition>` elements, nor be the target of a
+///   transition.  Neither can the `<scxml>` element itself.
+/// - Transitions have at most one target and no event wildcards, and
+///   `<final>` states have no children.
+/// - Executable content is `<raise>`, which posts the event, and
+///   `<script>`.  Each `cond` and each `<script>` must be a bare method
+///   name, like `cond="hasMedia"` or `<script>spinUp()</script>`, and
+///   becomes the context method `fn has_media(&self) -> bool` or
+///   `fn spin_up(&mut self)`.
+#[proc_macro]
+pub fn umlstate_scxml(input: proc_macro::TokenStream) -> proc_macro::TokenStream {
+    let path = parse_macro_input!(input as LitStr);
+    let ast = match scxml::import(&path) {
+        Err(err) => return err.into_compile_error().into(),
+        Ok(ast) => ast,
+    };
+    let file = scxml::resolve(&path).to_string_lossy().into_owned();
+    let machines = expand(ast);
+
+    quote::quote! {
+        // Rebuilds the machine whenever the document changes.
+        const _: &str = include_str!(#file);
+        #machines
+    }
+    .into()
+}
+
+fn expand(ast: parse::UmlState) -> proc_macro2::TokenStream {
     let model = analyze::analyze(ast);
     let model = match model {
-        Err(err) => return err.into_compile_error().into(),
+        Err(err) => return err.into_compile_error(),
         Ok(model) => model,
     };
     let lower_model = lower::lower(model);
 
-    codegen::generate(&lower_model).into()
+    codegen::generate(&lower_model)
 }
@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use quote::{format_ident, quote};
 
-use crate::analyze;
+use crate::{analyze, pretty};
 
 pub struct Model {
     pub machines: Vec<TopMachine>,
@@ -12,46 +12,142 @@ pub struct TopMachine {
     pub vis: syn::Visibility,
     pub ident: syn::Ident,
     pub mod_name: syn::Ident,
+    pub path_type: syn::Ident,
+    /// The machine without its context and services, as embedded by
+    /// submachine states.
+    pub embedded_type: syn::Ident,
     pub events: Vec<(syn::Path, syn::Ident)>,
+    pub shared_events: Vec<syn::Ident>,
     pub context: Context,
     pub generics: syn::Generics,
+    pub is_async: bool,
+    /// Whether the futures of the machine and of its context are `Send`.
+    pub is_send: bool,
+    /// Number of events the queues of the machine hold without the `std`
+    /// feature.
+    pub queue_capacity: syn::Expr,
+    /// Whether some transition is timed, so the machine needs a timer.
+    pub has_timers: bool,
+    /// Whether some state of an `async machine` has a do-activity, so the
+    /// machine needs a spawner.
+    pub has_activities: bool,
     pub state: State,
 }
 
 pub struct Context {
     pub ident: syn::Ident,
     pub methods: Vec<syn::TraitItemMethod>,
+    /// Context traits of the embedded machines.
+    pub supertraits: Vec<syn::Path>,
     pub zst: Option<syn::Ident>,
 }
 
 pub struct State {
     pub mod_name: syn::Ident,
     pub ident: syn::Ident,
+    /// Path of the state from the top of the machine, which is empty.
+    pub path: Vec<syn::Ident>,
+    /// Numbers the states and regions of the machine, so that an event
+    /// deferred in a region alone is dispatched again to that region.
+    pub index: usize,
+    pub kind: analyze::StateKind,
+    /// Whether the state belongs to an `async machine`.
+    pub is_async: bool,
     pub root_path: proc_macro2::TokenStream,
     pub field_ident: syn::Ident,
     pub context_type: syn::Ident,
     pub state_type: syn::Ident,
+    pub snapshot_type: syn::Ident,
     pub entry: Option<Box<syn::Expr>>,
     pub exit: Option<Box<syn::Expr>>,
+    pub activity: Option<Activity>,
+    pub submachine: Option<Submachine>,
     pub initial_transition: Option<Transition>,
+    pub shallow_history: Option<Transition>,
+    pub deep_history: Option<Transition>,
     pub internal_transitions: Vec<Transition>,
+    pub deferred_events: Vec<DeferredEvent>,
+    /// Timers of the timed transitions leaving the state, running while it
+    /// is active.
+    pub timers: Vec<Timer>,
     pub states: Vec<State>,
+    pub pseudostates: Vec<Pseudostate>,
     pub regions: Vec<State>,
     pub out_transitions: Vec<Transition>,
+    /// Drawn on diagrams only, see `analyze::State::exit_transitions`.
+    pub exit_points: Vec<ExitPoint>,
+    pub exit_transitions: Vec<Transition>,
+}
+
+/// An exit point with the transitions reaching it, drawn on diagrams only.
+pub struct ExitPoint {
+    pub ident: syn::Ident,
+    pub incoming: Vec<Transition>,
+}
+
+pub struct DeferredEvent {
+    pub event: syn::Ident,
+    pub event_pat: Option<syn::Pat>,
+}
+
+pub struct Pseudostate {
+    pub ident: syn::Ident,
+    pub kind: analyze::PseudostateKind,
+    pub transitions: Vec<Transition>,
+    pub else_transition: Option<Transition>,
 }
 
 pub struct Transition {
     pub event: Option<syn::Ident>,
+    pub event_name: Option<String>,
     pub event_pat: Option<syn::Pat>,
+    pub source_path: Vec<syn::Ident>,
     pub target: Option<syn::Ident>,
-    pub target_state_field: Option<syn::Ident>,
+    pub target_path: Vec<syn::Ident>,
+    pub target_history: Option<analyze::History>,
     pub action: Option<Box<syn::Expr>>,
     pub guard: Option<Box<syn::Expr>>,
+    pub timer: Option<Timer>,
+    pub through_exit_point: bool,
+}
+
+/// The machine embedded by a submachine state, named after the path given
+/// in `state S: Machine;`.
+pub struct Submachine {
+    pub embedded_type: syn::Path,
+    pub snapshot_type: syn::Path,
+    /// Events declared with `forward`, offered to the embedded machine.
+    pub events: Vec<(syn::Path, syn::Ident)>,
+}
+
+pub struct Activity {
+    pub id: usize,
+    pub start: Box<syn::Expr>,
+    pub cancel: Option<Box<syn::Expr>>,
+    /// The `umlstate::ActivityId` event telling that the activity finished,
+    /// in an `async machine`.
+    pub event: Option<syn::Ident>,
+}
+
+#[derive(Clone)]
+pub struct Timer {
+    pub id: usize,
+    pub duration: Box<syn::Expr>,
 }
 
 struct EventTracker {
     index: u32,
     map: HashMap<syn::Path, syn::Ident>,
+    timers: usize,
+    activities: usize,
+    states: usize,
+}
+
+impl Pseudostate {
+    /// Outgoing transitions in evaluation order, the `else` branch last.
+    pub fn branches(&self) -> impl Iterator<Item = &Transition> {
+        self.transitions.iter().chain(self.else_transition.iter())
+    }
 }
 
 impl EventTracker {
@@ -59,9 +155,27 @@ impl EventTracker {
         EventTracker {
             index: 0,
             map: HashMap::new(),
+            timers: 0,
+            activities: 0,
+            states: 0,
         }
     }
 
+    pub fn next_timer(&mut self) -> usize {
+        self.timers += 1;
+        self.timers - 1
+    }
+
+    pub fn next_state(&mut self) -> usize {
+        self.states += 1;
+        self.states - 1
+    }
+
+    pub fn next_activity(&mut self) -> usize {
+        self.activities += 1;
+        self.activities - 1
+    }
+
     pub fn get_or_create(&mut self, path: &syn::Path) -> syn::Ident {
         if let Some(ident) = self.map.get(path) {
             return ident.clone();
@@ -74,12 +188,21 @@ impl EventTracker {
 }
 
 pub fn lower(model: analyze::Model) -> Model {
-    Model {
-        machines: model.items.iter().map(lower_machine).collect(),
+    // Machines without a context, which the machines declared after them
+    // embed without needing one either.
+    let mut context_less = vec![];
+    let mut machines = vec![];
+    for machine in &model.items {
+        let lowered = lower_machine(machine, &context_less);
+        if lowered.context.zst.is_some() {
+            context_less.push(machine.ident.clone());
+        }
+        machines.push(lowered);
     }
+    Model { machines }
 }
 
-fn lower_machine(machine: &analyze::Machine) -> TopMachine {
+fn lower_machine(machine: &analyze::Machine, context_less: &[syn::Ident]) -> TopMachine {
     let mod_name = format_ident!(
         "{}_machine",
         convert_case::Casing::to_case(&machine.ident.to_string(), convert_case::Case::Snake)
@@ -88,36 +211,76 @@ fn lower_machine(machine: &analyze::Machine) -> TopMachine {
     let context;
     let mut generics = syn::Generics::default();
 
+    let mut supertraits = vec![];
+    collect_submachine_contexts(&machine.state, context_less, &mut supertraits);
+
     let context_ident = format_ident!("{}Context", &machine.ident);
+    let needs_context = !machine.methods.is_empty() || !supertraits.is_empty();
     context = Context {
         ident: context_ident.clone(),
         methods: machine.methods.clone(),
-        zst: match machine.methods.is_empty() {
-            true => Some(format_ident!("{}Dummy", &context_ident)),
+        supertraits,
+        zst: match needs_context {
+            false => Some(format_ident!("{}Dummy", &context_ident)),
             _ => None,
         },
     };
 
-    if !machine.methods.is_empty() {
+    if needs_context {
         generics.params.push_value(syn::GenericParam::Type(
             syn::parse_quote! { Context: #context_ident },
         ));
     }
 
-    let submachine = lower_state(
+    let mut submachine = lower_state(
         &machine.state,
+        None,
         quote! { super },
         &mut events,
         &context.ident,
+        machine.is_async,
     );
 
+    let mut timers = vec![];
+    collect_timers(&submachine, &mut timers);
+    let has_timers = !timers.is_empty();
+    for (path, timer) in timers {
+        find_state_mut(&mut submachine, &path).timers.push(timer);
+    }
+    if has_timers {
+        generics.params.push(syn::GenericParam::Type(
+            syn::parse_quote! { Timer: ::umlstate::Timer },
+        ));
+    }
+
+    let has_activities = machine.is_async && events.activities > 0;
+    if has_activities {
+        generics.params.push(syn::GenericParam::Type(
+            syn::parse_quote! { Spawner: ::umlstate::Spawner },
+        ));
+    }
+
+    let mut shared_events = vec![];
+    collect_shared_events(&submachine, &mut shared_events);
+
     TopMachine {
         vis: machine.vis.clone(),
         ident: machine.ident.clone(),
         mod_name,
+        path_type: format_ident!("{}StatePath", &machine.ident),
+        embedded_type: format_ident!("{}Embedded", &machine.ident),
         events: events.map.into_iter().collect(),
+        shared_events,
         context,
         generics,
+        is_async: machine.is_async,
+        is_send: machine.is_send,
+        queue_capacity: machine
+            .queue_capacity
+            .clone()
+            .unwrap_or_else(|| syn::parse_quote! { ::umlstate::QUEUE_CAPACITY }),
+        has_timers,
+        has_activities,
         state: submachine,
     }
 }
@@ -131,28 +294,73 @@ fn state_field_ident(state: &syn::Ident) -> syn::Ident {
 
 fn lower_state(
     state: &analyze::State,
+    parent: Option<&[syn::Ident]>,
     root_path: proc_macro2::TokenStream,
     events: &mut EventTracker,
     context: &syn::Ident,
+    is_async: bool,
 ) -> State {
     let ident = state.ident.clone();
+    let index = events.next_state();
     let mod_name = format_ident!(
         "{}_state",
         convert_case::Casing::to_case(&ident.to_string(), convert_case::Case::Snake)
     );
     let field_ident = state_field_ident(&ident);
     let state_type = format_ident!("{}State", &ident);
+    let snapshot_type = format_ident!("{}Snapshot", &ident);
+    let path = match parent {
+        // The top state is the machine itself.
+        None => vec![],
+        Some(parent) => parent.iter().cloned().chain([ident.clone()]).collect(),
+    };
 
     let states = state
         .states
-        .values()
-        .map(|s| lower_state(s, quote! { #root_path::super }, events, context))
+        .iter()
+        .map(|s| {
+            lower_state(
+                s,
+                Some(&path),
+                quote! { #root_path::super },
+                events,
+                context,
+                is_async,
+            )
+        })
+        .collect();
+
+    let pseudostates = state
+        .pseudostates
+        .iter()
+        .map(|p| Pseudostate {
+            ident: p.ident.clone(),
+            kind: p.kind,
+            transitions: p
+                .transitions
+                .iter()
+                .map(|t| lower_transition(t, events))
+                .collect(),
+            else_transition: p
+                .else_transition
+                .as_ref()
+                .map(|t| lower_transition(t, events)),
+        })
         .collect();
 
     let regions = state
         .regions
         .iter()
-        .map(|s| lower_state(s, quote! { #root_path::super }, events, context))
+        .map(|s| {
+            lower_state(
+                s,
+                Some(&path),
+                quote! { #root_path::super },
+                events,
+                context,
+                is_async,
+            )
+        })
         .collect();
 
     let initial_transition = state
@@ -160,53 +368,304 @@ fn lower_state(
         .as_ref()
         .map(|t| lower_transition(t, events));
 
+    let shallow_history = state
+        .shallow_history
+        .as_ref()
+        .map(|t| lower_transition(t, events));
+
+    let deep_history = state
+        .deep_history
+        .as_ref()
+        .map(|t| lower_transition(t, events));
+
     let internal_transitions = state
         .internal_transitions
         .iter()
         .map(|t| lower_transition(t, events))
         .collect();
 
+    let deferred_events = state
+        .deferred_events
+        .iter()
+        .map(|d| DeferredEvent {
+            event: events.get_or_create(&d.event_path),
+            event_pat: d.event_pat.clone(),
+        })
+        .collect();
+
     let out_transitions = state
         .out_transitions
         .iter()
         .map(|t| lower_transition(t, events))
         .collect();
 
+    let forwarded_events: Vec<_> = state
+        .forwarded_events
+        .iter()
+        .map(|path| (path.clone(), events.get_or_create(path)))
+        .collect();
+    let submachine = state.submachine.as_ref().map(|path| Submachine {
+        embedded_type: sibling_path(path, "Embedded"),
+        snapshot_type: sibling_path(path, "Snapshot"),
+        events: forwarded_events,
+    });
+
+    let exit_points = state
+        .exit_points
+        .iter()
+        .map(|p| ExitPoint {
+            ident: p.ident.clone(),
+            incoming: p
+                .incoming
+                .iter()
+                .map(|t| lower_drawn_transition(t, events))
+                .collect(),
+        })
+        .collect();
+
+    let exit_transitions = state
+        .exit_transitions
+        .iter()
+        .map(|t| lower_drawn_transition(t, events))
+        .collect();
+
+    let activity = state.activity.as_ref().map(|a| Activity {
+        id: events.next_activity(),
+        start: a.start.clone(),
+        cancel: a.cancel.clone(),
+        event: is_async
+            .then(|| events.get_or_create(&syn::parse_quote! { ::umlstate::ActivityId })),
+    });
+
     State {
         ident,
+        path,
+        index,
+        kind: state.kind,
+        is_async,
         mod_name,
         root_path,
         field_ident,
         context_type: context.clone(),
         state_type,
+        snapshot_type,
         entry: state.entry.clone(),
         exit: state.exit.clone(),
+        activity,
+        submachine,
         internal_transitions,
+        deferred_events,
+        timers: vec![],
         initial_transition,
+        shallow_history,
+        deep_history,
         states,
+        pseudostates,
         regions,
         out_transitions,
+        exit_points,
+        exit_transitions,
+    }
+}
+
+/// Names an item generated along with the machine at `path`, like its
+/// `MachineContext` trait.
+fn sibling_path(path: &syn::Path, suffix: &str) -> syn::Path {
+    let mut path = path.clone();
+    let last = path.segments.last_mut().unwrap();
+    last.ident = format_ident!("{}{}", last.ident, suffix);
+    last.arguments = syn::PathArguments::None;
+    path
+}
+
+/// Collects the context traits of the machines embedded by submachine
+/// states, but those of the `context_less` machines.
+fn collect_submachine_contexts(
+    state: &analyze::State,
+    context_less: &[syn::Ident],
+    contexts: &mut Vec<syn::Path>,
+) {
+    let submachine = state
+        .submachine
+        .as_ref()
+        .filter(|path| !context_less.iter().any(|m| path.is_ident(m)));
+    if let Some(path) = submachine {
+        let context = sibling_path(path, "Context");
+        if !contexts.contains(&context) {
+            contexts.push(context);
+        }
+    }
+
+    for s in state.states.iter().chain(state.regions.iter()) {
+        collect_submachine_contexts(s, context_less, contexts);
+    }
+}
+
+/// Collects the timers of timed transitions, with the path of their source.
+fn collect_timers(state: &State, timers: &mut Vec<(Vec<syn::Ident>, Timer)>) {
+    for s in &state.states {
+        for t in &s.out_transitions {
+            if let Some(timer) = &t.timer {
+                let mut path = s.path.clone();
+                path.extend(t.source_path.iter().cloned());
+                timers.push((path, timer.clone()));
+            }
+        }
+    }
+
+    for s in state.states.iter().chain(state.regions.iter()) {
+        collect_timers(s, timers);
+    }
+}
+
+fn find_state_mut<'a>(state: &'a mut State, path: &[syn::Ident]) -> &'a mut State {
+    match path.split_first() {
+        None => state,
+        Some((ident, rest)) => {
+            let child = state
+                .states
+                .iter_mut()
+                .chain(state.regions.iter_mut())
+                .find(|s| &s.ident == ident)
+                .unwrap();
+            find_state_mut(child, rest)
+        }
     }
 }
 
+/// Collects the events used by more than one orthogonal region of the same
+/// state.  Only these have to be cloned when dispatching.
+fn collect_shared_events(state: &State, shared: &mut Vec<syn::Ident>) {
+    let mut seen = vec![];
+    for region in &state.regions {
+        let mut used = vec![];
+        collect_used_events(region, &mut used);
+        for event in used {
+            if seen.contains(&event) {
+                if !shared.contains(&event) {
+                    shared.push(event);
+                }
+            } else {
+                seen.push(event);
+            }
+        }
+    }
+
+    for s in state.states.iter().chain(state.regions.iter()) {
+        collect_shared_events(s, shared);
+    }
+}
+
+fn collect_used_events(state: &State, used: &mut Vec<syn::Ident>) {
+    let events = state
+        .internal_transitions
+        .iter()
+        .chain(state.out_transitions.iter())
+        .filter_map(|t| t.event.as_ref())
+        .chain(state.deferred_events.iter().map(|d| &d.event))
+        .chain(state.activity.iter().filter_map(|a| a.event.as_ref()))
+        .chain(
+            state
+                .submachine
+                .iter()
+                .flat_map(|s| s.events.iter().map(|(_, e)| e)),
+        );
+    for event in events {
+        if !used.contains(event) {
+            used.push(event.clone());
+        }
+    }
+
+    for s in state.states.iter().chain(state.regions.iter()) {
+        collect_used_events(s, used);
+    }
+}
+
+/// Whether any transition of the machine enters the given history of the
+/// state at `path`.
+pub fn is_history_target(
+    machine: &TopMachine,
+    path: &[&syn::Ident],
+    history: analyze::History,
+) -> bool {
+    fn visit(
+        state: &State,
+        scope: &[&syn::Ident],
+        path: &[&syn::Ident],
+        history: analyze::History,
+    ) -> bool {
+        let targets = |t: &Transition| {
+            let mut target = scope.to_vec();
+            target.extend(t.target.iter());
+            target.extend(&t.target_path);
+            t.target_history == Some(history) && target == path
+        };
+        let own = state
+            .initial_transition
+            .iter()
+            .chain(state.shallow_history.iter())
+            .chain(state.deep_history.iter())
+            .chain(state.pseudostates.iter().flat_map(|p| p.branches()))
+            .chain(state.states.iter().flat_map(|s| s.out_transitions.iter()))
+            .any(targets);
+        own || state.states.iter().chain(state.regions.iter()).any(|s| {
+            let mut scope = scope.to_vec();
+            scope.push(&s.ident);
+            visit(s, &scope, path, history)
+        })
+    }
+    visit(&machine.state, &[], path, history)
+}
+
 fn lower_transition(transition: &analyze::Transition, events: &mut EventTracker) -> Transition {
     let event = transition
         .event_path
         .as_ref()
         .map(|e| events.get_or_create(e));
 
-    let target_state_field = transition.target.as_ref().map(|t| state_field_ident(t));
+    let timer = transition.after.as_ref().map(|duration| Timer {
+        id: events.next_timer(),
+        duration: duration.clone(),
+    });
+    let event_name = match &timer {
+        Some(timer) => Some(format!("after({})", pretty::tokens(&timer.duration))),
+        None => transition.event_path.as_ref().map(pretty::tokens),
+    };
+    let event_pat = match &timer {
+        Some(timer) => {
+            let id = proc_macro2::Literal::usize_unsuffixed(timer.id);
+            Some(syn::parse_quote! { ::umlstate::TimerId { index: #id, generation } })
+        }
+        None => transition.event_pat.clone(),
+    };
 
     Transition {
         event,
-        event_pat: transition.event_pat.clone(),
+        event_name,
+        event_pat,
+        source_path: transition.source_path.clone(),
         target: transition.target.clone(),
-        target_state_field,
+        target_path: transition.target_path.clone(),
+        target_history: transition.target_history,
         action: transition.action.clone(),
         guard: transition.guard.clone(),
+        timer,
+        through_exit_point: transition.through_exit_point,
     }
 }
 
+/// Lowers a transition drawn on diagrams only, which arms no timer and so
+/// takes no timer id.
+fn lower_drawn_transition(
+    transition: &analyze::Transition,
+    events: &mut EventTracker,
+) -> Transition {
+    let timers = events.timers;
+    let t = lower_transition(transition, events);
+    events.timers = timers;
+    t
+}
+
 #[cfg(test)]
 mod tests {
     use crate::parse;
@@ -0,0 +1,292 @@
+use std::fmt::Write;
+
+use crate::{analyze, lower, pretty};
+
+/// Renders a machine as a Mermaid `stateDiagram-v2`.
+pub fn generate(machine: &lower::TopMachine) -> String {
+    let mut w = Writer {
+        machine,
+        out: String::new(),
+    };
+    w.out.push_str("stateDiagram-v2\n");
+    w.write_contents(&machine.state, &[], 1);
+    w.out
+}
+
+struct Writer<'a> {
+    machine: &'a lower::TopMachine,
+    out: String,
+}
+
+impl<'a> Writer<'a> {
+    fn write_contents(&mut self, state: &lower::State, path: &[&syn::Ident], depth: usize) {
+        let indent = "    ".repeat(depth);
+
+        // Mermaid has no junction or entry point stereotype, so they are
+        // states named after the stereotype.
+        for pseudostate in &state.pseudostates {
+            let mut id = path.to_vec();
+            id.push(&pseudostate.ident);
+            let stereotype = match pseudostate.kind {
+                analyze::PseudostateKind::Choice => {
+                    writeln!(self.out, "{}state {} <<choice>>", indent, state_id(&id)).unwrap();
+                    continue;
+                }
+                analyze::PseudostateKind::Junction => "junction",
+                analyze::PseudostateKind::EntryPoint => "entryPoint",
+            };
+            writeln!(
+                self.out,
+                "{}state \"«{}» {}\" as {}",
+                indent,
+                stereotype,
+                pseudostate.ident,
+                state_id(&id)
+            )
+            .unwrap();
+        }
+        for exit_point in &state.exit_points {
+            let mut id = path.to_vec();
+            id.push(&exit_point.ident);
+            writeln!(
+                self.out,
+                "{}state \"«exitPoint» {}\" as {}",
+                indent,
+                exit_point.ident,
+                state_id(&id)
+            )
+            .unwrap();
+        }
+
+        // History is not part of Mermaid either, so it becomes a plain state.
+        for (history, default) in [
+            (analyze::History::Shallow, &state.shallow_history),
+            (analyze::History::Deep, &state.deep_history),
+        ] {
+            if default.is_some() || lower::is_history_target(self.machine, path, history) {
+                writeln!(
+                    self.out,
+                    "{}state \"{}\" as {}",
+                    indent,
+                    history_label(history),
+                    history_id(path, history)
+                )
+                .unwrap();
+            }
+        }
+
+        for sub_state in &state.states {
+            let mut sub_path = path.to_vec();
+            sub_path.push(&sub_state.ident);
+            self.write_state(sub_state, &sub_path, depth);
+        }
+
+        for (i, region) in state.regions.iter().enumerate() {
+            if i > 0 {
+                writeln!(self.out, "{}--", indent).unwrap();
+            }
+            let mut region_path = path.to_vec();
+            region_path.push(&region.ident);
+            self.write_contents(region, &region_path, depth);
+        }
+
+        if let Some(t) = &state.initial_transition {
+            self.write_transition("[*]".to_string(), state, path, t, &indent);
+        }
+        for (history, default) in [
+            (analyze::History::Shallow, &state.shallow_history),
+            (analyze::History::Deep, &state.deep_history),
+        ] {
+            if let Some(t) = default {
+                self.write_transition(history_id(path, history), state, path, t, &indent);
+            }
+        }
+        for pseudostate in &state.pseudostates {
+            let mut source = path.to_vec();
+            source.push(&pseudostate.ident);
+            for t in &pseudostate.transitions {
+                let label = pretty::transition_label(self.machine, t);
+                self.write_labelled(state_id(&source), state, path, t, label, &indent);
+            }
+            if let Some(t) = &pseudostate.else_transition {
+                let label = pretty::else_label(self.machine, t);
+                self.write_labelled(state_id(&source), state, path, t, label, &indent);
+            }
+        }
+        for exit_point in &state.exit_points {
+            for t in exit_point.incoming.iter().filter(|t| !t.through_exit_point) {
+                let mut source = path.to_vec();
+                source.extend(&t.source_path);
+                self.write_transition(state_id(&source), state, path, t, &indent);
+            }
+        }
+        for sub_state in &state.states {
+            for t in pretty::out_transitions(sub_state) {
+                let mut source = path.to_vec();
+                source.push(&sub_state.ident);
+                source.extend(&t.source_path);
+                self.write_transition(state_id(&source), state, path, t, &indent);
+            }
+        }
+    }
+
+    fn write_state(&mut self, state: &lower::State, path: &[&syn::Ident], depth: usize) {
+        // Final states are drawn as `[*]` by the transitions entering them.
+        if state.kind == analyze::StateKind::Final {
+            return;
+        }
+
+        let indent = "    ".repeat(depth);
+        let id = state_id(path);
+        if state.ident != id {
+            writeln!(self.out, "{}state \"{}\" as {}", indent, state.ident, id).unwrap();
+        } else if state.states.is_empty() && state.regions.is_empty() {
+            writeln!(self.out, "{}state {}", indent, id).unwrap();
+        }
+
+        if !state.states.is_empty() || !state.regions.is_empty() {
+            writeln!(self.out, "{}state {} {{", indent, id).unwrap();
+            self.write_contents(state, path, depth + 1);
+            writeln!(self.out, "{}}}", indent).unwrap();
+        }
+
+        for line in pretty::descriptions(self.machine, state) {
+            writeln!(self.out, "{}{} : {}", indent, id, line).unwrap();
+        }
+    }
+
+    fn write_transition(
+        &mut self,
+        source: String,
+        scope_state: &lower::State,
+        scope: &[&syn::Ident],
+        t: &lower::Transition,
+        indent: &str,
+    ) {
+        let label = pretty::transition_label(self.machine, t);
+        self.write_labelled(source, scope_state, scope, t, label, indent);
+    }
+
+    /// Writes the transition `t` from `source`, with the target resolved in
+    /// `scope_state`, the state at `scope`.
+    fn write_labelled(
+        &mut self,
+        source: String,
+        scope_state: &lower::State,
+        scope: &[&syn::Ident],
+        t: &lower::Transition,
+        label: String,
+        indent: &str,
+    ) {
+        let mut target_path = scope.to_vec();
+        target_path.extend(t.target.iter());
+        target_path.extend(&t.target_path);
+
+        let is_final = t.target_path.is_empty()
+            && scope_state.states.iter().any(|s| {
+                t.target.as_ref() == Some(&s.ident) && s.kind == analyze::StateKind::Final
+            });
+        let target = match t.target_history {
+            Some(history) => history_id(&target_path, history),
+            None if is_final => "[*]".to_string(),
+            None => state_id(&target_path),
+        };
+
+        if label.is_empty() {
+            writeln!(self.out, "{}{} --> {}", indent, source, target).unwrap();
+        } else {
+            writeln!(self.out, "{}{} --> {} : {}", indent, source, target, label).unwrap();
+        }
+    }
+}
+
+fn state_id(path: &[&syn::Ident]) -> String {
+    pretty::path_id(path)
+}
+
+fn history_id(path: &[&syn::Ident], history: analyze::History) -> String {
+    // No path id has an underscore followed by `H`.
+    let name = match history {
+        analyze::History::Shallow => "H",
+        analyze::History::Deep => "Hdeep",
+    };
+    format!("{}_{}", state_id(path), name)
+}
+
+fn history_label(history: analyze::History) -> &'static str {
+    match history {
+        analyze::History::Shallow => "H",
+        analyze::History::Deep => "H*",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parse;
+
+    #[test]
+    fn basic() {
+        let ast: parse::UmlState = syn::parse_quote! {
+            machine Foo {
+                state A {
+                    entry / ctx.hello();
+                }
+                state B {
+                    state X;
+                    state Done final;
+                    <*> => X;
+                    X + F => Done;
+                }
+                <*> => A;
+                A + E(n) => B / ctx.go(n) if n > 0;
+            }
+        };
+
+        let model = analyze::analyze(ast).unwrap();
+        let lowered = lower::lower(model);
+        let mermaid = generate(&lowered.machines[0]);
+
+        assert_eq!(
+            mermaid,
+            "stateDiagram-v2\n\
+             \x20   state A\n\
+             \x20   A : entry / ctx.hello()\n\
+             \x20   state B {\n\
+             \x20       state \"X\" as B__X\n\
+             \x20       [*] --> B__X\n\
+             \x20       B__X --> [*] : F\n\
+             \x20   }\n\
+             \x20   [*] --> A\n\
+             \x20   A --> B : E(n) [n > 0] / ctx.go(n)\n"
+        );
+
+        let ast: parse::UmlState = syn::parse_quote! {
+            machine Bar {
+                state A {
+                    entry_point In;
+                    state B;
+                    <*> => B;
+                    In => B;
+                }
+                state A_B;
+                state J junction;
+                <*> => A_B;
+                A_B + E => J;
+                J => A.In if ctx.ok();
+                J => A if else;
+            }
+        };
+
+        let model = analyze::analyze(ast).unwrap();
+        let lowered = lower::lower(model);
+        let mermaid = generate(&lowered.machines[0]);
+
+        assert!(!mermaid.contains("<<choice>>"));
+        assert!(mermaid.contains("    state \"«junction» J\" as J\n"));
+        assert!(mermaid.contains("        state \"«entryPoint» In\" as A__In\n"));
+        assert!(mermaid.contains("        state \"B\" as A__B\n"));
+        assert!(mermaid.contains("    state \"A_B\" as A_uB\n"));
+    }
+}
@@ -6,6 +6,18 @@ mod kw {
     syn::custom_keyword!(machine);
     syn::custom_keyword!(state);
     syn::custom_keyword!(region);
+    syn::custom_keyword!(H);
+    syn::custom_keyword!(X);
+    syn::custom_keyword!(choice);
+    syn::custom_keyword!(junction);
+    syn::custom_keyword!(defer);
+    syn::custom_keyword!(after);
+    syn::custom_keyword!(cancel);
+    syn::custom_keyword!(forward);
+    syn::custom_keyword!(entry_point);
+    syn::custom_keyword!(exit_point);
+    syn::custom_keyword!(partial);
+    syn::custom_keyword!(queue_capacity);
 }
 
 #[derive(Clone)]
@@ -16,8 +28,11 @@ pub struct UmlState {
 #[derive(Clone)]
 pub struct Machine {
     pub vis: syn::Visibility,
+    pub async_token: Option<Token![async]>,
     pub machine_token: kw::machine,
     pub ident: syn::Ident,
+    /// `: Send`, for an `async machine` whose futures are `Send`.
+    pub send: Option<(Token![:], syn::Ident)>,
     pub brace_token: syn::token::Brace,
     pub items: Vec<MachineItem>,
 }
@@ -26,22 +41,85 @@ pub struct Machine {
 pub struct State {
     pub state_token: kw::state,
     pub ident: syn::Ident,
+    pub submachine: Option<(Token![:], syn::Path)>,
+    pub kind: Option<StateKind>,
     pub brace_token: Option<syn::token::Brace>,
     pub items: Vec<StateItem>,
     pub semi_token: Option<Token![;]>,
 }
 
+#[derive(Clone)]
+pub enum StateKind {
+    Final(Token![final]),
+    /// `choice`, or `choice(partial)` to do without an `else` branch.
+    Choice(kw::choice, Option<(syn::token::Paren, kw::partial)>),
+    Junction(kw::junction),
+}
+
 #[derive(Clone)]
 pub enum MachineItem {
     Method(syn::TraitItemMethod),
+    QueueCapacity(ItemQueueCapacity),
     StateItem(StateItem),
 }
 
+/// `queue_capacity = N;`, the number of events the queues of the machine
+/// hold without the `std` feature.
+#[derive(Clone)]
+pub struct ItemQueueCapacity {
+    pub queue_capacity_token: kw::queue_capacity,
+    pub eq_token: Token![=],
+    pub capacity: syn::Expr,
+    pub semi_token: Token![;],
+}
+
 #[derive(Clone)]
 pub enum StateItem {
     State(Box<State>),
     Region(Box<Region>),
     Transition(ItemTransition),
+    Defer(ItemDefer),
+    Activity(ItemActivity),
+    Forward(ItemForward),
+    EntryPoint(ItemEntryPoint),
+    ExitPoint(ItemExitPoint),
+}
+
+#[derive(Clone)]
+pub struct ItemDefer {
+    pub defer_token: kw::defer,
+    pub event: Event,
+    pub semi_token: Token![;],
+}
+
+#[derive(Clone)]
+pub struct ItemForward {
+    pub forward_token: kw::forward,
+    pub event: Event,
+    pub semi_token: Token![;],
+}
+
+#[derive(Clone)]
+pub struct ItemEntryPoint {
+    pub entry_point_token: kw::entry_point,
+    pub ident: syn::Ident,
+    pub semi_token: Token![;],
+}
+
+#[derive(Clone)]
+pub struct ItemExitPoint {
+    pub exit_point_token: kw::exit_point,
+    pub ident: syn::Ident,
+    pub semi_token: Token![;],
+}
+
+#[derive(Clone)]
+pub struct ItemActivity {
+    pub do_token: Token![do],
+    pub slash_token: Token![/],
+    pub activity: Box<syn::Expr>,
+    pub cancel: Option<(kw::cancel, Action)>,
+    pub semi_token: Token![;],
 }
 
 #[derive(Clone)]
@@ -55,8 +133,8 @@ pub struct Region {
 #[derive(Clone)]
 pub struct ItemTransition {
     pub source: TransitionSource,
-    pub event: Option<(Token![+], Event)>,
-    pub target: Option<(Token![=>], syn::Ident)>,
+    pub event: Option<(Token![+], Trigger)>,
+    pub target: Option<(Token![=>], TransitionTarget)>,
     pub action: Option<(Token![/], Action)>,
     pub guard: Option<(Token![if], Guard)>,
     pub semi_token: Token![;],
@@ -65,9 +143,16 @@ pub struct ItemTransition {
 #[derive(Clone)]
 pub enum TransitionSource {
     Initial(SourceInitial),
+    History(History),
+    Path(StatePath),
     State(syn::Pat),
 }
 
+#[derive(Clone)]
+pub struct StatePath {
+    pub segments: syn::punctuated::Punctuated<syn::Ident, Token![.]>,
+}
+
 #[derive(Clone)]
 pub struct SourceInitial {
     pub lt_token: Token![<],
@@ -75,19 +160,60 @@ pub struct SourceInitial {
     pub gt_token: Token![>],
 }
 
+#[derive(Clone)]
+pub struct History {
+    pub lt_token: Token![<],
+    pub h_token: kw::H,
+    pub asterisk_token: Option<Token![*]>,
+    pub gt_token: Token![>],
+}
+
+#[derive(Clone)]
+pub enum TransitionTarget {
+    State(TargetState),
+    Final(TargetFinal),
+}
+
+#[derive(Clone)]
+pub struct TargetState {
+    pub path: StatePath,
+    pub history: Option<(Token![.], History)>,
+}
+
+#[derive(Clone)]
+pub struct TargetFinal {
+    pub lt_token: Token![<],
+    pub x_token: kw::X,
+    pub gt_token: Token![>],
+}
+
+#[derive(Clone)]
+pub enum Trigger {
+    Event(Event),
+    After(After),
+}
+
 #[derive(Clone)]
 pub struct Event {
     pub pat: syn::Pat,
 }
 
+#[derive(Clone)]
+pub struct After {
+    pub after_token: kw::after,
+    pub paren_token: syn::token::Paren,
+    pub duration: Box<syn::Expr>,
+}
+
 #[derive(Clone)]
 pub struct Action {
     pub expr: Box<syn::Expr>,
 }
 
 #[derive(Clone)]
-pub struct Guard {
-    pub expr: Box<syn::Expr>,
+pub enum Guard {
+    Expr(Box<syn::Expr>),
+    Else(Token![else]),
 }
 
 impl Parse for UmlState {
@@ -117,8 +243,14 @@ impl Parse for Machine {
         let content;
         Ok(Machine {
             vis: input.parse()?,
+            async_token: input.parse()?,
             machine_token: input.parse()?,
             ident: input.parse()?,
+            send: if input.peek(Token![:]) {
+                Some((input.parse()?, input.parse()?))
+            } else {
+                None
+            },
             brace_token: syn::braced!(content in input),
             items: {
                 let mut items = Vec::new();
@@ -134,8 +266,13 @@ impl Parse for Machine {
 impl ToTokens for Machine {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         self.vis.to_tokens(tokens);
+        self.async_token.to_tokens(tokens);
         self.machine_token.to_tokens(tokens);
         self.ident.to_tokens(tokens);
+        if let Some((colon_token, send)) = &self.send {
+            colon_token.to_tokens(tokens);
+            send.to_tokens(tokens);
+        }
         self.brace_token.surround(tokens, |tokens| {
             for item in self.items.iter() {
                 item.to_tokens(tokens);
@@ -149,10 +286,28 @@ impl Parse for State {
         let content;
         let state_token = input.parse()?;
         let ident = input.parse()?;
+        let submachine = if input.peek(Token![:]) {
+            Some((input.parse()?, input.parse()?))
+        } else {
+            None
+        };
+        let kind: Option<StateKind> = if submachine.is_none()
+            && (input.peek(Token![final]) || input.peek(kw::choice) || input.peek(kw::junction))
+        {
+            Some(input.parse()?)
+        } else {
+            None
+        };
         let semi_token;
         let brace_token;
         let mut items = vec![];
-        if input.peek(Token![;]) {
+        if let (Some(kind), true) = (&kind, input.peek(syn::token::Brace)) {
+            return Err(Error::new_spanned(
+                kind,
+                "final, choice and junction states cannot have a body",
+            ));
+        }
+        if input.peek(Token![;]) || kind.is_some() {
             semi_token = input.parse()?;
             brace_token = None;
         } else {
@@ -166,6 +321,8 @@ impl Parse for State {
         Ok(State {
             state_token,
             ident,
+            submachine,
+            kind,
             brace_token,
             items,
             semi_token,
@@ -177,6 +334,11 @@ impl ToTokens for State {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         self.state_token.to_tokens(tokens);
         self.ident.to_tokens(tokens);
+        if let Some((colon, path)) = &self.submachine {
+            colon.to_tokens(tokens);
+            path.to_tokens(tokens);
+        }
+        self.kind.to_tokens(tokens);
         if let Some(b) = self.brace_token {
             b.surround(tokens, |tokens| {
                 for item in self.items.iter() {
@@ -221,9 +383,12 @@ impl ToTokens for Region {
 
 impl Parse for MachineItem {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
-        if input.peek(Token![fn]) {
+        if input.peek(Token![fn]) || (input.peek(Token![async]) && input.peek2(Token![fn])) {
             return Ok(MachineItem::Method(input.parse()?));
         }
+        if input.peek(kw::queue_capacity) {
+            return Ok(MachineItem::QueueCapacity(input.parse()?));
+        }
         Ok(MachineItem::StateItem(input.parse()?))
     }
 }
@@ -232,11 +397,32 @@ impl ToTokens for MachineItem {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         match self {
             MachineItem::Method(m) => m.to_tokens(tokens),
+            MachineItem::QueueCapacity(q) => q.to_tokens(tokens),
             MachineItem::StateItem(i) => i.to_tokens(tokens),
         }
     }
 }
 
+impl Parse for ItemQueueCapacity {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(ItemQueueCapacity {
+            queue_capacity_token: input.parse()?,
+            eq_token: input.parse()?,
+            capacity: input.parse()?,
+            semi_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for ItemQueueCapacity {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.queue_capacity_token.to_tokens(tokens);
+        self.eq_token.to_tokens(tokens);
+        self.capacity.to_tokens(tokens);
+        self.semi_token.to_tokens(tokens);
+    }
+}
+
 impl Parse for StateItem {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
         if input.peek(kw::state) {
@@ -245,6 +431,21 @@ impl Parse for StateItem {
         if input.peek(kw::region) {
             return Ok(StateItem::Region(input.parse()?));
         }
+        if input.peek(kw::defer) {
+            return Ok(StateItem::Defer(input.parse()?));
+        }
+        if input.peek(kw::forward) {
+            return Ok(StateItem::Forward(input.parse()?));
+        }
+        if input.peek(Token![do]) {
+            return Ok(StateItem::Activity(input.parse()?));
+        }
+        if input.peek(kw::entry_point) {
+            return Ok(StateItem::EntryPoint(input.parse()?));
+        }
+        if input.peek(kw::exit_point) {
+            return Ok(StateItem::ExitPoint(input.parse()?));
+        }
         Ok(StateItem::Transition(input.parse()?))
     }
 }
@@ -255,10 +456,116 @@ impl ToTokens for StateItem {
             StateItem::State(s) => s.to_tokens(tokens),
             StateItem::Region(r) => r.to_tokens(tokens),
             StateItem::Transition(t) => t.to_tokens(tokens),
+            StateItem::Defer(d) => d.to_tokens(tokens),
+            StateItem::Activity(a) => a.to_tokens(tokens),
+            StateItem::Forward(f) => f.to_tokens(tokens),
+            StateItem::EntryPoint(p) => p.to_tokens(tokens),
+            StateItem::ExitPoint(p) => p.to_tokens(tokens),
         }
     }
 }
 
+impl Parse for ItemDefer {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(ItemDefer {
+            defer_token: input.parse()?,
+            event: input.parse()?,
+            semi_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for ItemDefer {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.defer_token.to_tokens(tokens);
+        self.event.to_tokens(tokens);
+        self.semi_token.to_tokens(tokens);
+    }
+}
+
+impl Parse for ItemForward {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(ItemForward {
+            forward_token: input.parse()?,
+            event: input.parse()?,
+            semi_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for ItemForward {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.forward_token.to_tokens(tokens);
+        self.event.to_tokens(tokens);
+        self.semi_token.to_tokens(tokens);
+    }
+}
+
+impl Parse for ItemEntryPoint {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(ItemEntryPoint {
+            entry_point_token: input.parse()?,
+            ident: input.parse()?,
+            semi_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for ItemEntryPoint {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.entry_point_token.to_tokens(tokens);
+        self.ident.to_tokens(tokens);
+        self.semi_token.to_tokens(tokens);
+    }
+}
+
+impl Parse for ItemExitPoint {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(ItemExitPoint {
+            exit_point_token: input.parse()?,
+            ident: input.parse()?,
+            semi_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for ItemExitPoint {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.exit_point_token.to_tokens(tokens);
+        self.ident.to_tokens(tokens);
+        self.semi_token.to_tokens(tokens);
+    }
+}
+
+impl Parse for ItemActivity {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(ItemActivity {
+            do_token: input.parse()?,
+            slash_token: input.parse()?,
+            activity: input.parse()?,
+            cancel: if input.peek(kw::cancel) {
+                Some((input.parse()?, input.parse()?))
+            } else {
+                None
+            },
+            semi_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for ItemActivity {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.do_token.to_tokens(tokens);
+        self.slash_token.to_tokens(tokens);
+        self.activity.to_tokens(tokens);
+        if let Some((cancel, action)) = &self.cancel {
+            cancel.to_tokens(tokens);
+            action.to_tokens(tokens);
+        }
+        self.semi_token.to_tokens(tokens);
+    }
+}
+
 impl Parse for ItemTransition {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
         Ok(ItemTransition {
@@ -313,7 +620,13 @@ impl ToTokens for ItemTransition {
 impl Parse for TransitionSource {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
         if input.peek(Token![<]) {
-            return Ok(TransitionSource::Initial(input.parse()?));
+            if input.peek2(Token![*]) {
+                return Ok(TransitionSource::Initial(input.parse()?));
+            }
+            return Ok(TransitionSource::History(input.parse()?));
+        }
+        if input.peek(syn::Ident) && input.peek2(Token![.]) {
+            return Ok(TransitionSource::Path(input.parse()?));
         }
         return Ok(TransitionSource::State(input.parse()?));
     }
@@ -323,11 +636,34 @@ impl ToTokens for TransitionSource {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
         match self {
             TransitionSource::Initial(i) => i.to_tokens(tokens),
+            TransitionSource::History(h) => h.to_tokens(tokens),
+            TransitionSource::Path(p) => p.to_tokens(tokens),
             TransitionSource::State(s) => s.to_tokens(tokens),
         }
     }
 }
 
+impl Parse for StatePath {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let mut segments = syn::punctuated::Punctuated::new();
+        loop {
+            segments.push_value(input.parse()?);
+            // A trailing `.<H>` belongs to the enclosing target, not the path.
+            if !input.peek(Token![.]) || input.peek2(Token![<]) {
+                break;
+            }
+            segments.push_punct(input.parse()?);
+        }
+        Ok(StatePath { segments })
+    }
+}
+
+impl ToTokens for StatePath {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.segments.to_tokens(tokens);
+    }
+}
+
 impl Parse for SourceInitial {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
         Ok(SourceInitial {
@@ -346,6 +682,114 @@ impl ToTokens for SourceInitial {
     }
 }
 
+impl Parse for History {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(History {
+            lt_token: input.parse()?,
+            h_token: input.parse()?,
+            asterisk_token: input.parse()?,
+            gt_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for History {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.lt_token.to_tokens(tokens);
+        self.h_token.to_tokens(tokens);
+        self.asterisk_token.to_tokens(tokens);
+        self.gt_token.to_tokens(tokens);
+    }
+}
+
+impl Parse for TransitionTarget {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        if input.peek(Token![<]) {
+            return Ok(TransitionTarget::Final(input.parse()?));
+        }
+        Ok(TransitionTarget::State(input.parse()?))
+    }
+}
+
+impl ToTokens for TransitionTarget {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        match self {
+            TransitionTarget::State(s) => s.to_tokens(tokens),
+            TransitionTarget::Final(f) => f.to_tokens(tokens),
+        }
+    }
+}
+
+impl Parse for TargetState {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(TargetState {
+            path: input.parse()?,
+            history: if input.peek(Token![.]) {
+                Some((input.parse()?, input.parse()?))
+            } else {
+                None
+            },
+        })
+    }
+}
+
+impl ToTokens for TargetState {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.path.to_tokens(tokens);
+        if let Some((dot, history)) = &self.history {
+            dot.to_tokens(tokens);
+            history.to_tokens(tokens);
+        }
+    }
+}
+
+impl Parse for TargetFinal {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        Ok(TargetFinal {
+            lt_token: input.parse()?,
+            x_token: input.parse()?,
+            gt_token: input.parse()?,
+        })
+    }
+}
+
+impl ToTokens for TargetFinal {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        self.lt_token.to_tokens(tokens);
+        self.x_token.to_tokens(tokens);
+        self.gt_token.to_tokens(tokens);
+    }
+}
+
+impl Parse for Trigger {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        if input.peek(kw::after) && input.peek2(syn::token::Paren) {
+            let content;
+            Ok(Trigger::After(After {
+                after_token: input.parse()?,
+                paren_token: syn::parenthesized!(content in input),
+                duration: content.parse()?,
+            }))
+        } else {
+            Ok(Trigger::Event(input.parse()?))
+        }
+    }
+}
+
+impl ToTokens for Trigger {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        match self {
+            Trigger::Event(event) => event.to_tokens(tokens),
+            Trigger::After(after) => {
+                after.after_token.to_tokens(tokens);
+                after
+                    .paren_token
+                    .surround(tokens, |tokens| after.duration.to_tokens(tokens));
+            }
+        }
+    }
+}
+
 impl Parse for Event {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
         let pat = input.parse()?;
@@ -371,6 +815,7 @@ impl Parse for Action {
         match expr.as_ref() {
             syn::Expr::Assign(_)
             | syn::Expr::AssignOp(_)
+            | syn::Expr::Await(_)
             | syn::Expr::Block(_)
             | syn::Expr::Call(_)
             | syn::Expr::Group(_)
@@ -389,17 +834,60 @@ impl ToTokens for Action {
     }
 }
 
+impl Parse for StateKind {
+    fn parse(input: syn::parse::ParseStream) -> Result<Self> {
+        let lookahead = input.lookahead1();
+        if lookahead.peek(Token![final]) {
+            Ok(StateKind::Final(input.parse()?))
+        } else if lookahead.peek(kw::choice) {
+            let choice_token = input.parse()?;
+            let partial = if input.peek(syn::token::Paren) {
+                let content;
+                let paren_token = syn::parenthesized!(content in input);
+                Some((paren_token, content.parse()?))
+            } else {
+                None
+            };
+            Ok(StateKind::Choice(choice_token, partial))
+        } else if lookahead.peek(kw::junction) {
+            Ok(StateKind::Junction(input.parse()?))
+        } else {
+            Err(lookahead.error())
+        }
+    }
+}
+
+impl ToTokens for StateKind {
+    fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
+        match self {
+            StateKind::Final(t) => t.to_tokens(tokens),
+            StateKind::Choice(t, partial) => {
+                t.to_tokens(tokens);
+                if let Some((paren_token, partial)) = partial {
+                    paren_token.surround(tokens, |tokens| partial.to_tokens(tokens));
+                }
+            }
+            StateKind::Junction(t) => t.to_tokens(tokens),
+        }
+    }
+}
+
 impl Parse for Guard {
     fn parse(input: syn::parse::ParseStream) -> Result<Self> {
-        Ok(Guard {
-            expr: input.parse()?,
-        })
+        if input.peek(Token![else]) {
+            Ok(Guard::Else(input.parse()?))
+        } else {
+            Ok(Guard::Expr(input.parse()?))
+        }
     }
 }
 
 impl ToTokens for Guard {
     fn to_tokens(&self, tokens: &mut proc_macro2::TokenStream) {
-        self.expr.to_tokens(tokens);
+        match self {
+            Guard::Expr(expr) => expr.to_tokens(tokens),
+            Guard::Else(t) => t.to_tokens(tokens),
+        }
     }
 }
 
@@ -412,6 +900,8 @@ mod tests {
     fn parse_umlstate() {
         let _sm: UmlState = parse_quote! {
             machine Foo {
+                queue_capacity = 32;
+
                 state S1;
 
                 <*> => S1;
@@ -422,10 +912,36 @@ mod tests {
                 state M2 {
                     state A;
                     state B;
+                    entry_point Resume;
+                    exit_point Abort;
 
+                    defer E3;
+                    do / poll() cancel stop();
                     <*> => A;
+                    <H*> => B;
                     A + E1 => B;
+                    B + E2 => <X>;
+                    Resume => B;
+                    A + E4 => Abort;
                 }
+
+                state Done final;
+                M2 => Done;
+
+                state P: power::PowerMachine {
+                    forward E5;
+                }
+                P => Done;
+
+                S1 + E3 => M2.<H>;
+                S1 + E5 => M2.Resume;
+                M2.Abort => S1;
+                M2.A + E3 => S1;
+
+                state C choice(partial);
+                S1 + E4 => C;
+                C => M2 if ready();
+                C => S1 if else;
             }
         };
     }
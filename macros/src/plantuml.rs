@@ -0,0 +1,228 @@
+use std::fmt::Write;
+
+use crate::{analyze, lower, pretty};
+
+/// Renders a machine as a PlantUML state diagram.
+pub fn generate(machine: &lower::TopMachine) -> String {
+    let mut w = Writer {
+        machine,
+        out: String::new(),
+    };
+    w.out.push_str("@startuml\n");
+    w.write_contents(&machine.state, &[], 0);
+    w.out.push_str("@enduml\n");
+    w.out
+}
+
+struct Writer<'a> {
+    machine: &'a lower::TopMachine,
+    out: String,
+}
+
+impl<'a> Writer<'a> {
+    fn write_contents(&mut self, state: &lower::State, path: &[&syn::Ident], depth: usize) {
+        let indent = "  ".repeat(depth);
+
+        for pseudostate in &state.pseudostates {
+            let stereotype = match pseudostate.kind {
+                analyze::PseudostateKind::Choice => "choice",
+                analyze::PseudostateKind::Junction => "junction",
+                analyze::PseudostateKind::EntryPoint => "entryPoint",
+            };
+            writeln!(
+                self.out,
+                "{}state {} <<{}>>",
+                indent,
+                declaration(path, &pseudostate.ident),
+                stereotype
+            )
+            .unwrap();
+        }
+        for exit_point in &state.exit_points {
+            writeln!(
+                self.out,
+                "{}state {} <<exitPoint>>",
+                indent,
+                declaration(path, &exit_point.ident)
+            )
+            .unwrap();
+        }
+
+        for sub_state in &state.states {
+            let mut sub_path = path.to_vec();
+            sub_path.push(&sub_state.ident);
+            self.write_state(sub_state, &sub_path, depth);
+        }
+
+        for (i, region) in state.regions.iter().enumerate() {
+            if i > 0 {
+                writeln!(self.out, "{}--", indent).unwrap();
+            }
+            let mut region_path = path.to_vec();
+            region_path.push(&region.ident);
+            self.write_contents(region, &region_path, depth);
+        }
+
+        if let Some(t) = &state.initial_transition {
+            self.write_transition("[*]".to_string(), path, t, &indent);
+        }
+        for (history, default) in [
+            (analyze::History::Shallow, &state.shallow_history),
+            (analyze::History::Deep, &state.deep_history),
+        ] {
+            if let Some(t) = default {
+                let source = format!("{}{}", state_id(path), history_suffix(history));
+                self.write_transition(source, path, t, &indent);
+            }
+        }
+        for pseudostate in &state.pseudostates {
+            let mut source = path.to_vec();
+            source.push(&pseudostate.ident);
+            for t in &pseudostate.transitions {
+                let label = pretty::transition_label(self.machine, t);
+                self.write_labelled(state_id(&source), path, t, label, &indent);
+            }
+            if let Some(t) = &pseudostate.else_transition {
+                let label = pretty::else_label(self.machine, t);
+                self.write_labelled(state_id(&source), path, t, label, &indent);
+            }
+        }
+        for exit_point in &state.exit_points {
+            for t in exit_point.incoming.iter().filter(|t| !t.through_exit_point) {
+                let mut source = path.to_vec();
+                source.extend(&t.source_path);
+                self.write_transition(state_id(&source), path, t, &indent);
+            }
+        }
+        for sub_state in &state.states {
+            for t in pretty::out_transitions(sub_state) {
+                let mut source = path.to_vec();
+                source.push(&sub_state.ident);
+                source.extend(&t.source_path);
+                self.write_transition(state_id(&source), path, t, &indent);
+            }
+        }
+    }
+
+    fn write_state(&mut self, state: &lower::State, path: &[&syn::Ident], depth: usize) {
+        let indent = "  ".repeat(depth);
+        let decl = declaration(&path[..path.len() - 1], &state.ident);
+
+        if state.kind == analyze::StateKind::Final {
+            writeln!(self.out, "{}state {} <<end>>", indent, decl).unwrap();
+            return;
+        }
+
+        if state.states.is_empty() && state.regions.is_empty() {
+            writeln!(self.out, "{}state {}", indent, decl).unwrap();
+        } else {
+            writeln!(self.out, "{}state {} {{", indent, decl).unwrap();
+            self.write_contents(state, path, depth + 1);
+            writeln!(self.out, "{}}}", indent).unwrap();
+        }
+
+        let id = state_id(path);
+        for line in pretty::descriptions(self.machine, state) {
+            writeln!(self.out, "{}{} : {}", indent, id, line).unwrap();
+        }
+    }
+
+    fn write_transition(
+        &mut self,
+        source: String,
+        scope: &[&syn::Ident],
+        t: &lower::Transition,
+        indent: &str,
+    ) {
+        let label = pretty::transition_label(self.machine, t);
+        self.write_labelled(source, scope, t, label, indent);
+    }
+
+    fn write_labelled(
+        &mut self,
+        source: String,
+        scope: &[&syn::Ident],
+        t: &lower::Transition,
+        label: String,
+        indent: &str,
+    ) {
+        let mut target = scope.to_vec();
+        target.extend(t.target.iter());
+        target.extend(&t.target_path);
+        let mut target = state_id(&target);
+        if let Some(history) = t.target_history {
+            target.push_str(history_suffix(history));
+        }
+
+        if label.is_empty() {
+            writeln!(self.out, "{}{} --> {}", indent, source, target).unwrap();
+        } else {
+            writeln!(self.out, "{}{} --> {} : {}", indent, source, target, label).unwrap();
+        }
+    }
+}
+
+/// Declares a state by name, aliased to its path id unless that is the name.
+fn declaration(scope: &[&syn::Ident], ident: &syn::Ident) -> String {
+    let mut path = scope.to_vec();
+    path.push(ident);
+    let id = state_id(&path);
+    if *ident == id {
+        id
+    } else {
+        format!("\"{}\" as {}", ident, id)
+    }
+}
+
+fn state_id(path: &[&syn::Ident]) -> String {
+    pretty::path_id(path)
+}
+
+fn history_suffix(history: analyze::History) -> &'static str {
+    match history {
+        analyze::History::Shallow => "[H]",
+        analyze::History::Deep => "[H*]",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::parse;
+
+    #[test]
+    fn basic() {
+        let ast: parse::UmlState = syn::parse_quote! {
+            machine Foo {
+                state A {
+                    entry / ctx.hello();
+                }
+                state B {
+                    state X;
+                    <*> => X;
+                }
+                <*> => A;
+                A + E(n) => B / ctx.go(n) if n > 0;
+            }
+        };
+
+        let model = analyze::analyze(ast).unwrap();
+        let lowered = lower::lower(model);
+        let uml = generate(&lowered.machines[0]);
+
+        assert_eq!(
+            uml,
+            "@startuml\n\
+             state A\n\
+             A : entry / ctx.hello()\n\
+             state B {\n\
+             \x20 state \"X\" as B__X\n\
+             \x20 [*] --> B__X\n\
+             }\n\
+             [*] --> A\n\
+             A --> B : E(n) [n > 0] / ctx.go(n)\n\
+             @enduml\n"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use proc_macro2::{Delimiter, Spacing, TokenStream, TokenTree};
+use quote::ToTokens;
+
+use crate::lower;
+
+/// Renders tokens roughly the way rustfmt would, for use in diagram labels.
+pub fn tokens(tokens: &impl ToTokens) -> String {
+    let mut out = String::new();
+    write_tokens(&mut out, tokens.to_token_stream());
+    out
+}
+
+/// Entry, do and exit behaviors, internal transitions and deferred events of
+/// a state, one per line.
+pub fn descriptions(machine: &lower::TopMachine, state: &lower::State) -> Vec<String> {
+    let mut lines = vec![];
+    if let Some(entry) = &state.entry {
+        lines.push(format!("entry / {}", tokens(entry)));
+    }
+    if let Some(activity) = &state.activity {
+        lines.push(format!("do / {}", tokens(&activity.start)));
+    }
+    if let Some(exit) = &state.exit {
+        lines.push(format!("exit / {}", tokens(exit)));
+    }
+    for t in &state.internal_transitions {
+        lines.push(transition_label(machine, t));
+    }
+    for d in &state.deferred_events {
+        if let Some(event) = event_label(machine, Some(&d.event), d.event_pat.as_ref()) {
+            lines.push(format!("{} / defer", event));
+        }
+    }
+    lines
+}
+
+/// Id of the state at `path` for PlantUML and Mermaid, where state names
+/// are global and so nested states are named after their whole path.  The
+/// names are joined with `__`, and their own underscores are spelled `_u`,
+/// so that `A_B` and `A.B` get different ids.
+pub fn path_id(path: &[&syn::Ident]) -> String {
+    path.iter()
+        .map(|i| i.to_string().replace('_', "_u"))
+        .collect::<Vec<_>>()
+        .join("__")
+}
+
+/// Variant of the state path enum for the state or region at `path`, the
+/// concatenation of the names on it, like `PluggedCharge` for
+/// `Plugged.Charge`.
+pub fn path_variant(path: &[&syn::Ident]) -> syn::Ident {
+    let name: String = path.iter().map(|i| i.to_string()).collect();
+    quote::format_ident!("{}", name)
+}
+
+/// The transitions leaving `state` as drawn, where a transition through an
+/// exit point is split at the exit point.  The transitions reaching the exit
+/// point are drawn along with it.
+pub fn out_transitions(state: &lower::State) -> impl Iterator<Item = &lower::Transition> {
+    state
+        .out_transitions
+        .iter()
+        .filter(|t| !t.through_exit_point)
+        .chain(&state.exit_transitions)
+}
+
+/// Label of a transition in the usual `Event [guard] / action` notation.
+pub fn transition_label(machine: &lower::TopMachine, t: &lower::Transition) -> String {
+    let guard = t.guard.as_ref().map(|g| format!("[{}]", tokens(g)));
+    label(machine, t, guard)
+}
+
+/// Label of the `else` branch of a choice or junction.
+pub fn else_label(machine: &lower::TopMachine, t: &lower::Transition) -> String {
+    label(machine, t, Some("[else]".to_string()))
+}
+
+fn label(machine: &lower::TopMachine, t: &lower::Transition, guard: Option<String>) -> String {
+    let mut parts = vec![];
+    if let Some(timer) = &t.timer {
+        parts.push(format!("after({})", tokens(&timer.duration)));
+    } else if let Some(event) = event_label(machine, t.event.as_ref(), t.event_pat.as_ref()) {
+        parts.push(event);
+    }
+    parts.extend(guard);
+    if let Some(action) = &t.action {
+        parts.push(format!("/ {}", tokens(action)));
+    }
+    parts.join(" ")
+}
+
+/// The event pattern as written, or the path of the event type.
+pub fn event_label(
+    machine: &lower::TopMachine,
+    event: Option<&syn::Ident>,
+    event_pat: Option<&syn::Pat>,
+) -> Option<String> {
+    if let Some(pat) = event_pat {
+        return Some(tokens(pat));
+    }
+    let event = event?;
+    machine
+        .events
+        .iter()
+        .find(|(_, ident)| ident == event)
+        .map(|(path, _)| tokens(path))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Prev {
+    Start,
+    Word,
+    Close,
+    // A punctuation character that binds to the following token.
+    Prefix,
+    // A punctuation character followed by a space.
+    Operator,
+}
+
+fn write_tokens(out: &mut String, tokens: TokenStream) {
+    let tokens: Vec<_> = tokens.into_iter().collect();
+    let mut prev = Prev::Start;
+
+    for (i, tt) in tokens.iter().enumerate() {
+        match tt {
+            TokenTree::Ident(_) | TokenTree::Literal(_) => {
+                if matches!(prev, Prev::Word | Prev::Close | Prev::Operator) {
+                    out.push(' ');
+                }
+                out.push_str(&tt.to_string());
+                prev = Prev::Word;
+            }
+            TokenTree::Group(g) => {
+                let (open, close) = match g.delimiter() {
+                    Delimiter::Parenthesis => ("(", ")"),
+                    Delimiter::Bracket => ("[", "]"),
+                    Delimiter::Brace => ("{ ", " }"),
+                    Delimiter::None => ("", ""),
+                };
+                let call = matches!(g.delimiter(), Delimiter::Parenthesis | Delimiter::Bracket)
+                    && matches!(prev, Prev::Word | Prev::Close);
+                if !call && matches!(prev, Prev::Word | Prev::Close | Prev::Operator) {
+                    out.push(' ');
+                }
+                out.push_str(open);
+                write_tokens(out, g.stream());
+                out.push_str(close);
+                prev = Prev::Close;
+            }
+            TokenTree::Punct(p) => {
+                let c = p.as_char();
+                let joint_prev = i > 0
+                    && matches!(&tokens[i - 1], TokenTree::Punct(q) if q.spacing() == Spacing::Joint);
+                let joint_next = p.spacing() == Spacing::Joint;
+                let unary =
+                    matches!(prev, Prev::Start | Prev::Prefix | Prev::Operator) && !joint_prev;
+
+                if c == '.' || c == ':' || (c == '!' && prev == Prev::Word) {
+                    if c == '.' && prev == Prev::Operator && !joint_prev {
+                        out.push(' ');
+                    }
+                    out.push(c);
+                    prev = Prev::Prefix;
+                } else if c == ',' || c == ';' {
+                    out.push(c);
+                    prev = Prev::Operator;
+                } else if unary && matches!(c, '&' | '*' | '-' | '!') {
+                    if prev == Prev::Operator {
+                        out.push(' ');
+                    }
+                    out.push(c);
+                    prev = Prev::Prefix;
+                } else {
+                    if !joint_prev && prev != Prev::Start {
+                        out.push(' ');
+                    }
+                    out.push(c);
+                    prev = if joint_next {
+                        Prev::Prefix
+                    } else {
+                        Prev::Operator
+                    };
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic() {
+        let expr: syn::Expr = syn::parse_quote! { ctx.log(n, "x") };
+        assert_eq!(tokens(&expr), "ctx.log(n, \"x\")");
+
+        let expr: syn::Expr = syn::parse_quote! { n >= 0 && !ctx.ready() };
+        assert_eq!(tokens(&expr), "n >= 0 && !ctx.ready()");
+
+        let path: syn::Path = syn::parse_quote! { events::Reset };
+        assert_eq!(tokens(&path), "events::Reset");
+    }
+}
@@ -0,0 +1,1019 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use convert_case::{Case, Casing};
+use proc_macro2::TokenStream;
+use quote::quote;
+use syn::{Error, Result};
+
+use crate::{analyze, lower, parse, pretty};
+
+/// Resolves the path given to `umlstate_scxml!` against the root of the
+/// crate being compiled.
+pub fn resolve(path: &syn::LitStr) -> PathBuf {
+    let root = std::env::var_os("CARGO_MANIFEST_DIR").unwrap_or_default();
+    Path::new(&root).join(path.value())
+}
+
+/// Reads an SCXML document and translates it into the machine it describes.
+/// Conditions and scripts become methods of the machine context.
+pub fn import(path: &syn::LitStr) -> Result<parse::UmlState> {
+    let text = std::fs::read_to_string(resolve(path)).map_err(|err| {
+        Error::new_spanned(path, format!("cannot read {}: {}", path.value(), err))
+    })?;
+    translate(&text, path)
+}
+
+fn translate(text: &str, path: &syn::LitStr) -> Result<parse::UmlState> {
+    let doc = roxmltree::Document::parse(text)
+        .map_err(|err| Error::new_spanned(path, format!("{}: {}", path.value(), err)))?;
+    let mut importer = Importer {
+        doc: &doc,
+        path,
+        states: HashMap::new(),
+        histories: HashMap::new(),
+        methods: vec![],
+        transitions: vec![],
+    };
+    let machine = importer.machine(doc.root_element())?;
+    syn::parse2(machine)
+}
+
+type Node<'a, 'input> = roxmltree::Node<'a, 'input>;
+
+struct Importer<'a, 'input> {
+    doc: &'a roxmltree::Document<'input>,
+    path: &'a syn::LitStr,
+    /// Path of every state by id, and whether it is a region.
+    states: HashMap<&'a str, (Vec<syn::Ident>, bool)>,
+    /// Path of the owning state of every history by id, and whether the
+    /// history is deep.
+    histories: HashMap<&'a str, (Vec<syn::Ident>, bool)>,
+    methods: Vec<(syn::Ident, Method)>,
+    /// Transitions between states, written at the top of the machine with
+    /// fully qualified paths.
+    transitions: Vec<TokenStream>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Method {
+    Condition,
+    Script,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Scope {
+    Root,
+    State,
+    Region,
+}
+
+impl<'a, 'input> Importer<'a, 'input> {
+    fn machine(&mut self, root: Node<'a, 'input>) -> Result<TokenStream> {
+        if root.tag_name().name() != "scxml" {
+            return Err(self.error(root, "expected an `<scxml>` document"));
+        }
+        let ident = match root.attribute("name") {
+            Some(name) => self.ident(root, name)?,
+            None => {
+                let file = Path::new(&self.path.value())
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+                self.ident(root, &file)?
+            }
+        };
+
+        self.collect(root, &[], false)?;
+        let items = self.contents(root, &[], Scope::Root)?;
+
+        let methods = self.methods.iter().map(|(name, method)| match method {
+            Method::Condition => quote! { fn #name(&self) -> bool; },
+            Method::Script => quote! { fn #name(&mut self); },
+        });
+        let transitions = &self.transitions;
+        Ok(quote! {
+            machine #ident {
+                #(#methods)*
+                #items
+                #(#transitions)*
+            }
+        })
+    }
+
+    /// Records the path of every state and history, so that transitions can
+    /// refer to states declared later in the document.
+    fn collect(
+        &mut self,
+        node: Node<'a, 'input>,
+        path: &[syn::Ident],
+        parallel: bool,
+    ) -> Result<()> {
+        for child in node.children().filter(Node::is_element) {
+            match child.tag_name().name() {
+                "state" | "parallel" | "final" => {
+                    let id = self.id(child)?;
+                    let mut child_path = path.to_vec();
+                    child_path.push(self.ident(child, id)?);
+                    if self.states.contains_key(id) || self.histories.contains_key(id) {
+                        return Err(self.error(child, format!("duplicate id `{}`", id)));
+                    }
+                    self.states.insert(id, (child_path.clone(), parallel));
+                    self.collect(child, &child_path, child.tag_name().name() == "parallel")?;
+                }
+                "history" => {
+                    let id = self.id(child)?;
+                    if self.states.contains_key(id) || self.histories.contains_key(id) {
+                        return Err(self.error(child, format!("duplicate id `{}`", id)));
+                    }
+                    let deep = child.attribute("type") == Some("deep");
+                    self.histories.insert(id, (path.to_vec(), deep));
+                }
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    fn contents(
+        &mut self,
+        node: Node<'a, 'input>,
+        path: &[syn::Ident],
+        scope: Scope,
+    ) -> Result<TokenStream> {
+        let parallel = node.tag_name().name() == "parallel";
+        let mut items = vec![];
+        let mut first = None;
+
+        for child in node.children().filter(Node::is_element) {
+            match child.tag_name().name() {
+                "state" | "parallel" | "final" => {
+                    first.get_or_insert(child);
+                    items.push(self.state(child, path, parallel)?);
+                }
+                "history" => items.push(self.history(child, path)?),
+                "initial" => (),
+                // The machine context takes the place of the data model.
+                "datamodel" => (),
+                "onentry" | "onexit" | "transition" if scope != Scope::State => {
+                    return Err(self.unsupported(
+                        child,
+                        format!(
+                            "`<{}>` is only supported in `<state>` and `<parallel>`, not in `<scxml>` or the children of `<parallel>`",
+                            child.tag_name().name()
+                        ),
+                    ));
+                }
+                "onentry" => {
+                    if let Some(action) = self.action(child)? {
+                        items.push(quote! { entry / #action; });
+                    }
+                }
+                "onexit" => {
+                    if let Some(action) = self.action(child)? {
+                        items.push(quote! { exit / #action; });
+                    }
+                }
+                "transition" => {
+                    if let Some(internal) = self.transition(child, path)? {
+                        items.push(internal);
+                    }
+                }
+                name => {
+                    return Err(
+                        self.unsupported(child, format!("unsupported element `<{}>`", name))
+                    );
+                }
+            }
+        }
+
+        if let (false, Some(first)) = (parallel, first) {
+            items.push(self.initial(node, first, path)?);
+        }
+
+        Ok(quote! { #(#items)* })
+    }
+
+    fn state(
+        &mut self,
+        node: Node<'a, 'input>,
+        scope: &[syn::Ident],
+        region: bool,
+    ) -> Result<TokenStream> {
+        let ident = self.ident(node, self.id(node)?)?;
+        let mut path = scope.to_vec();
+        path.push(ident.clone());
+
+        match (node.tag_name().name(), region) {
+            ("final", false) => {
+                if let Some(child) = node.children().find(Node::is_element) {
+                    return Err(
+                        self.unsupported(child, "final states cannot have entry or exit behavior")
+                    );
+                }
+                Ok(quote! { state #ident final; })
+            }
+            ("state", true) => {
+                if !node.children().any(|c| is_state(&c)) {
+                    return Err(self.unsupported(
+                        node,
+                        "children of `<parallel>` must have sub-states of their own",
+                    ));
+                }
+                let items = self.contents(node, &path, Scope::Region)?;
+                Ok(quote! { region #ident { #items } })
+            }
+            (_, true) => {
+                Err(self.unsupported(node, "children of `<parallel>` must be `<state>` elements"))
+            }
+            _ => {
+                let items = self.contents(node, &path, Scope::State)?;
+                if items.is_empty() {
+                    Ok(quote! { state #ident; })
+                } else {
+                    Ok(quote! { state #ident { #items } })
+                }
+            }
+        }
+    }
+
+    fn initial(
+        &mut self,
+        node: Node<'a, 'input>,
+        first: Node<'a, 'input>,
+        path: &[syn::Ident],
+    ) -> Result<TokenStream> {
+        let initial = node.children().find(|c| c.has_tag_name("initial"));
+        let (source, target, action) = match (initial, node.attribute("initial")) {
+            (Some(initial), _) => {
+                let t = initial
+                    .children()
+                    .find(|c| c.has_tag_name("transition"))
+                    .ok_or_else(|| self.error(initial, "`<initial>` needs a `<transition>`"))?;
+                let target = t
+                    .attribute("target")
+                    .ok_or_else(|| self.error(t, "initial transition needs a `target`"))?;
+                (t, target, self.action(t)?)
+            }
+            (None, Some(target)) => (node, target, None),
+            (None, None) => (node, self.id(first)?, None),
+        };
+        let target = self.target(source, target, path)?;
+        match action {
+            Some(action) => Ok(quote! { <*> => #target / #action; }),
+            None => Ok(quote! { <*> => #target; }),
+        }
+    }
+
+    fn history(&mut self, node: Node<'a, 'input>, path: &[syn::Ident]) -> Result<TokenStream> {
+        let source = match node.attribute("type") {
+            Some("deep") => quote! { <H*> },
+            None | Some("shallow") => quote! { <H> },
+            Some(other) => {
+                return Err(self.error(node, format!("unknown history type `{}`", other)));
+            }
+        };
+        let t = match node.children().find(|c| c.has_tag_name("transition")) {
+            Some(t) => t,
+            None => return Ok(quote! {}),
+        };
+        let target = t
+            .attribute("target")
+            .ok_or_else(|| self.error(t, "history default transition needs a `target`"))?;
+        let target = self.target(t, target, path)?;
+        match self.action(t)? {
+            Some(action) => Ok(quote! { #source => #target / #action; }),
+            None => Ok(quote! { #source => #target; }),
+        }
+    }
+
+    /// Translates a transition of the state at `path`.  A transition with a
+    /// target is added to the machine; an internal one is returned, as it
+    /// belongs to the state itself.
+    fn transition(
+        &mut self,
+        node: Node<'a, 'input>,
+        path: &[syn::Ident],
+    ) -> Result<Option<TokenStream>> {
+        let events = match node.attribute("event") {
+            Some(events) => events
+                .split_whitespace()
+                .map(|event| {
+                    if event.contains('*') {
+                        return Err(self.unsupported(node, "event wildcards are not supported"));
+                    }
+                    self.ident(node, event)
+                })
+                .collect::<Result<Vec<_>>>()?,
+            None => vec![],
+        };
+        let guard = match node.attribute("cond") {
+            Some(cond) => {
+                let name = self.method(node, cond, Method::Condition)?;
+                Some(quote! { if ctx.#name() })
+            }
+            None => None,
+        };
+        let action = self.action(node)?;
+
+        let target = match node.attribute("target") {
+            Some(target) => target,
+            None if events.is_empty() => {
+                return Err(self.error(node, "eventless transition needs a `target`"));
+            }
+            None => {
+                let action = action.unwrap_or_else(|| quote! { {} });
+                let internal = events.iter().map(|event| {
+                    quote! { #event / #action #guard; }
+                });
+                return Ok(Some(quote! { #(#internal)* }));
+            }
+        };
+        let target = self.target(node, target, &[])?;
+        let action = action.map(|action| quote! { / #action });
+        if events.is_empty() {
+            self.transitions
+                .push(quote! { #(#path).* => #target #action #guard; });
+        }
+        for event in events {
+            self.transitions
+                .push(quote! { #(#path).* + #event => #target #action #guard; });
+        }
+        Ok(None)
+    }
+
+    /// The path of the state or history `id`, relative to `scope`.
+    fn target(
+        &self,
+        node: Node<'a, 'input>,
+        id: &str,
+        scope: &[syn::Ident],
+    ) -> Result<TokenStream> {
+        let mut ids = id.split_whitespace();
+        let id = ids
+            .next()
+            .ok_or_else(|| self.error(node, "transition needs a `target`"))?;
+        if ids.next().is_some() {
+            return Err(
+                self.unsupported(node, "transitions with several targets are not supported")
+            );
+        }
+
+        let (path, history) = match (self.states.get(id), self.histories.get(id)) {
+            (Some((_, true)), _) => {
+                return Err(self.unsupported(
+                    node,
+                    format!("`{}` is a child of `<parallel>` and cannot be a target", id),
+                ));
+            }
+            (Some((path, false)), _) => (path, None),
+            (None, Some((path, deep))) => {
+                let history = if *deep {
+                    quote! { <H*> }
+                } else {
+                    quote! { <H> }
+                };
+                (path, Some(history))
+            }
+            (None, None) => {
+                return Err(self.error(node, format!("unknown target `{}`", id)));
+            }
+        };
+
+        let relative = match path.strip_prefix(scope) {
+            Some(relative) if !relative.is_empty() => relative,
+            _ => {
+                return Err(self.error(
+                    node,
+                    format!(
+                        "target `{}` must be a descendant of the enclosing state",
+                        id
+                    ),
+                ));
+            }
+        };
+        match history {
+            Some(history) => Ok(quote! { #(#relative).* . #history }),
+            None => Ok(quote! { #(#relative).* }),
+        }
+    }
+
+    /// The executable content of `node` as a single action expression.
+    fn action(&mut self, node: Node<'a, 'input>) -> Result<Option<TokenStream>> {
+        let mut exprs = vec![];
+        for child in node.children().filter(Node::is_element) {
+            match child.tag_name().name() {
+                "script" => {
+                    let text = child.text().unwrap_or_default();
+                    let name = self.method(child, text, Method::Script)?;
+                    exprs.push(quote! { ctx.#name() });
+                }
+                "raise" => {
+                    let event = child
+                        .attribute("event")
+                        .ok_or_else(|| self.error(child, "`<raise>` needs an `event`"))?;
+                    let event = self.ident(child, event)?;
+                    exprs.push(quote! { queue.post(#event) });
+                }
+                name => {
+                    return Err(self.unsupported(
+                        child,
+                        format!("unsupported executable content `<{}>`", name),
+                    ));
+                }
+            }
+        }
+        Ok(match exprs.len() {
+            0 => None,
+            1 => exprs.pop(),
+            _ => Some(quote! { { #(#exprs;)* } }),
+        })
+    }
+
+    /// Declares the context method called by a condition or script, which
+    /// has to consist of just the method name.
+    fn method(&mut self, node: Node<'a, 'input>, text: &str, kind: Method) -> Result<syn::Ident> {
+        let text = text.trim();
+        let text = text.strip_suffix(';').unwrap_or(text);
+        let text = text.strip_suffix("()").unwrap_or(text);
+        let name = syn::parse_str::<syn::Ident>(&text.to_case(Case::Snake))
+            .map_err(|_| {
+                self.unsupported(
+                    node,
+                    format!(
+                        "`{}` does not name a context method, as conditions and scripts must each be a bare method name",
+                        text
+                    ),
+                )
+            })?;
+
+        match self.methods.iter().find(|(n, _)| *n == name) {
+            Some((_, k)) if *k != kind => Err(self.error(
+                node,
+                format!("`{}` is used both as a condition and as a script", name),
+            )),
+            Some(_) => Ok(name),
+            None => {
+                self.methods.push((name.clone(), kind));
+                Ok(name)
+            }
+        }
+    }
+
+    fn id(&self, node: Node<'a, 'input>) -> Result<&'a str> {
+        node.attribute("id").ok_or_else(|| {
+            self.error(
+                node,
+                format!("`<{}>` needs an `id`", node.tag_name().name()),
+            )
+        })
+    }
+
+    /// Turns an SCXML name like `wait-charge` or `usb.connected` into the
+    /// identifier of a state or event type.
+    fn ident(&self, node: Node<'a, 'input>, name: &str) -> Result<syn::Ident> {
+        let converted = name.replace(['.', '-'], "_").to_case(Case::UpperCamel);
+        syn::parse_str(&converted)
+            .map_err(|_| self.error(node, format!("`{}` is not a valid name", name)))
+    }
+
+    fn error(&self, node: Node<'a, 'input>, msg: impl std::fmt::Display) -> Error {
+        let pos = self.doc.text_pos_at(node.range().start);
+        Error::new_spanned(self.path, format!("{}:{}: {}", self.path.value(), pos, msg))
+    }
+
+    /// Like `error`, for SCXML outside the subset the import supports.
+    fn unsupported(&self, node: Node<'a, 'input>, msg: impl std::fmt::Display) -> Error {
+        self.error(
+            node,
+            format!("{}; see `umlstate_scxml` for the supported subset", msg),
+        )
+    }
+}
+
+fn is_state(node: &Node) -> bool {
+    matches!(node.tag_name().name(), "state" | "parallel" | "final")
+}
+
+/// Renders a machine as an SCXML document, with the whole machine in a root
+/// state named after it.  Guards and actions are kept as Rust source in
+/// `cond` attributes and `<script>` elements.  Junctions are folded into the
+/// transitions reaching them, while choices become transient states.
+/// Deferred events have no SCXML counterpart, so each `defer` is left as a
+/// comment.
+///
+/// Ids are the paths of the states joined with `.`, which no identifier
+/// contains, and histories append `-history` or `-deep-history` to the id of
+/// their state.
+pub fn generate(machine: &lower::TopMachine) -> String {
+    let mut w = Exporter {
+        machine,
+        transitions: HashMap::new(),
+    };
+    w.collect(&machine.state, &[]);
+
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+    writeln!(
+        out,
+        "<scxml xmlns=\"http://www.w3.org/2005/07/scxml\" version=\"1.0\" name=\"{0}\" initial=\"{0}\">",
+        machine.ident
+    )
+    .unwrap();
+    w.write_state(&mut out, &machine.state, &[], 1);
+    out.push_str("</scxml>\n");
+    out
+}
+
+struct Exporter<'a> {
+    machine: &'a lower::TopMachine,
+    /// Outgoing transitions by the id of their source state, with the state
+    /// owning them and its path.
+    transitions: HashMap<String, Vec<Owned<'a>>>,
+}
+
+type Owned<'a> = (Vec<&'a syn::Ident>, &'a lower::State, &'a lower::Transition);
+
+impl<'a> Exporter<'a> {
+    fn collect(&mut self, state: &'a lower::State, path: &[&'a syn::Ident]) {
+        for sub_state in state.states.iter().chain(state.regions.iter()) {
+            let mut sub_path = path.to_vec();
+            sub_path.push(&sub_state.ident);
+            for t in &sub_state.out_transitions {
+                let mut source = sub_path.clone();
+                source.extend(&t.source_path);
+                self.transitions
+                    .entry(self.state_id(&source))
+                    .or_default()
+                    .push((path.to_vec(), state, t));
+            }
+            self.collect(sub_state, &sub_path);
+        }
+    }
+
+    fn write_state(
+        &self,
+        out: &mut String,
+        state: &lower::State,
+        path: &[&syn::Ident],
+        depth: usize,
+    ) {
+        let indent = "  ".repeat(depth);
+        let id = self.state_id(path);
+
+        if state.kind == analyze::StateKind::Final {
+            writeln!(out, "{}<final id=\"{}\"/>", indent, id).unwrap();
+            return;
+        }
+
+        let element = if state.regions.is_empty() {
+            "state"
+        } else {
+            "parallel"
+        };
+        write!(out, "{}<{} id=\"{}\"", indent, element, id).unwrap();
+        if let Some(t) = state
+            .initial_transition
+            .as_ref()
+            .filter(|t| !needs_initial_element(state, t))
+        {
+            write!(out, " initial=\"{}\"", self.target_id(path, t)).unwrap();
+        }
+
+        let body = self.body(state, path, depth + 1);
+        if body.is_empty() {
+            out.push_str("/>\n");
+        } else {
+            writeln!(out, ">\n{}{}</{}>", body, indent, element).unwrap();
+        }
+    }
+
+    fn body(&self, state: &lower::State, path: &[&syn::Ident], depth: usize) -> String {
+        let indent = "  ".repeat(depth);
+        let mut out = String::new();
+
+        // Timers are delayed events, sent on entry and cancelled on exit.
+        let sends = state.timers.iter().map(|t| {
+            format!(
+                "<send id=\"timer.{0}\" event=\"timer.{0}\" delayexpr=\"{1}\"/>",
+                t.id,
+                escape(&pretty::tokens(&t.duration))
+            )
+        });
+        let cancels = state
+            .timers
+            .iter()
+            .map(|t| format!("<cancel sendid=\"timer.{}\"/>", t.id));
+        // The cancellation hook of a do-activity runs before the exit behavior.
+        let activity_cancel = state
+            .activity
+            .as_ref()
+            .and_then(|a| a.cancel.as_ref())
+            .map(|c| format!("<script>{}</script>", escape(&pretty::tokens(c))));
+        for (element, behavior, timers) in [
+            ("onentry", &state.entry, sends.collect::<Vec<_>>()),
+            (
+                "onexit",
+                &state.exit,
+                cancels.chain(activity_cancel).collect(),
+            ),
+        ] {
+            let mut content = timers.concat();
+            if let Some(behavior) = behavior {
+                write!(
+                    content,
+                    "<script>{}</script>",
+                    escape(&pretty::tokens(behavior))
+                )
+                .unwrap();
+            }
+            if !content.is_empty() {
+                writeln!(out, "{}<{}>{}</{}>", indent, element, content, element).unwrap();
+            }
+        }
+
+        // A do-activity is invoked while the state is active.
+        if let Some(activity) = &state.activity {
+            writeln!(
+                out,
+                "{}<invoke id=\"activity.{}\" srcexpr=\"{}\"/>",
+                indent,
+                activity.id,
+                escape(&pretty::tokens(&activity.start))
+            )
+            .unwrap();
+        }
+
+        if let Some(t) = state
+            .initial_transition
+            .as_ref()
+            .filter(|t| needs_initial_element(state, t))
+        {
+            writeln!(out, "{}<initial>", indent).unwrap();
+            self.write_transition(&mut out, path, state, t, None, depth + 1);
+            writeln!(out, "{}</initial>", indent).unwrap();
+        }
+        for (history, default) in [
+            (analyze::History::Shallow, &state.shallow_history),
+            (analyze::History::Deep, &state.deep_history),
+        ] {
+            if default.is_none() && !lower::is_history_target(self.machine, path, history) {
+                continue;
+            }
+            // A history needs a default, which is otherwise the initial
+            // state.
+            let kind = match history {
+                analyze::History::Shallow => "shallow",
+                analyze::History::Deep => "deep",
+            };
+            writeln!(
+                out,
+                "{}<history id=\"{}\" type=\"{}\">",
+                indent,
+                self.history_id(path, history),
+                kind
+            )
+            .unwrap();
+            if let Some(t) = default.as_ref().or(state.initial_transition.as_ref()) {
+                self.write_transition(&mut out, path, state, t, None, depth + 1);
+            }
+            writeln!(out, "{}</history>", indent).unwrap();
+        }
+
+        for deferred in &state.deferred_events {
+            writeln!(
+                out,
+                "{}<!-- defer {}: SCXML cannot defer events -->",
+                indent,
+                self.event_name(&deferred.event)
+            )
+            .unwrap();
+        }
+
+        for exit_point in &state.exit_points {
+            writeln!(
+                out,
+                "{}<!-- exit point {}: SCXML has no exit points, so the transitions through it lead straight to its target -->",
+                indent, exit_point.ident
+            )
+            .unwrap();
+        }
+
+        // Junctions are static, so they are folded into the transitions
+        // reaching them rather than drawn as states.
+        for pseudostate in state
+            .pseudostates
+            .iter()
+            .filter(|p| p.kind != analyze::PseudostateKind::Junction)
+        {
+            let mut pseudo_path = path.to_vec();
+            pseudo_path.push(&pseudostate.ident);
+            writeln!(
+                out,
+                "{}<state id=\"{}\">",
+                indent,
+                self.state_id(&pseudo_path)
+            )
+            .unwrap();
+            for t in pseudostate.branches() {
+                self.write_transition(&mut out, path, state, t, None, depth + 1);
+            }
+            writeln!(out, "{}</state>", indent).unwrap();
+        }
+
+        for sub_state in &state.states {
+            let mut sub_path = path.to_vec();
+            sub_path.push(&sub_state.ident);
+            self.write_state(&mut out, sub_state, &sub_path, depth);
+        }
+        for region in &state.regions {
+            let mut region_path = path.to_vec();
+            region_path.push(&region.ident);
+            write!(
+                out,
+                "{}<state id=\"{}\"",
+                indent,
+                self.state_id(&region_path)
+            )
+            .unwrap();
+            if let Some(t) = region
+                .initial_transition
+                .as_ref()
+                .filter(|t| !needs_initial_element(region, t))
+            {
+                write!(out, " initial=\"{}\"", self.target_id(&region_path, t)).unwrap();
+            }
+            writeln!(
+                out,
+                ">\n{}{}</state>",
+                self.body(region, &region_path, depth + 1),
+                indent
+            )
+            .unwrap();
+        }
+
+        for t in &state.internal_transitions {
+            self.write_transition(&mut out, path, state, t, None, depth);
+        }
+        if let Some(transitions) = self.transitions.get(&self.state_id(path)) {
+            // Completion of a composite state is signalled by an event.
+            let completion = (!state.states.is_empty() || !state.regions.is_empty())
+                .then(|| format!("done.state.{}", self.state_id(path)));
+            for (scope_path, scope, t) in transitions {
+                self.write_transition(&mut out, scope_path, scope, t, completion.as_deref(), depth);
+            }
+        }
+
+        out
+    }
+
+    /// Writes `t` with its target resolved in `scope`, at `scope_path`, once
+    /// for every path through the junctions it reaches.  An eventless
+    /// transition is triggered by `completion` if given.
+    fn write_transition(
+        &self,
+        out: &mut String,
+        scope_path: &[&syn::Ident],
+        scope: &lower::State,
+        t: &lower::Transition,
+        completion: Option<&str>,
+        depth: usize,
+    ) {
+        let indent = "  ".repeat(depth);
+
+        let event = match (&t.timer, &t.event) {
+            (Some(timer), _) => Some(format!("timer.{}", timer.id)),
+            (None, Some(event)) => Some(self.event_name(event)),
+            (None, None) => completion.map(str::to_string),
+        };
+
+        for steps in junction_paths(scope, t) {
+            let mut attrs = String::new();
+            if let Some(event) = &event {
+                write!(attrs, " event=\"{}\"", escape(event)).unwrap();
+            }
+            let guards: Vec<_> = steps
+                .iter()
+                .filter_map(|t| t.guard.as_ref())
+                .map(pretty::tokens)
+                .collect();
+            let cond = match guards.len() {
+                0 => None,
+                1 => Some(guards[0].clone()),
+                _ => Some(
+                    guards
+                        .iter()
+                        .map(|g| format!("({})", g))
+                        .collect::<Vec<_>>()
+                        .join(" && "),
+                ),
+            };
+            if let Some(cond) = cond {
+                write!(attrs, " cond=\"{}\"", escape(&cond)).unwrap();
+            }
+            let last = steps.last().unwrap();
+            if last.target.is_some() {
+                write!(attrs, " target=\"{}\"", self.target_id(scope_path, last)).unwrap();
+            } else {
+                attrs.push_str(" type=\"internal\"");
+            }
+
+            let scripts: String = steps
+                .iter()
+                .filter_map(|t| t.action.as_ref())
+                .map(|a| format!("<script>{}</script>", escape(&pretty::tokens(a))))
+                .collect();
+            if scripts.is_empty() {
+                writeln!(out, "{}<transition{}/>", indent, attrs).unwrap();
+            } else {
+                writeln!(
+                    out,
+                    "{}<transition{}>{}</transition>",
+                    indent, attrs, scripts
+                )
+                .unwrap();
+            }
+        }
+    }
+
+    /// The name of an event, as its path with `.` for `::`.
+    fn event_name(&self, event: &syn::Ident) -> String {
+        self.machine
+            .events
+            .iter()
+            .find(|(_, ident)| ident == event)
+            .map(|(path, _)| pretty::tokens(path).replace("::", "."))
+            .unwrap_or_else(|| event.to_string())
+    }
+
+    fn state_id(&self, path: &[&syn::Ident]) -> String {
+        std::iter::once(&self.machine.ident)
+            .chain(path.iter().copied())
+            .map(|i| i.to_string())
+            .collect::<Vec<_>>()
+            .join(".")
+    }
+
+    fn target_id(&self, scope: &[&syn::Ident], t: &lower::Transition) -> String {
+        let mut target = scope.to_vec();
+        target.extend(t.target.iter());
+        target.extend(&t.target_path);
+        match t.target_history {
+            Some(history) => self.history_id(&target, history),
+            None => self.state_id(&target),
+        }
+    }
+
+    fn history_id(&self, path: &[&syn::Ident], history: analyze::History) -> String {
+        match history {
+            analyze::History::Shallow => format!("{}-history", self.state_id(path)),
+            analyze::History::Deep => format!("{}-deep-history", self.state_id(path)),
+        }
+    }
+}
+
+/// Whether the initial transition `t` of `scope` is written as an
+/// `<initial>` element, as the `initial` attribute can only name a state:
+/// when it has an action or leads to a junction, whose paths are folded
+/// into the element.
+fn needs_initial_element(scope: &lower::State, t: &lower::Transition) -> bool {
+    t.action.is_some() || junction_paths(scope, t).iter().any(|steps| steps.len() > 1)
+}
+
+/// The paths from `t` through the junctions of `scope` it reaches, in
+/// evaluation order, each ending with the transition leaving the last
+/// junction.
+fn junction_paths<'a>(
+    scope: &'a lower::State,
+    t: &'a lower::Transition,
+) -> Vec<Vec<&'a lower::Transition>> {
+    let junction = t.target.as_ref().and_then(|target| {
+        scope
+            .pseudostates
+            .iter()
+            .find(|p| p.ident == *target && p.kind == analyze::PseudostateKind::Junction)
+    });
+    match junction {
+        Some(junction) => junction
+            .branches()
+            .flat_map(|branch| junction_paths(scope, branch))
+            .map(|steps| std::iter::once(t).chain(steps).collect())
+            .collect(),
+        None => vec![vec![t]],
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use quote::ToTokens;
+
+    #[test]
+    fn basic() {
+        let path: syn::LitStr = syn::parse_quote!("door.scxml");
+        let ast = translate(
+            r#"<scxml xmlns="http://www.w3.org/2005/07/scxml" version="1.0" initial="closed">
+                <state id="closed">
+                    <onentry><script>lock</script></onentry>
+                    <transition event="open" cond="allowed" target="opened"/>
+                </state>
+                <state id="opened">
+                    <transition event="push" target="closed"><raise event="done"/></transition>
+                </state>
+            </scxml>"#,
+            &path,
+        )
+        .unwrap();
+
+        let expected: parse::UmlState = syn::parse_quote! {
+            machine Door {
+                fn lock(&mut self);
+                fn allowed(&self) -> bool;
+
+                state Closed {
+                    entry / ctx.lock();
+                }
+                state Opened;
+                <*> => Closed;
+
+                Closed + Open => Opened if ctx.allowed();
+                Opened + Push => Closed / queue.post(Done);
+            }
+        };
+        assert_eq!(
+            ast.to_token_stream().to_string(),
+            expected.to_token_stream().to_string()
+        );
+
+        let err = translate("<scxml><state/></scxml>", &path).err().unwrap();
+        assert_eq!(err.to_string(), "door.scxml:1:8: `<state>` needs an `id`");
+
+        let err = translate(
+            r#"<scxml><state id="a"><transition cond="x &gt; 1" target="a"/></state></scxml>"#,
+            &path,
+        )
+        .err()
+        .unwrap();
+        assert_eq!(
+            err.to_string(),
+            "door.scxml:1:22: `x > 1` does not name a context method, as conditions and scripts \
+             must each be a bare method name; see `umlstate_scxml` for the supported subset"
+        );
+
+        let ast: parse::UmlState = syn::parse_quote! {
+            machine Foo {
+                state A {
+                    E(n) / ctx.count(n);
+                    state X;
+                    state Done final;
+                    <*> => X;
+                    X => Done;
+                }
+                state B {
+                    defer E;
+                }
+                state A_X;
+                state J junction;
+                <*> => A / ctx.start();
+                A => B;
+                B + F => J / ctx.leave();
+                J => A_X / ctx.count(1) if ctx.far();
+                J => A if else;
+            }
+        };
+        let model = analyze::analyze(ast).unwrap();
+        let lowered = lower::lower(model);
+        let scxml = generate(&lowered.machines[0]);
+
+        assert!(scxml.contains("name=\"Foo\" initial=\"Foo\">\n  <state id=\"Foo\">\n"));
+        assert!(scxml.contains(
+            "    <initial>\n      <transition target=\"Foo.A\"><script>ctx.start()</script></transition>\n"
+        ));
+        assert!(scxml.contains(
+            "      <transition event=\"E\" type=\"internal\"><script>ctx.count(n)</script></transition>\n"
+        ));
+        assert!(scxml.contains("        <transition target=\"Foo.A.Done\"/>\n"));
+        assert!(scxml.contains("      <final id=\"Foo.A.Done\"/>\n"));
+        assert!(scxml.contains("    <state id=\"Foo.A_X\"/>\n"));
+        assert!(scxml.contains("      <transition event=\"done.state.Foo.A\" target=\"Foo.B\"/>\n"));
+        assert!(scxml.contains("      <!-- defer E: SCXML cannot defer events -->\n"));
+
+        // The junction is folded into the transition reaching it.
+        assert!(!scxml.contains("Foo.J"));
+        assert!(scxml.contains(
+            "      <transition event=\"F\" cond=\"ctx.far()\" target=\"Foo.A_X\"><script>ctx.leave()</script><script>ctx.count(1)</script></transition>\n"
+        ));
+        assert!(scxml.contains(
+            "      <transition event=\"F\" target=\"Foo.A\"><script>ctx.leave()</script></transition>\n"
+        ));
+    }
+}
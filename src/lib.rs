@@ -1,11 +1,395 @@
-pub use umlstate_macros::umlstate;
+#![no_std]
+
+#[cfg(feature = "std")]
+extern crate std;
+
+pub use umlstate_macros::{umlstate, umlstate_scxml};
+
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+#[doc(hidden)]
+pub use heapless;
+
+#[cfg(feature = "serde")]
+#[doc(hidden)]
+pub use serde;
+
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub use tracing;
 
 pub trait EventProcessor<E> {
     fn process(&mut self, event: E) -> ProcessResult;
 }
 
+/// Like [`EventProcessor`], for machines declared with `async machine`.
+/// The machine stays borrowed until the event and the events it posted are
+/// processed, so the steps of a machine never interleave.
+pub trait AsyncEventProcessor<E> {
+    fn process(&mut self, event: E) -> impl core::future::Future<Output = ProcessResult>;
+}
+
+/// Like [`AsyncEventProcessor`], for machines declared with
+/// `async machine Name: Send`, whose futures can be spawned on executors
+/// moving tasks between threads.  The futures of the context methods of
+/// such a machine are `Send` too, and so must be those of the machines it
+/// embeds.
+pub trait SendAsyncEventProcessor<E>: Send {
+    fn process(&mut self, event: E) -> impl core::future::Future<Output = ProcessResult> + Send;
+}
+
+/// A machine embedded by the submachine states of other machines, entered
+/// and exited along with them and acting on their context.  Implemented by
+/// the generated code for machines without timed transitions or
+/// do-activities.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be embedded by a submachine state",
+    note = "machines with timed transitions or do-activities, or with another context, cannot be embedded"
+)]
+pub trait Submachine<C> {
+    /// Fails with [`Error::QueueFull`] if an event posted on entry was
+    /// dropped, the queue of the machine being full.
+    fn enter_in(&mut self, ctx: &mut C, observer: &mut impl Observer) -> Result<(), Error>;
+
+    fn exit_in(&mut self, ctx: &mut C, observer: &mut impl Observer);
+}
+
+/// Like [`Submachine`], for machines declared with `async machine`.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` cannot be embedded by a submachine state of an `async machine`",
+    note = "machines with timed transitions or do-activities, or with another context, cannot be embedded"
+)]
+pub trait AsyncSubmachine<C> {
+    fn enter_in(
+        &mut self,
+        ctx: &mut C,
+        observer: &mut impl Observer,
+    ) -> impl core::future::Future<Output = Result<(), Error>>;
+
+    fn exit_in(
+        &mut self,
+        ctx: &mut C,
+        observer: &mut impl Observer,
+    ) -> impl core::future::Future<Output = ()>;
+}
+
+/// An event `E` forwarded to an embedded machine by the submachine state
+/// embedding it, `forward E;`.  The event is left in `event` unless the
+/// machine handled or deferred it.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "`{E}` cannot be forwarded to `{Self}`",
+    label = "forwarded here",
+    note = "only events handled by the embedded machine can be forwarded, and machines with timed transitions or do-activities cannot be embedded"
+)]
+pub trait Forward<E, C>: Submachine<C> {
+    fn forward(
+        &mut self,
+        ctx: &mut C,
+        observer: &mut impl Observer,
+        event: &mut Option<E>,
+    ) -> ProcessResult;
+}
+
+/// Like [`Forward`], for machines declared with `async machine`.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "`{E}` cannot be forwarded to `{Self}`",
+    label = "forwarded here",
+    note = "only events handled by the embedded machine can be forwarded, and machines with timed transitions or do-activities cannot be embedded"
+)]
+pub trait AsyncForward<E, C>: AsyncSubmachine<C> {
+    fn forward(
+        &mut self,
+        ctx: &mut C,
+        observer: &mut impl Observer,
+        event: &mut Option<E>,
+    ) -> impl core::future::Future<Output = ProcessResult>;
+}
+
+/// An event handled in several orthogonal regions, each of them taking
+/// its own copy.
+#[doc(hidden)]
+#[diagnostic::on_unimplemented(
+    message = "`{Self}` is handled in several regions, so it must be `Clone`",
+    label = "handled in several regions",
+    note = "each region takes its own copy of the event"
+)]
+pub trait SharedEvent: Sized {
+    fn share(&self) -> Self;
+}
+
+impl<E: Clone> SharedEvent for E {
+    fn share(&self) -> Self {
+        self.clone()
+    }
+}
+
 #[derive(Debug, PartialEq)]
 pub enum ProcessResult {
     Handled,
     Unhandled,
+    Deferred,
+    /// The machine could not process any event.
+    Error(Error),
+}
+
+/// Why a machine refused to enter, exit or process an event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    /// The machine was not entered yet, or was exited.
+    NotStarted,
+    /// The machine was entered already.
+    AlreadyActive,
+    /// The machine is not active, so it cannot be exited.
+    NotActive,
+    /// The machine reached one of its top level final states.
+    Terminated,
+    /// The snapshot to restore is not a configuration the machine can be
+    /// in.
+    InvalidSnapshot,
+    /// An event was posted or deferred while the queue holding it was
+    /// full, so it was dropped.
+    QueueFull,
+}
+
+impl core::fmt::Display for Error {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str(match self {
+            Error::NotStarted => "machine is not started",
+            Error::AlreadyActive => "machine is already active",
+            Error::NotActive => "machine is not active",
+            Error::Terminated => "machine is terminated",
+            Error::InvalidSnapshot => "snapshot is not a valid configuration",
+            Error::QueueFull => "event queue is full",
+        })
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for Error {}
+
+/// Identifies the timer of a timed transition, `S + after(duration) => T`.
+/// Once the duration passed, the host processes the id with the machine,
+/// which takes the transition if its source is still active.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct TimerId {
+    /// The timed transition, numbered in declaration order.
+    pub index: usize,
+    /// Counts the times the source of the transition was entered, so that
+    /// a timer armed before the source was left and entered again, or
+    /// before the machine was restored, is ignored.
+    pub generation: u32,
+}
+
+/// Runs the timers of a machine with timed transitions, using whatever
+/// clock the host has.
+pub trait Timer {
+    /// Starts the timer `id`, the source of its transition being entered.
+    /// A timer started again restarts with the new duration.
+    fn arm(&mut self, id: TimerId, duration: core::time::Duration);
+
+    /// Stops the timer `id`, the source of its transition being exited.
+    fn cancel(&mut self, id: TimerId);
+}
+
+/// The timer of machines without timed transitions.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoTimer;
+
+impl Timer for NoTimer {
+    fn arm(&mut self, _: TimerId, _: core::time::Duration) {}
+
+    fn cancel(&mut self, _: TimerId) {}
+}
+
+/// Identifies the do-activity of a state in an `async machine`,
+/// `do / activity;`.  Once the activity finished, the host processes the id
+/// with the machine, which completes the state if it is still active.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ActivityId {
+    /// The state running the activity, numbered in declaration order.
+    pub index: usize,
+    /// Counts the times the activity was started, so that a run cancelled
+    /// too late to be stopped is ignored when it finishes.
+    pub generation: u32,
+}
+
+/// Runs the do-activities of an `async machine` alongside it, using
+/// whatever executor the host has.
+pub trait Spawner {
+    /// Starts running the activity `id`, its state being entered.
+    fn spawn(&mut self, id: ActivityId, activity: impl core::future::Future<Output = ()> + 'static);
+
+    /// Drops the activity `id` if it is still running, its state being
+    /// exited.  A cancelled activity must not be reported as finished.
+    fn cancel(&mut self, id: ActivityId);
+}
+
+/// The spawner of machines without do-activities.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoSpawner;
+
+impl Spawner for NoSpawner {
+    fn spawn(&mut self, _: ActivityId, _: impl core::future::Future<Output = ()> + 'static) {}
+
+    fn cancel(&mut self, _: ActivityId) {}
+}
+
+/// Number of events a machine can hold in its queue of posted events, and
+/// in its queue of deferred events, unless it sets its own with
+/// `queue_capacity = N;` or is built with the `std` feature.
+pub const QUEUE_CAPACITY: usize = 16;
+
+/// Events waiting to be dispatched by a machine.  Holds at most `N` events,
+/// or grows as needed with the `std` feature.
+#[doc(hidden)]
+pub struct Queue<T, const N: usize = QUEUE_CAPACITY> {
+    #[cfg(feature = "std")]
+    items: std::collections::VecDeque<T>,
+    #[cfg(not(feature = "std"))]
+    items: heapless::Deque<T, N>,
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            #[cfg(feature = "std")]
+            items: std::collections::VecDeque::new(),
+            #[cfg(not(feature = "std"))]
+            items: heapless::Deque::new(),
+        }
+    }
+
+    /// Gives `item` back if the queue is full.
+    #[cfg(feature = "std")]
+    pub fn push_back(&mut self, item: T) -> Result<(), T> {
+        self.items.push_back(item);
+        Ok(())
+    }
+
+    /// Gives `item` back if the queue is full.
+    #[cfg(not(feature = "std"))]
+    pub fn push_back(&mut self, item: T) -> Result<(), T> {
+        self.items.push_back(item)
+    }
+
+    pub fn pop_front(&mut self) -> Option<T> {
+        self.items.pop_front()
+    }
+
+    /// Removes the events in order, leaving the queue empty even if the
+    /// iterator is dropped early.
+    pub fn drain(&mut self) -> impl Iterator<Item = T> {
+        core::mem::take(self).items.into_iter()
+    }
+
+    pub fn clear(&mut self) {
+        self.items.clear();
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Notified of everything a machine does, with states named by their path
+/// like `Running.Work` and events by their type.  Every method does nothing
+/// by default.
+#[allow(unused_variables)]
+pub trait Observer {
+    /// An event is about to be dispatched, including events posted by
+    /// actions.
+    fn on_event_received(&mut self, event: &'static str) {}
+
+    /// A transition is taken, before its source is exited.  Completion
+    /// transitions and branches of choices and junctions have no event.
+    fn on_transition(
+        &mut self,
+        source: &'static str,
+        target: &'static str,
+        event: Option<&'static str>,
+    ) {
+    }
+
+    fn on_entry(&mut self, state: &'static str) {}
+
+    fn on_exit(&mut self, state: &'static str) {}
+
+    /// No state handled or deferred the event.
+    fn on_unhandled(&mut self, event: &'static str) {}
+
+    /// The guard of a transition triggered by `event` was false.
+    fn on_guard_rejected(&mut self, source: &'static str, event: &'static str) {}
+}
+
+/// The observer of machines nobody observes.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NoObserver;
+
+impl Observer for NoObserver {}
+
+/// Reports everything a machine does to `tracing` before handing it to the
+/// observer of the machine.  Used by the generated code when the `tracing`
+/// feature is enabled.
+#[cfg(feature = "tracing")]
+#[doc(hidden)]
+pub struct Traced<'a, O> {
+    pub machine: &'static str,
+    pub observer: &'a mut O,
+}
+
+#[cfg(feature = "tracing")]
+impl<O: Observer> Observer for Traced<'_, O> {
+    fn on_event_received(&mut self, event: &'static str) {
+        self.observer.on_event_received(event);
+    }
+
+    fn on_transition(
+        &mut self,
+        source: &'static str,
+        target: &'static str,
+        event: Option<&'static str>,
+    ) {
+        tracing::debug!(
+            target: "umlstate",
+            machine = self.machine,
+            source,
+            target,
+            event,
+            "transition"
+        );
+        self.observer.on_transition(source, target, event);
+    }
+
+    fn on_entry(&mut self, state: &'static str) {
+        tracing::trace!(target: "umlstate", machine = self.machine, state, "entry");
+        self.observer.on_entry(state);
+    }
+
+    fn on_exit(&mut self, state: &'static str) {
+        tracing::trace!(target: "umlstate", machine = self.machine, state, "exit");
+        self.observer.on_exit(state);
+    }
+
+    fn on_unhandled(&mut self, event: &'static str) {
+        tracing::debug!(target: "umlstate", machine = self.machine, event, "unhandled event");
+        self.observer.on_unhandled(event);
+    }
+
+    fn on_guard_rejected(&mut self, source: &'static str, event: &'static str) {
+        tracing::trace!(
+            target: "umlstate",
+            machine = self.machine,
+            source,
+            event,
+            "guard rejected"
+        );
+        self.observer.on_guard_rejected(source, event);
+    }
 }
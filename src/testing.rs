@@ -0,0 +1,132 @@
+//! Helpers for testing machines.
+
+use core::cell::RefCell;
+use core::time::Duration;
+
+use crate::{AsyncEventProcessor, EventProcessor, Timer, TimerId};
+
+/// Number of timers a [`VirtualClock`] can run at once.
+pub const TIMER_CAPACITY: usize = 16;
+
+/// A clock that only moves when told to, so that tests of timed
+/// transitions neither sleep nor depend on the scheduler.  Machines borrow
+/// it as their timer:
+///
+/// ```
+/// use core::time::Duration;
+/// use umlstate::testing::VirtualClock;
+/// use umlstate::*;
+///
+/// umlstate! {
+///     machine Charger {
+///         state WaitCharge;
+///         state Fault;
+///
+///         <*> => WaitCharge;
+///         WaitCharge + after(Duration::from_secs(30)) => Fault;
+///     }
+/// }
+///
+/// fn main() {
+///     let clock = VirtualClock::new();
+///     let mut charger = Charger::new(&clock);
+///     charger.enter();
+///     clock.advance(&mut charger, Duration::from_secs(30));
+///     assert!(charger.state() == Some(ChargerState::Fault));
+/// }
+/// ```
+#[derive(Default)]
+pub struct VirtualClock {
+    inner: RefCell<Clock>,
+}
+
+#[derive(Default)]
+struct Clock {
+    now: Duration,
+    // Breaks ties between timers due at the same time, earliest armed first.
+    armed: u64,
+    timers: heapless::Vec<Armed, TIMER_CAPACITY>,
+}
+
+struct Armed {
+    id: TimerId,
+    deadline: Duration,
+    order: u64,
+}
+
+impl VirtualClock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time passed since the clock was created.
+    pub fn now(&self) -> Duration {
+        self.inner.borrow().now
+    }
+
+    /// When the timer `id` falls due, if it is running.
+    pub fn deadline(&self, id: TimerId) -> Option<Duration> {
+        let inner = self.inner.borrow();
+        inner.timers.iter().find(|t| t.id == id).map(|t| t.deadline)
+    }
+
+    /// Moves the clock forward by `by`, processing with `machine` the
+    /// timers falling due meanwhile in deadline order.  Timers armed by the
+    /// transitions they take fire too if they fall due in time.
+    pub fn advance<M: EventProcessor<TimerId>>(&self, machine: &mut M, by: Duration) {
+        let until = self.now() + by;
+        while let Some(id) = self.next_due(until) {
+            machine.process(id);
+        }
+        self.inner.borrow_mut().now = until;
+    }
+
+    /// Like [`advance`](Self::advance), for machines declared with
+    /// `async machine`.
+    pub async fn advance_async<M: AsyncEventProcessor<TimerId>>(
+        &self,
+        machine: &mut M,
+        by: Duration,
+    ) {
+        let until = self.now() + by;
+        while let Some(id) = self.next_due(until) {
+            machine.process(id).await;
+        }
+        self.inner.borrow_mut().now = until;
+    }
+
+    /// Stops the earliest timer due by `until` and moves the clock to its
+    /// deadline.
+    fn next_due(&self, until: Duration) -> Option<TimerId> {
+        let mut inner = self.inner.borrow_mut();
+        let (index, _) = inner
+            .timers
+            .iter()
+            .enumerate()
+            .filter(|(_, t)| t.deadline <= until)
+            .min_by_key(|(_, t)| (t.deadline, t.order))?;
+        let timer = inner.timers.swap_remove(index);
+        inner.now = timer.deadline;
+        Some(timer.id)
+    }
+}
+
+impl Timer for &VirtualClock {
+    fn arm(&mut self, id: TimerId, duration: Duration) {
+        self.cancel(id);
+        let mut inner = self.inner.borrow_mut();
+        let timer = Armed {
+            id,
+            deadline: inner.now + duration,
+            order: inner.armed,
+        };
+        inner.armed += 1;
+        if inner.timers.push(timer).is_err() {
+            panic!("too many timers running");
+        }
+    }
+
+    fn cancel(&mut self, id: TimerId) {
+        self.inner.borrow_mut().timers.retain(|t| t.id != id);
+    }
+}
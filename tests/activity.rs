@@ -0,0 +1,186 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use umlstate::*;
+
+mod common;
+use common::{block_on, Log};
+
+#[derive(Clone)]
+struct Start;
+#[derive(Clone)]
+struct Stop;
+
+umlstate! {
+    machine Probe {
+        fn log(&mut self, line: &'static str);
+
+        state Idle;
+        state Sampling {
+            entry / ctx.log("entry");
+            do / ctx.log("start sampling") cancel ctx.log("stop sampling");
+            exit / ctx.log("exit");
+        }
+
+        <*> => Idle;
+        Idle + Start => Sampling;
+        Sampling + Stop => Idle;
+    }
+}
+
+common::log_context!(ProbeContext::log);
+
+#[test]
+fn activity() {
+    let mut m = Probe::new(Log::default());
+    m.enter();
+    m.process(Start);
+    assert_eq!(m.context().0, ["entry", "start sampling"]);
+
+    m.process(Stop);
+    assert_eq!(
+        m.context().0,
+        ["entry", "start sampling", "stop sampling", "exit"]
+    );
+}
+
+umlstate! {
+    async machine Meter {
+        state Idle;
+        state Measuring {
+            do / Countdown(1);
+        }
+        state Done;
+
+        <*> => Idle;
+        Idle + Start => Measuring;
+        Measuring + Stop => Idle;
+        Measuring => Done;
+    }
+}
+
+/// Pending the given number of times before completing.
+struct Countdown(u32);
+
+impl Future for Countdown {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<()> {
+        if self.0 == 0 {
+            return Poll::Ready(());
+        }
+        self.0 -= 1;
+        Poll::Pending
+    }
+}
+
+type Activity = Pin<Box<dyn Future<Output = ()>>>;
+
+/// Runs activities when told to, reporting those that finished.
+#[derive(Default)]
+struct Tasks {
+    running: Vec<(ActivityId, Activity)>,
+    cancelled: Vec<ActivityId>,
+}
+
+impl Spawner for Tasks {
+    fn spawn(&mut self, id: ActivityId, activity: impl Future<Output = ()> + 'static) {
+        self.running.push((id, Box::pin(activity)));
+    }
+
+    fn cancel(&mut self, id: ActivityId) {
+        self.running.retain(|(i, _)| *i != id);
+        self.cancelled.push(id);
+    }
+}
+
+impl Tasks {
+    fn poll(&mut self) -> Vec<ActivityId> {
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut done = vec![];
+        self.running.retain_mut(|(id, activity)| {
+            let ready = activity.as_mut().poll(&mut cx).is_ready();
+            if ready {
+                done.push(*id);
+            }
+            !ready
+        });
+        done
+    }
+}
+
+fn run(generation: u32) -> ActivityId {
+    ActivityId {
+        index: 0,
+        generation,
+    }
+}
+
+#[test]
+fn activity_completion() {
+    block_on(async {
+        let mut m = Meter::new(Tasks::default());
+        m.enter().await;
+        m.process(Start).await;
+        assert_eq!(m.spawner().running.len(), 1);
+
+        assert!(m.spawner_mut().poll().is_empty());
+        assert!(m.state() == Some(MeterState::Measuring));
+
+        assert_eq!(m.spawner_mut().poll(), [run(1)]);
+        assert_eq!(m.process(run(1)).await, ProcessResult::Handled);
+        assert!(m.state() == Some(MeterState::Done));
+    });
+}
+
+#[test]
+fn activity_cancel() {
+    block_on(async {
+        let mut m = Meter::new(Tasks::default());
+        m.enter().await;
+        m.process(Start).await;
+        m.process(Stop).await;
+        assert!(m.spawner().running.is_empty());
+        assert_eq!(m.spawner().cancelled, [run(1)]);
+
+        // An activity finishing after its state was left is ignored, and
+        // so is one started before its state was last entered.
+        assert_eq!(m.process(run(1)).await, ProcessResult::Unhandled);
+        assert!(m.state() == Some(MeterState::Idle));
+
+        m.process(Start).await;
+        assert_eq!(m.process(run(1)).await, ProcessResult::Unhandled);
+        assert!(m.state() == Some(MeterState::Measuring));
+    });
+}
+
+#[test]
+fn restore_restarts_activity() {
+    let mut m = Probe::new(Log::default());
+    m.enter();
+    m.process(Start);
+
+    let m = Probe::restore(Log::default(), m.snapshot()).unwrap();
+    assert_eq!(m.context().0, ["start sampling"]);
+
+    block_on(async {
+        let mut m = Meter::new(Tasks::default());
+        m.enter().await;
+        m.process(Start).await;
+
+        let mut m = Meter::restore(Tasks::default(), m.snapshot()).unwrap();
+        assert_eq!(m.spawner().running.len(), 1);
+        assert_eq!(m.process(run(1)).await, ProcessResult::Unhandled);
+
+        m.spawner_mut().poll();
+        assert_eq!(m.spawner_mut().poll(), [run(2)]);
+        assert_eq!(m.process(run(2)).await, ProcessResult::Handled);
+        assert!(m.state() == Some(MeterState::Done));
+    });
+}
+
+#[test]
+fn activity_label() {
+    assert!(Probe::<Log>::PLANTUML.contains("Sampling : do / ctx.log(\"start sampling\")"));
+    assert!(Meter::<Tasks>::SCXML.contains("<invoke id=\"activity.0\" srcexpr=\"Countdown(1)\"/>"));
+}
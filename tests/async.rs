@@ -0,0 +1,192 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use umlstate::*;
+
+mod common;
+use common::block_on;
+
+#[derive(Clone)]
+struct Connect;
+#[derive(Clone)]
+struct Ack;
+#[derive(Clone)]
+struct Close;
+
+umlstate! {
+    async machine Link {
+        async fn send(&mut self, frame: &'static str);
+        async fn acked(&self) -> bool;
+
+        state Idle;
+
+        state Connecting {
+            entry / ctx.send("SYN").await;
+        }
+
+        state Connected {
+            exit / ctx.send("FIN").await;
+        }
+
+        <*> => Idle;
+        Idle + Connect => Connecting;
+        Connecting + Ack => Connected if ctx.acked().await;
+        Connected + Close => Idle / {
+            ctx.send("CLOSE").await;
+            queue.post(Connect);
+        };
+    }
+}
+
+umlstate! {
+    async machine Session {
+        state Offline;
+        state Online: Link {
+            forward Connect;
+            forward Ack;
+        }
+
+        <*> => Offline;
+        Offline + Ack => Online;
+        Online + Close => Offline;
+    }
+}
+
+umlstate! {
+    async machine Relay: Send {
+        async fn send(&mut self, frame: &'static str);
+        async fn acked(&self) -> bool;
+
+        state Idle;
+        state Connected {
+            entry / ctx.send("SYN").await;
+        }
+
+        <*> => Idle;
+        Idle + Connect => Connected if ctx.acked().await;
+    }
+}
+
+umlstate! {
+    async machine Gateway: Send {
+        state Offline;
+        state Online: Relay {
+            forward Connect;
+        }
+
+        <*> => Offline;
+        Offline + Ack => Online;
+    }
+}
+
+/// Pending once before completing, like any I/O.
+struct YieldNow(bool);
+
+impl Future for YieldNow {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        if self.0 {
+            return Poll::Ready(());
+        }
+        self.0 = true;
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+#[derive(Default)]
+struct Wire {
+    sent: Vec<&'static str>,
+    acks: bool,
+}
+
+impl LinkContext for Wire {
+    async fn send(&mut self, frame: &'static str) {
+        YieldNow(false).await;
+        self.sent.push(frame);
+    }
+
+    async fn acked(&self) -> bool {
+        YieldNow(false).await;
+        self.acks
+    }
+}
+
+#[test]
+fn async_machine() {
+    block_on(async {
+        let mut link = Link::new(Wire::default());
+        link.enter().await;
+        assert_eq!(link.process(Connect).await, ProcessResult::Handled);
+        assert_eq!(link.context().sent, ["SYN"]);
+
+        assert_eq!(link.process(Ack).await, ProcessResult::Unhandled);
+        link.context_mut().acks = true;
+        assert_eq!(link.try_process(Ack).await, Ok(ProcessResult::Handled));
+        assert!(link.state() == Some(LinkState::Connected));
+
+        // The posted `Connect` runs once `Close` is done.
+        link.process(Close).await;
+        assert_eq!(link.context().sent, ["SYN", "FIN", "CLOSE", "SYN"]);
+        assert!(link.state() == Some(LinkState::Connecting));
+
+        link.exit().await;
+        assert_eq!(link.try_enter().await, Ok(()));
+    });
+}
+
+impl RelayContext for Wire {
+    async fn send(&mut self, frame: &'static str) {
+        YieldNow(false).await;
+        self.sent.push(frame);
+    }
+
+    async fn acked(&self) -> bool {
+        YieldNow(false).await;
+        self.acks
+    }
+}
+
+/// Runs `future` as an executor moving tasks between threads would.
+fn spawn<F: Future + Send>(future: F) -> F::Output {
+    block_on(future)
+}
+
+async fn connect<M: SendAsyncEventProcessor<Connect>>(machine: &mut M) -> ProcessResult {
+    machine.process(Connect).await
+}
+
+#[test]
+fn send_machine() {
+    let mut relay = Relay::new(Wire {
+        acks: true,
+        ..Wire::default()
+    });
+    spawn(relay.enter());
+    assert_eq!(spawn(connect(&mut relay)), ProcessResult::Handled);
+    assert_eq!(relay.context().sent, ["SYN"]);
+
+    let mut gateway = Gateway::new(Wire {
+        acks: true,
+        ..Wire::default()
+    });
+    spawn(gateway.enter());
+    assert_eq!(spawn(gateway.process(Ack)), ProcessResult::Handled);
+    assert_eq!(spawn(connect(&mut gateway)), ProcessResult::Handled);
+    assert_eq!(gateway.context().sent, ["SYN"]);
+}
+
+#[test]
+fn async_submachine() {
+    block_on(async {
+        let mut session = Session::new(Wire::default());
+        session.enter().await;
+        session.process(Ack).await;
+        assert_eq!(session.process(Connect).await, ProcessResult::Handled);
+        assert_eq!(session.context().sent, ["SYN"]);
+
+        session.process(Close).await;
+        assert!(session.state() == Some(SessionState::Offline));
+    });
+}
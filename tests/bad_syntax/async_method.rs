@@ -0,0 +1,17 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        async fn send(&mut self);
+
+        state A;
+        state B;
+
+        <*> => A;
+        A + E => B / ctx.send().await;
+    }
+}
+
+fn main() {}
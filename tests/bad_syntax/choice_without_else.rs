@@ -0,0 +1,19 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        fn ready(&self) -> bool;
+
+        state A;
+        state B;
+        state C choice;
+
+        <*> => A;
+        A + E => C;
+        C => B if ctx.ready();
+    }
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        state A;
+        state B;
+        state C;
+
+        <*> => C;
+        C + E => A;
+        A => B;
+        B => A;
+    }
+}
+
+fn main() {}
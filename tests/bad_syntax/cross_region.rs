@@ -0,0 +1,21 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        region A {
+            state X;
+            <*> => X;
+        }
+
+        region B {
+            state Y;
+            <*> => Y;
+        }
+
+        A.X + E => B.Y;
+    }
+}
+
+fn main() {}
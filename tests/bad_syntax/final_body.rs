@@ -0,0 +1,17 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        state A;
+        state Done final {
+            entry / println!("done");
+        }
+
+        <*> => A;
+        A + E => Done;
+    }
+}
+
+fn main() {}
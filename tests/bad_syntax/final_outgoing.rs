@@ -0,0 +1,16 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        state A;
+        state B final;
+
+        <*> => A;
+        A + E => B;
+        B + E => A;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,17 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        state A {
+            forward E;
+        }
+        state B;
+
+        <*> => A;
+        A + E => B;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,27 @@
+use umlstate::umlstate;
+
+struct Go;
+struct Other;
+
+umlstate! {
+    machine Inner {
+        state A;
+        state B;
+
+        <*> => A;
+        A + Go => B;
+    }
+}
+
+umlstate! {
+    machine Outer {
+        state S: Inner {
+            forward Go;
+            forward Other;
+        }
+
+        <*> => S;
+    }
+}
+
+fn main() {}
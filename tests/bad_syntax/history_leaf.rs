@@ -0,0 +1,15 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        state A;
+        state B;
+
+        <*> => A;
+        A + E => B.<H>;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,18 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        fn poll(&mut self);
+
+        state A;
+        state B;
+
+        do / ctx.poll();
+        <*> => A;
+        A + E => B;
+    }
+}
+
+fn main() {}
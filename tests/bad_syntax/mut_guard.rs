@@ -0,0 +1,17 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        fn check(&mut self) -> bool;
+
+        state A;
+        state B;
+
+        <*> => A;
+        A + E => B if ctx.check();
+    }
+}
+
+fn main() {}
@@ -0,0 +1,19 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        state A {
+            state B;
+
+            <*> => B;
+        }
+        state AB;
+
+        <*> => A;
+        A + E => AB;
+    }
+}
+
+fn main() {}
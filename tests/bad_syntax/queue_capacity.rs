@@ -0,0 +1,18 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        queue_capacity = 4;
+        queue_capacity = 8;
+
+        state A;
+        state B;
+
+        <*> => A;
+        A + E => B;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,15 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo: Send {
+        state A;
+        state B;
+
+        <*> => A;
+        A + E => B;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,25 @@
+use umlstate::umlstate;
+
+struct Go;
+
+umlstate! {
+    machine Foo {
+        region A {
+            state S;
+            state T;
+
+            <*> => S;
+            S + Go => T;
+        }
+
+        region B {
+            state S;
+            state T;
+
+            <*> => S;
+            S + Go => T;
+        }
+    }
+}
+
+fn main() {}
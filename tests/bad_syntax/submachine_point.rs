@@ -0,0 +1,21 @@
+use umlstate::umlstate;
+
+umlstate! {
+    machine Inner {
+        state A;
+
+        <*> => A;
+    }
+
+    machine Outer {
+        state S: Inner {
+            exit_point Done;
+        }
+        state T;
+
+        <*> => S;
+        S.Done => T;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,23 @@
+use umlstate::umlstate;
+
+struct Go;
+
+umlstate! {
+    machine Inner {
+        state A;
+        state B;
+
+        <*> => A;
+        A + after(core::time::Duration::from_secs(1)) => B;
+    }
+
+    machine Outer {
+        state S: Inner {
+            forward Go;
+        }
+
+        <*> => S;
+    }
+}
+
+fn main() {}
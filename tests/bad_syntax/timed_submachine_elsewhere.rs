@@ -0,0 +1,25 @@
+use umlstate::umlstate;
+
+struct Go;
+
+umlstate! {
+    machine Inner {
+        state A;
+        state B;
+
+        <*> => A;
+        A + after(core::time::Duration::from_secs(1)) => B;
+    }
+}
+
+umlstate! {
+    machine Outer {
+        state S: Inner {
+            forward Go;
+        }
+
+        <*> => S;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,20 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        fn ready(&self) -> bool;
+
+        state A;
+        state B;
+        state J junction;
+
+        <*> => A;
+        A + E => J;
+        J => B if ctx.ready();
+        J => A;
+    }
+}
+
+fn main() {}
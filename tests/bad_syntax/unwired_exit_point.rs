@@ -0,0 +1,20 @@
+use umlstate::umlstate;
+
+struct E;
+
+umlstate! {
+    machine Foo {
+        state A {
+            exit_point Failed;
+            state B;
+
+            <*> => B;
+            B + E => Failed;
+        }
+        state C;
+
+        <*> => A;
+    }
+}
+
+fn main() {}
@@ -0,0 +1,226 @@
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use umlstate::*;
+
+#[derive(Clone)]
+struct Measure(u32);
+#[derive(Clone)]
+struct Charge(u32);
+#[derive(Clone)]
+struct Reset;
+
+umlstate! {
+    machine Dispatch {
+        fn level(&self) -> u32;
+        fn set_level(&self, level: u32);
+        fn log(&self, msg: &'static str);
+
+        state Idle {
+            exit / ctx.log("exit Idle");
+        }
+        state Low;
+        state High;
+        state Full;
+
+        state Powered {
+            state Ready;
+            state Empty;
+            state Pick choice;
+
+            <*> => Pick;
+            Pick => Ready if ctx.level() > 0;
+            Pick => Empty if else;
+        }
+
+        state Level choice;
+        state Limit junction;
+
+        <*> => Idle;
+        Idle + Measure(n) => Level / ctx.set_level(n);
+        Level => Full / ctx.log("full") if ctx.level() == 100;
+        Level => High / ctx.log("high") if ctx.level() > 50;
+        Level => Low / ctx.log("low") if else;
+
+        Idle + Charge(n) => Limit;
+        Limit => Full if n >= 100;
+        Limit => Powered if n > 0;
+
+        Low + Reset => Idle;
+        High + Reset => Idle;
+        Full + Reset => Idle;
+        Powered + Reset => Idle;
+    }
+}
+
+#[derive(Default)]
+struct Probe {
+    level: Cell<u32>,
+    log: RefCell<Vec<&'static str>>,
+}
+
+type Ctx = Rc<Probe>;
+
+impl DispatchContext for Ctx {
+    fn level(&self) -> u32 {
+        self.level.get()
+    }
+
+    fn set_level(&self, level: u32) {
+        self.level.set(level);
+    }
+
+    fn log(&self, msg: &'static str) {
+        self.log.borrow_mut().push(msg);
+    }
+}
+
+fn take(ctx: &Ctx) -> Vec<&'static str> {
+    ctx.log.borrow_mut().drain(..).collect()
+}
+
+#[test]
+fn choice_evaluates_after_action() {
+    let ctx = Ctx::default();
+    let mut m = Dispatch::new(ctx.clone());
+    m.enter();
+
+    assert_eq!(m.process(Measure(80)), ProcessResult::Handled);
+    assert!(m.state() == Some(DispatchState::High));
+    assert_eq!(take(&ctx), ["exit Idle", "high"]);
+
+    m.process(Reset);
+    m.process(Measure(100));
+    assert!(m.state() == Some(DispatchState::Full));
+    assert_eq!(take(&ctx), ["exit Idle", "full"]);
+}
+
+#[test]
+fn choice_else_branch() {
+    let ctx = Ctx::default();
+    let mut m = Dispatch::new(ctx.clone());
+    m.enter();
+
+    m.process(Measure(10));
+    assert!(m.state() == Some(DispatchState::Low));
+    assert_eq!(take(&ctx), ["exit Idle", "low"]);
+}
+
+#[test]
+fn junction_is_static() {
+    let ctx = Ctx::default();
+    let mut m = Dispatch::new(ctx.clone());
+    m.enter();
+
+    // No branch of the junction is enabled, so `Idle` is never exited.
+    assert_eq!(m.process(Charge(0)), ProcessResult::Unhandled);
+    assert!(m.state() == Some(DispatchState::Idle));
+    assert!(take(&ctx).is_empty());
+
+    assert_eq!(m.process(Charge(120)), ProcessResult::Handled);
+    assert!(m.state() == Some(DispatchState::Full));
+}
+
+#[test]
+fn initial_choice() {
+    let ctx = Ctx::default();
+    let mut m = Dispatch::new(ctx.clone());
+    m.enter();
+
+    m.process(Charge(5));
+    assert!(m.state() == Some(DispatchState::Powered));
+    // `Pick` chose `Empty`, which does not handle `Measure`.
+    assert_eq!(m.process(Measure(1)), ProcessResult::Unhandled);
+}
+
+#[derive(Clone)]
+struct Open(u32);
+
+umlstate! {
+    machine Gate {
+        fn log(&self, msg: &'static str);
+
+        state Closed {
+            exit / ctx.log("exit Closed");
+        }
+        state Wide;
+        state Ajar;
+
+        state Latched {
+            state Locked;
+            state Check junction;
+
+            <*> => Check;
+            Check => Locked if n % 2 == 0;
+        }
+
+        state Route choice;
+        state Size junction;
+
+        <*> => Closed;
+        Closed + Open(n) => Route;
+        Route => Latched if n > 100;
+        Route => Size if n > 0;
+        Route => Closed if else;
+        Size => Wide if n > 50;
+        Size => Ajar if n > 20;
+        Wide + Open(n) => Latched;
+    }
+}
+
+impl GateContext for Ctx {
+    fn log(&self, msg: &'static str) {
+        self.log.borrow_mut().push(msg);
+    }
+}
+
+#[test]
+fn junction_in_choice_branch() {
+    let ctx = Ctx::default();
+    let mut m = Gate::new(ctx.clone());
+    m.enter();
+
+    // `Size` has no enabled path, so the choice takes its `else` branch.
+    assert_eq!(m.process(Open(10)), ProcessResult::Handled);
+    assert!(m.state() == Some(GateState::Closed));
+    assert_eq!(take(&ctx), ["exit Closed"]);
+
+    m.process(Open(30));
+    assert!(m.state() == Some(GateState::Ajar));
+}
+
+#[test]
+fn junction_in_initial_transition() {
+    let ctx = Ctx::default();
+    let mut m = Gate::new(ctx.clone());
+    m.enter();
+
+    // `Latched` cannot be entered with an odd number, so `Size` is taken.
+    assert_eq!(m.process(Open(101)), ProcessResult::Handled);
+    assert!(m.state() == Some(GateState::Wide));
+
+    assert_eq!(m.process(Open(3)), ProcessResult::Unhandled);
+    assert!(m.state() == Some(GateState::Wide));
+
+    assert_eq!(m.process(Open(4)), ProcessResult::Handled);
+    assert!(m.state() == Some(GateState::Latched));
+}
+
+umlstate! {
+    machine Sorter {
+        state Idle;
+        state Small;
+        state Pick choice(partial);
+
+        <*> => Idle;
+        Idle + Measure(n) => Pick;
+        Pick => Small if n < 10;
+    }
+}
+
+#[test]
+#[should_panic(expected = "partial choice Pick has no enabled outgoing transition")]
+fn partial_choice() {
+    let mut m = Sorter::new();
+    m.enter();
+    m.process(Measure(20));
+}
@@ -0,0 +1,37 @@
+//! Helpers shared by the integration tests, each of them using a few.
+#![allow(dead_code, unused_macros, unused_imports)]
+
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+/// Polls `future` until it completes, the futures of the tests being
+/// pending only for a few polls.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+    let mut future = pin!(future);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(output) = future.as_mut().poll(&mut cx) {
+            return output;
+        }
+    }
+}
+
+/// Context of the machines whose actions only log lines.
+#[derive(Default)]
+pub struct Log(pub Vec<&'static str>);
+
+/// Implements the context traits of such machines for `Log`, each of them
+/// named along with its logging method, like `PlayerContext::log`.
+macro_rules! log_context {
+    ($($context:ident::$method:ident),* $(,)?) => {
+        $(
+            impl $context for $crate::common::Log {
+                fn $method(&mut self, line: &'static str) {
+                    self.0.push(line);
+                }
+            }
+        )*
+    };
+}
+pub(crate) use log_context;
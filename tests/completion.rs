@@ -0,0 +1,88 @@
+use umlstate::*;
+
+#[derive(Clone)]
+struct UsbConnected;
+#[derive(Clone)]
+struct ChargeActive;
+#[derive(Clone)]
+struct ChargeInactive;
+#[derive(Clone)]
+struct Go;
+#[derive(Clone)]
+struct Done;
+
+umlstate! {
+    machine Charge {
+        state Unpowered;
+
+        state Powered {
+            state WaitCharge;
+            state Charging;
+            state ChargeDone final;
+
+            <*> => WaitCharge;
+            WaitCharge + ChargeActive => Charging;
+            Charging + ChargeInactive => ChargeDone;
+        }
+
+        state Finished;
+        state Resting;
+
+        <*> => Unpowered;
+        Unpowered + UsbConnected => Powered;
+        Powered => Finished;
+        Finished => Resting;
+    }
+}
+
+umlstate! {
+    machine Parallel {
+        state Working {
+            region A {
+                state Busy;
+
+                <*> => Busy;
+                Busy + Go => <X>;
+            }
+
+            region B {
+                state Busy;
+
+                <*> => Busy;
+                Busy + Done => <X>;
+            }
+        }
+
+        state Idle;
+
+        <*> => Working;
+        Working => Idle;
+    }
+}
+
+#[test]
+fn completion() {
+    let mut m = Charge::new();
+    m.enter();
+    m.process(UsbConnected);
+    assert!(m.state() == Some(ChargeState::Powered));
+    m.process(ChargeActive);
+    assert!(m.state() == Some(ChargeState::Powered));
+
+    // Reaching the final state completes `Powered`, and the simple state
+    // `Finished` completes right after being entered.
+    m.process(ChargeInactive);
+    assert!(m.state() == Some(ChargeState::Resting));
+}
+
+#[test]
+fn region_completion() {
+    let mut m = Parallel::new();
+    m.enter();
+    assert!(m.state() == Some(ParallelState::Working));
+    m.process(Go);
+    assert!(m.state() == Some(ParallelState::Working));
+    assert_eq!(m.process(Go), ProcessResult::Unhandled);
+    m.process(Done);
+    assert!(m.state() == Some(ParallelState::Idle));
+}
@@ -0,0 +1,172 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use umlstate::*;
+
+#[derive(Clone)]
+struct Request(u32);
+#[derive(Clone)]
+struct Done;
+#[derive(Clone)]
+struct Start;
+
+umlstate! {
+    machine Server {
+        fn serve(&self, id: u32);
+
+        state Idle;
+
+        state Busy {
+            state Working;
+            state Flushing;
+
+            <*> => Working;
+            Working + Done => Flushing;
+
+            defer Request;
+        }
+
+        <*> => Idle;
+        Idle + Start => Busy;
+        Busy.Flushing + Done => Idle;
+        Idle + Request(id) => Busy / ctx.serve(id);
+    }
+}
+
+type Log = Rc<RefCell<Vec<u32>>>;
+
+impl ServerContext for Log {
+    fn serve(&self, id: u32) {
+        self.borrow_mut().push(id);
+    }
+}
+
+#[test]
+fn defer_until_state_left() {
+    let log = Log::default();
+    let mut m = Server::new(log.clone());
+    m.enter();
+    m.process(Start);
+
+    assert_eq!(m.process(Request(1)), ProcessResult::Deferred);
+    assert_eq!(m.process(Request(2)), ProcessResult::Deferred);
+
+    // Changing sub-states within `Busy` keeps the requests deferred.
+    assert_eq!(m.process(Done), ProcessResult::Handled);
+    assert!(log.borrow().is_empty());
+
+    // Leaving `Busy` serves the first request, which enters `Busy` again
+    // and defers the second one once more.
+    assert_eq!(m.process(Done), ProcessResult::Handled);
+    assert_eq!(*log.borrow(), [1]);
+    assert!(m.state() == Some(ServerState::Busy));
+
+    m.process(Done);
+    m.process(Done);
+    assert_eq!(*log.borrow(), [1, 2]);
+}
+
+#[test]
+fn exit_drops_deferred_events() {
+    let log = Log::default();
+    let mut m = Server::new(log.clone());
+    m.enter();
+    m.process(Start);
+    m.process(Request(1));
+    m.exit();
+
+    m.enter();
+    m.process(Start);
+    m.process(Done);
+    m.process(Done);
+    assert!(log.borrow().is_empty());
+}
+
+#[derive(Clone)]
+struct Job(u32);
+#[derive(Clone)]
+struct Ready;
+#[derive(Clone)]
+struct Tick;
+
+umlstate! {
+    machine Pipeline {
+        fn run(&self, id: u32);
+        fn audit(&self, id: u32);
+        fn accepts(&self, id: u32) -> bool;
+        fn tick(&self);
+
+        region Worker {
+            state Busy {
+                state Working;
+
+                <*> => Working;
+                Working + Job(id) => Working if ctx.accepts(id);
+
+                defer Job;
+            }
+            state Free;
+
+            <*> => Busy;
+            Busy + Ready => Free;
+            Free + Job(id) => Busy / ctx.run(id);
+        }
+
+        region Auditor {
+            state Watching {
+                Job(id) / ctx.audit(id);
+                Tick / ctx.tick();
+            }
+
+            <*> => Watching;
+        }
+    }
+}
+
+#[derive(Default)]
+struct Trace(RefCell<Vec<String>>);
+
+impl PipelineContext for Rc<Trace> {
+    fn run(&self, id: u32) {
+        self.0.borrow_mut().push(format!("run {id}"));
+    }
+
+    fn audit(&self, id: u32) {
+        self.0.borrow_mut().push(format!("audit {id}"));
+    }
+
+    fn accepts(&self, id: u32) -> bool {
+        self.0.borrow_mut().push(format!("check {id}"));
+        false
+    }
+
+    fn tick(&self) {
+        self.0.borrow_mut().push("tick".into());
+    }
+}
+
+#[test]
+fn region_keeps_its_deferral() {
+    let trace = Rc::new(Trace::default());
+    let mut m = Pipeline::new(trace.clone());
+    m.enter();
+
+    // `Auditor` handles the job while `Worker` defers it.
+    assert_eq!(m.process(Job(1)), ProcessResult::Handled);
+    assert_eq!(*trace.0.borrow(), ["check 1", "audit 1"]);
+
+    // Once `Worker` is free, the job goes to it alone.
+    m.process(Ready);
+    assert_eq!(*trace.0.borrow(), ["check 1", "audit 1", "run 1"]);
+}
+
+#[test]
+fn redispatch_only_after_state_change() {
+    let trace = Rc::new(Trace::default());
+    let mut m = Pipeline::new(trace.clone());
+    m.enter();
+    m.process(Job(1));
+
+    // Nothing changes the states, so the job is not tried again.
+    assert_eq!(m.process(Tick), ProcessResult::Handled);
+    assert_eq!(*trace.0.borrow(), ["check 1", "audit 1", "tick"]);
+}
@@ -0,0 +1,221 @@
+use umlstate::*;
+
+struct UsbConnected;
+struct ChargeActive;
+struct Fault;
+struct Resume;
+
+umlstate! {
+    machine Charger {
+        fn log(&self, msg: &'static str);
+        fn healthy(&self) -> bool;
+
+        state Unpowered;
+
+        state Powered {
+            state WaitCharge;
+            state Charging {
+                entry / ctx.log("charging");
+            }
+
+            <*> => WaitCharge;
+            WaitCharge + ChargeActive => Charging;
+        }
+
+        state Monitor {
+            region Battery {
+                state Ok;
+                <*> => Ok;
+            }
+            region Thermal {
+                state Cool;
+                <*> => Cool;
+            }
+        }
+
+        state Check choice;
+
+        <*> => Unpowered;
+        Unpowered + UsbConnected => Check;
+        Check => Powered if ctx.healthy();
+        Check => Monitor if else;
+        Powered + Fault => Unpowered / ctx.log("fault");
+        Monitor + Resume => Powered.<H>;
+    }
+}
+
+impl ChargerContext for () {
+    fn log(&self, _msg: &'static str) {}
+
+    fn healthy(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn dot() {
+    let dot = Charger::<()>::DOT;
+
+    assert!(dot.starts_with("digraph Charger {\n"));
+    assert!(dot.contains("    \"<*>\" [shape=point, width=0.15];\n"));
+    assert!(dot.contains("    subgraph \"cluster_Powered\" {\n"));
+    assert!(dot.contains(
+        "        \"Powered.Charging\" [label=\"Charging\\nentry / ctx.log(\\\"charging\\\")\"];\n"
+    ));
+    assert!(dot.contains("        subgraph \"cluster_Monitor.Battery\" {\n            label=\"Battery\";\n            style=dashed;\n"));
+    assert!(dot.contains("    \"Check\" [shape=diamond"));
+    assert!(dot.contains(
+        "    \"Check\" -> \"Powered\" [label=\"[ctx.healthy()]\", lhead=\"cluster_Powered\"];\n"
+    ));
+    assert!(dot
+        .contains("    \"Check\" -> \"Monitor\" [label=\"[else]\", lhead=\"cluster_Monitor\"];\n"));
+    assert!(dot.contains("    \"Powered\" -> \"Unpowered\" [label=\"Fault / ctx.log(\\\"fault\\\")\", ltail=\"cluster_Powered\"];\n"));
+    assert!(dot.contains(
+        "    \"Monitor\" -> \"Powered.<H>\" [label=\"Resume\", ltail=\"cluster_Monitor\"];\n"
+    ));
+    assert!(dot.ends_with("}\n"));
+}
+
+#[test]
+fn plantuml() {
+    assert_eq!(
+        Charger::<()>::PLANTUML,
+        r#"@startuml
+state Check <<choice>>
+state Unpowered
+state Powered {
+  state "WaitCharge" as Powered__WaitCharge
+  state "Charging" as Powered__Charging
+  Powered__Charging : entry / ctx.log("charging")
+  [*] --> Powered__WaitCharge
+  Powered__WaitCharge --> Powered__Charging : ChargeActive
+}
+state Monitor {
+  state "Ok" as Monitor__Battery__Ok
+  [*] --> Monitor__Battery__Ok
+  --
+  state "Cool" as Monitor__Thermal__Cool
+  [*] --> Monitor__Thermal__Cool
+}
+[*] --> Unpowered
+Check --> Powered : [ctx.healthy()]
+Check --> Monitor : [else]
+Unpowered --> Check : UsbConnected
+Powered --> Unpowered : Fault / ctx.log("fault")
+Monitor --> Powered[H] : Resume
+@enduml
+"#
+    );
+}
+
+#[test]
+fn mermaid() {
+    assert_eq!(
+        Charger::<()>::MERMAID,
+        r#"stateDiagram-v2
+    state Check <<choice>>
+    state Unpowered
+    state Powered {
+        state "H" as Powered_H
+        state "WaitCharge" as Powered__WaitCharge
+        state "Charging" as Powered__Charging
+        Powered__Charging : entry / ctx.log("charging")
+        [*] --> Powered__WaitCharge
+        Powered__WaitCharge --> Powered__Charging : ChargeActive
+    }
+    state Monitor {
+        state "Ok" as Monitor__Battery__Ok
+        [*] --> Monitor__Battery__Ok
+        --
+        state "Cool" as Monitor__Thermal__Cool
+        [*] --> Monitor__Thermal__Cool
+    }
+    [*] --> Unpowered
+    Check --> Powered : [ctx.healthy()]
+    Check --> Monitor : [else]
+    Unpowered --> Check : UsbConnected
+    Powered --> Unpowered : Fault / ctx.log("fault")
+    Monitor --> Powered_H : Resume
+"#
+    );
+}
+
+#[test]
+fn scxml() {
+    assert_eq!(
+        Charger::<()>::SCXML,
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<scxml xmlns="http://www.w3.org/2005/07/scxml" version="1.0" name="Charger" initial="Charger">
+  <state id="Charger" initial="Charger.Unpowered">
+    <state id="Charger.Check">
+      <transition cond="ctx.healthy()" target="Charger.Powered"/>
+      <transition target="Charger.Monitor"/>
+    </state>
+    <state id="Charger.Unpowered">
+      <transition event="UsbConnected" target="Charger.Check"/>
+    </state>
+    <state id="Charger.Powered" initial="Charger.Powered.WaitCharge">
+      <history id="Charger.Powered-history" type="shallow">
+        <transition target="Charger.Powered.WaitCharge"/>
+      </history>
+      <state id="Charger.Powered.WaitCharge">
+        <transition event="ChargeActive" target="Charger.Powered.Charging"/>
+      </state>
+      <state id="Charger.Powered.Charging">
+        <onentry><script>ctx.log(&quot;charging&quot;)</script></onentry>
+      </state>
+      <transition event="Fault" target="Charger.Unpowered"><script>ctx.log(&quot;fault&quot;)</script></transition>
+    </state>
+    <parallel id="Charger.Monitor">
+      <state id="Charger.Monitor.Battery" initial="Charger.Monitor.Battery.Ok">
+        <state id="Charger.Monitor.Battery.Ok"/>
+      </state>
+      <state id="Charger.Monitor.Thermal" initial="Charger.Monitor.Thermal.Cool">
+        <state id="Charger.Monitor.Thermal.Cool"/>
+      </state>
+      <transition event="Resume" target="Charger.Powered-history"/>
+    </parallel>
+  </state>
+</scxml>
+"#
+    );
+}
+
+umlstate! {
+    machine Gate {
+        fn even(&self) -> bool;
+
+        state Latched {
+            state Locked;
+            state Unlocked;
+            state Check junction;
+
+            <*> => Check;
+            Check => Locked if ctx.even();
+            Check => Unlocked if else;
+        }
+
+        <*> => Latched;
+    }
+}
+
+impl GateContext for () {
+    fn even(&self) -> bool {
+        true
+    }
+}
+
+#[test]
+fn scxml_initial_junction() {
+    // The `initial` attribute can only name a state, so the paths through
+    // the junction make up an `<initial>` element.
+    assert!(Gate::<()>::SCXML.contains(
+        r#"    <state id="Gate.Latched">
+      <initial>
+        <transition cond="ctx.even()" target="Gate.Latched.Locked"/>
+        <transition target="Gate.Latched.Unlocked"/>
+      </initial>
+"#
+    ));
+    assert!(!Gate::<()>::SCXML.contains("Gate.Latched.Check"));
+}
@@ -0,0 +1,157 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use umlstate::*;
+
+#[derive(Clone)]
+struct UsbConnected;
+#[derive(Clone)]
+struct ChargeActive;
+#[derive(Clone)]
+struct Skip;
+#[derive(Clone)]
+struct Fault;
+#[derive(Clone)]
+struct Jump;
+
+umlstate! {
+    machine Cross {
+        fn log(&self, msg: &'static str);
+
+        state Unpowered {
+            entry / ctx.log("enter Unpowered");
+            exit / ctx.log("exit Unpowered");
+        }
+
+        state Powered {
+            state WaitCharge {
+                entry / ctx.log("enter WaitCharge");
+                exit / ctx.log("exit WaitCharge");
+            }
+            state Charging {
+                entry / ctx.log("enter Charging");
+                exit / ctx.log("exit Charging");
+            }
+
+            entry / ctx.log("enter Powered");
+            exit / ctx.log("exit Powered");
+
+            <*> => WaitCharge;
+            WaitCharge + ChargeActive => Charging;
+        }
+
+        state Split {
+            region A {
+                state X;
+                state Y {
+                    entry / ctx.log("enter A.Y");
+                }
+
+                <*> => X;
+            }
+
+            region B {
+                state X {
+                    entry / ctx.log("enter B.X");
+                }
+
+                <*> => X;
+            }
+
+            entry / ctx.log("enter Split");
+        }
+
+        <*> => Unpowered;
+        Unpowered + UsbConnected => Powered.Charging;
+        Unpowered + ChargeActive => Powered;
+        Unpowered + Jump => Split.A.Y;
+        Powered.Charging + Fault => Unpowered;
+        Powered.WaitCharge + Skip => Powered.Charging;
+    }
+}
+
+type Log = Rc<RefCell<Vec<&'static str>>>;
+
+impl CrossContext for Log {
+    fn log(&self, msg: &'static str) {
+        self.borrow_mut().push(msg);
+    }
+}
+
+fn take(log: &Log) -> Vec<&'static str> {
+    log.borrow_mut().drain(..).collect()
+}
+
+#[test]
+fn enter_nested_target() {
+    let log = Log::default();
+    let mut m = Cross::new(log.clone());
+    m.enter();
+    assert_eq!(take(&log), ["enter Unpowered"]);
+
+    m.process(UsbConnected);
+    assert_eq!(
+        take(&log),
+        ["exit Unpowered", "enter Powered", "enter Charging"]
+    );
+    assert!(m.state() == Some(CrossState::Powered));
+}
+
+#[test]
+fn exit_nested_source() {
+    let log = Log::default();
+    let mut m = Cross::new(log.clone());
+    m.enter();
+    m.process(UsbConnected);
+    take(&log);
+
+    m.process(Fault);
+    assert_eq!(
+        take(&log),
+        ["exit Charging", "exit Powered", "enter Unpowered"]
+    );
+    assert!(m.state() == Some(CrossState::Unpowered));
+}
+
+#[test]
+fn nested_source_must_be_active() {
+    let log = Log::default();
+    let mut m = Cross::new(log.clone());
+    m.enter();
+    m.process(ChargeActive);
+    take(&log);
+
+    assert_eq!(m.process(Fault), ProcessResult::Unhandled);
+    assert!(take(&log).is_empty());
+}
+
+#[test]
+fn common_ancestor_is_not_exited() {
+    let log = Log::default();
+    let mut m = Cross::new(log.clone());
+    m.enter();
+    take(&log);
+
+    m.process(ChargeActive);
+    assert_eq!(
+        take(&log),
+        ["exit Unpowered", "enter Powered", "enter WaitCharge"]
+    );
+
+    assert_eq!(m.process(Skip), ProcessResult::Handled);
+    assert_eq!(take(&log), ["exit WaitCharge", "enter Charging"]);
+}
+
+#[test]
+fn enter_region_path() {
+    let log = Log::default();
+    let mut m = Cross::new(log.clone());
+    m.enter();
+    take(&log);
+
+    m.process(Jump);
+    assert_eq!(
+        take(&log),
+        ["exit Unpowered", "enter Split", "enter A.Y", "enter B.X"]
+    );
+    assert!(m.state() == Some(CrossState::Split));
+}
@@ -0,0 +1,81 @@
+use umlstate::*;
+
+#[derive(Clone)]
+struct Next;
+#[derive(Clone)]
+struct Interrupt;
+#[derive(Clone)]
+struct Resume;
+#[derive(Clone)]
+struct ResumeDeep;
+
+umlstate! {
+    machine Firmware {
+        state Idle;
+
+        state Running {
+            state Boot;
+            state Work {
+                state Step1;
+                state Step2;
+
+                <*> => Step1;
+                Step1 + Next => Step2;
+            }
+
+            <*> => Boot;
+            Boot + Next => Work;
+        }
+
+        state Paused;
+
+        <*> => Idle;
+        Idle + Next => Running.<H>;
+        Running + Interrupt => Paused;
+        Paused + Resume => Running.<H>;
+        Paused + ResumeDeep => Running.<H*>;
+    }
+}
+
+#[test]
+fn history_fallback() {
+    let mut m = Firmware::new();
+    m.enter();
+    m.process(Next);
+    assert!(m.state() == Some(FirmwareState::Running));
+    assert_eq!(m.process(Next), ProcessResult::Handled);
+    assert_eq!(m.process(Next), ProcessResult::Handled);
+    assert_eq!(m.process(Next), ProcessResult::Unhandled);
+}
+
+#[test]
+fn shallow_history() {
+    let mut m = Firmware::new();
+    m.enter();
+    m.process(Next);
+    m.process(Next);
+    m.process(Next);
+    m.process(Interrupt);
+    assert!(m.state() == Some(FirmwareState::Paused));
+
+    // Shallow history restores `Work`, which is entered at `Step1` again.
+    m.process(Resume);
+    assert!(m.state() == Some(FirmwareState::Running));
+    assert_eq!(m.process(Next), ProcessResult::Handled);
+    assert_eq!(m.process(Next), ProcessResult::Unhandled);
+}
+
+#[test]
+fn deep_history() {
+    let mut m = Firmware::new();
+    m.enter();
+    m.process(Next);
+    m.process(Next);
+    m.process(Next);
+    m.process(Interrupt);
+
+    // Deep history restores `Work.Step2`, so `Next` is no longer handled.
+    m.process(ResumeDeep);
+    assert!(m.state() == Some(FirmwareState::Running));
+    assert_eq!(m.process(Next), ProcessResult::Unhandled);
+}
@@ -0,0 +1,36 @@
+use umlstate::*;
+
+#[derive(Clone)]
+struct Go;
+#[derive(Clone)]
+struct Quit;
+
+umlstate! {
+    machine Session {
+        state Open;
+        state Closed final;
+
+        <*> => Open;
+        Open + Go => Open;
+        Open + Quit => Closed;
+    }
+}
+
+#[test]
+fn lifecycle() {
+    let mut m = Session::new();
+    assert_eq!(m.process(Go), ProcessResult::Error(Error::NotStarted));
+    assert_eq!(m.try_process(Go), Err(Error::NotStarted));
+    assert_eq!(m.try_exit(), Err(Error::NotActive));
+
+    assert_eq!(m.try_enter(), Ok(()));
+    assert_eq!(m.try_enter(), Err(Error::AlreadyActive));
+    assert_eq!(m.try_process(Go), Ok(ProcessResult::Handled));
+
+    assert_eq!(m.try_process(Quit), Ok(ProcessResult::Handled));
+    assert!(m.state() == Some(SessionState::Closed));
+    assert_eq!(m.process(Go), ProcessResult::Error(Error::Terminated));
+
+    assert_eq!(m.try_exit(), Ok(()));
+    assert_eq!(m.try_process(Go), Err(Error::NotStarted));
+}
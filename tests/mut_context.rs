@@ -0,0 +1,65 @@
+use umlstate::*;
+
+#[derive(Clone)]
+struct Coin(u32);
+#[derive(Clone)]
+struct Push;
+
+umlstate! {
+    machine Turnstile {
+        fn insert(&mut self, amount: u32);
+        fn paid(&self) -> bool;
+        fn pass(&mut self);
+
+        state Locked {
+            Coin(n) / ctx.insert(n);
+        }
+
+        state Unlocked {
+            exit / ctx.pass();
+        }
+
+        <*> => Locked;
+        Locked + Push => Unlocked if ctx.paid();
+        Unlocked + Push => Locked;
+    }
+}
+
+#[derive(Default)]
+struct Gate {
+    credit: u32,
+    passed: u32,
+}
+
+impl TurnstileContext for Gate {
+    fn insert(&mut self, amount: u32) {
+        self.credit += amount;
+    }
+
+    fn paid(&self) -> bool {
+        self.credit >= 50
+    }
+
+    fn pass(&mut self) {
+        self.credit -= 50;
+        self.passed += 1;
+    }
+}
+
+#[test]
+fn mutable_context() {
+    let mut m = Turnstile::new(Gate::default());
+    m.enter();
+
+    m.process(Coin(20));
+    assert_eq!(m.process(Push), ProcessResult::Unhandled);
+    m.process(Coin(40));
+    assert_eq!(m.process(Push), ProcessResult::Handled);
+    m.process(Push);
+
+    assert_eq!(m.context().passed, 1);
+    assert_eq!(m.context().credit, 10);
+
+    m.context_mut().credit += 40;
+    assert_eq!(m.process(Push), ProcessResult::Handled);
+}
@@ -39,8 +39,10 @@ fn no_context() {
     let r = b.process(E(5));
     assert_eq!(r, ProcessResult::Handled);
     assert!(b.state() == Some(NoContextState::B));
+    assert!(b.is_in(NoContextStatePath::BA));
     b.process(E3 {});
     assert!(b.state() == Some(NoContextState::B));
+    assert!(b.is_in(NoContextStatePath::BX));
     b.process(E3 {});
     assert!(b.state() == Some(NoContextState::B));
     b.process(E2 {});
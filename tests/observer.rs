@@ -0,0 +1,124 @@
+use umlstate::*;
+
+#[derive(Clone)]
+struct Start;
+#[derive(Clone)]
+struct Step(u32);
+#[derive(Clone)]
+struct Poke;
+#[derive(Clone)]
+struct Stop;
+
+umlstate! {
+    machine Job {
+        state Idle;
+
+        state Running {
+            state Work;
+            state Rest {
+                Poke / println!("poked");
+            }
+
+            <*> => Work;
+            Work + Step(n) => Rest if n > 1;
+        }
+
+        <*> => Idle;
+        Idle + Start => Running;
+        Running + Stop => Idle;
+    }
+}
+
+#[derive(Default)]
+struct Recorder(Vec<String>);
+
+impl Observer for Recorder {
+    fn on_event_received(&mut self, event: &'static str) {
+        self.0.push(format!("event {}", event));
+    }
+
+    fn on_transition(
+        &mut self,
+        source: &'static str,
+        target: &'static str,
+        event: Option<&'static str>,
+    ) {
+        self.0
+            .push(format!("{} -> {} on {:?}", source, target, event));
+    }
+
+    fn on_entry(&mut self, state: &'static str) {
+        self.0.push(format!("entry {}", state));
+    }
+
+    fn on_exit(&mut self, state: &'static str) {
+        self.0.push(format!("exit {}", state));
+    }
+
+    fn on_unhandled(&mut self, event: &'static str) {
+        self.0.push(format!("unhandled {}", event));
+    }
+
+    fn on_guard_rejected(&mut self, source: &'static str, event: &'static str) {
+        self.0.push(format!("rejected {} in {}", event, source));
+    }
+}
+
+fn take(job: &mut Job<Recorder>) -> Vec<String> {
+    std::mem::take(&mut job.observer_mut().0)
+}
+
+#[test]
+fn observer() {
+    let mut job = Job::new().with_observer(Recorder::default());
+    job.enter();
+    assert_eq!(take(&mut job), ["entry Idle"]);
+
+    job.process(Start);
+    assert_eq!(
+        take(&mut job),
+        [
+            "event Start",
+            "Idle -> Running on Some(\"Start\")",
+            "exit Idle",
+            "entry Running",
+            "entry Running.Work",
+        ]
+    );
+
+    job.process(Step(1));
+    assert_eq!(
+        take(&mut job),
+        [
+            "event Step",
+            "rejected Step in Running.Work",
+            "unhandled Step"
+        ]
+    );
+
+    job.process(Step(2));
+    job.process(Poke);
+    assert_eq!(
+        take(&mut job),
+        [
+            "event Step",
+            "Running.Work -> Running.Rest on Some(\"Step\")",
+            "exit Running.Work",
+            "entry Running.Rest",
+            "event Poke",
+            "Running.Rest -> Running.Rest on Some(\"Poke\")",
+        ]
+    );
+
+    job.process(Stop);
+    assert_eq!(
+        take(&mut job),
+        [
+            "event Stop",
+            "Running -> Idle on Some(\"Stop\")",
+            "exit Running.Rest",
+            "exit Running",
+            "entry Idle",
+        ]
+    );
+}
@@ -0,0 +1,107 @@
+use umlstate::*;
+
+struct Load(Vec<u8>);
+struct Append(Vec<u8>);
+struct Flush;
+#[derive(Clone)]
+struct Tick;
+
+umlstate! {
+    machine Writer {
+        fn store(&mut self, data: Vec<u8>);
+
+        state Idle;
+
+        state Busy {
+            Append(data) / ctx.store(data);
+            defer Load;
+        }
+
+        <*> => Idle;
+        Idle + Load(data) => Busy / ctx.store(data);
+        Busy + Flush => Idle;
+    }
+}
+
+#[derive(Default)]
+struct Sink {
+    stored: Vec<Vec<u8>>,
+}
+
+impl WriterContext for Sink {
+    fn store(&mut self, data: Vec<u8>) {
+        self.stored.push(data);
+    }
+}
+
+#[test]
+fn move_into_action() {
+    let mut m = Writer::new(Sink::default());
+    m.enter();
+
+    assert_eq!(m.process(Load(vec![1])), ProcessResult::Handled);
+    assert_eq!(m.process(Append(vec![2])), ProcessResult::Handled);
+    assert_eq!(m.process(Load(vec![3])), ProcessResult::Deferred);
+    assert_eq!(m.context().stored, [vec![1], vec![2]]);
+
+    // The deferred payload is kept by value until it is handled.
+    m.process(Flush);
+    assert_eq!(m.context().stored, [vec![1], vec![2], vec![3]]);
+}
+
+umlstate! {
+    machine Duplex {
+        fn store(&mut self, data: Vec<u8>);
+        fn tick(&mut self);
+
+        state Running {
+            region Rx {
+                state Listening {
+                    Load(data) / ctx.store(data);
+                    Tick / ctx.tick();
+                }
+
+                <*> => Listening;
+            }
+
+            region Tx {
+                state Sending {
+                    Append(data) / ctx.store(data);
+                    Tick / ctx.tick();
+                }
+
+                <*> => Sending;
+            }
+        }
+
+        <*> => Running;
+    }
+}
+
+#[derive(Default)]
+struct Link {
+    stored: Vec<Vec<u8>>,
+    ticks: u32,
+}
+
+impl DuplexContext for Link {
+    fn store(&mut self, data: Vec<u8>) {
+        self.stored.push(data);
+    }
+
+    fn tick(&mut self) {
+        self.ticks += 1;
+    }
+}
+
+#[test]
+fn regions_share_only_common_events() {
+    let mut m = Duplex::new(Link::default());
+    m.enter();
+
+    m.process(Load(vec![1]));
+    m.process(Append(vec![2]));
+    m.process(Tick);
+    assert_eq!(m.context().stored, [vec![1], vec![2]]);
+    assert_eq!(m.context().ticks, 2);
+}
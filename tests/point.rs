@@ -0,0 +1,106 @@
+use umlstate::*;
+
+mod common;
+use common::Log;
+
+#[derive(Clone)]
+struct Play;
+#[derive(Clone)]
+struct Resume;
+#[derive(Clone)]
+struct Next;
+#[derive(Clone)]
+struct Fail;
+
+umlstate! {
+    machine Player {
+        fn log(&mut self, line: &'static str);
+
+        state Stopped;
+        state Playing {
+            entry_point Continue;
+            exit_point Aborted;
+
+            entry / ctx.log("enter Playing");
+            exit / ctx.log("exit Playing");
+
+            state Intro;
+            state Track {
+                entry / ctx.log("enter Track");
+                exit / ctx.log("exit Track");
+            }
+
+            <*> => Intro;
+            Continue => Track / ctx.log("continue");
+            Intro + Next => Track;
+            Track + Fail => Aborted / ctx.log("fail");
+        }
+        state Broken;
+
+        <*> => Stopped;
+        Stopped + Play => Playing;
+        Stopped + Resume => Playing.Continue;
+        Playing.Aborted => Broken / ctx.log("abort");
+    }
+}
+
+common::log_context!(PlayerContext::log);
+
+use PlayerStatePath::*;
+
+#[test]
+fn entry_point() {
+    let mut m = Player::new(Log::default());
+    m.enter();
+    m.process(Resume);
+    assert!(m.is_in(PlayingTrack));
+    assert_eq!(m.context().0, ["enter Playing", "continue", "enter Track"]);
+}
+
+#[test]
+fn default_entry() {
+    let mut m = Player::new(Log::default());
+    m.enter();
+    m.process(Play);
+    assert!(m.is_in(PlayingIntro));
+    assert_eq!(m.context().0, ["enter Playing"]);
+}
+
+#[test]
+fn exit_point() {
+    let mut m = Player::new(Log::default());
+    m.enter();
+    m.process(Play);
+    assert_eq!(m.process(Fail), ProcessResult::Unhandled);
+
+    m.process(Next);
+    assert_eq!(m.process(Fail), ProcessResult::Handled);
+    assert!(m.is_in(Broken));
+    assert_eq!(
+        m.context().0,
+        [
+            "enter Playing",
+            "enter Track",
+            "exit Track",
+            "exit Playing",
+            "fail",
+            "abort"
+        ]
+    );
+}
+
+#[test]
+fn point_diagram() {
+    assert!(Player::<Log>::PLANTUML.contains("<<entryPoint>>"));
+
+    // Exit points are drawn with the transitions meeting there.
+    assert!(Player::<Log>::PLANTUML.contains("state \"Aborted\" as Playing__Aborted <<exitPoint>>"));
+    assert!(Player::<Log>::PLANTUML
+        .contains("Playing__Track --> Playing__Aborted : Fail / ctx.log(\"fail\")"));
+    assert!(Player::<Log>::PLANTUML.contains("Playing__Aborted --> Broken : / ctx.log(\"abort\")"));
+    assert!(Player::<Log>::MERMAID.contains("state \"«exitPoint» Aborted\" as Playing__Aborted"));
+    assert!(Player::<Log>::DOT.contains(
+        "\"Playing.Track\" -> \"Playing.Aborted\" [label=\"Fail / ctx.log(\\\"fail\\\")\"];"
+    ));
+    assert!(Player::<Log>::SCXML.contains("<!-- exit point Aborted:"));
+}
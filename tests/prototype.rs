@@ -1,3 +1,6 @@
+// Hand-written the way the generated code looked, kept as is.
+#![allow(clippy::match_single_binding, clippy::manual_is_multiple_of)]
+
 use std::cell::RefCell;
 use std::rc::Rc;
 use umlstate::EventProcessor;
@@ -90,6 +93,10 @@ mod mymachine_mod {
                 MyMachineState::SubMachine1 => {
                     match self.sub_machine1.process_event(event.clone()) {
                         umlstate::ProcessResult::Handled => umlstate::ProcessResult::Handled,
+                        umlstate::ProcessResult::Deferred => umlstate::ProcessResult::Deferred,
+                        umlstate::ProcessResult::Error(error) => {
+                            umlstate::ProcessResult::Error(error)
+                        }
                         umlstate::ProcessResult::Unhandled => {
                             let _ctx = self.context.borrow();
                             match event {
@@ -0,0 +1,79 @@
+use umlstate::*;
+
+#[derive(Clone)]
+struct Plug;
+#[derive(Clone)]
+struct Full;
+#[derive(Clone)]
+struct Heat;
+#[derive(Clone)]
+struct Unplug;
+
+umlstate! {
+    machine Battery {
+        state Unplugged;
+
+        state Plugged {
+            region Charge {
+                state Charging;
+                state Charged;
+                <*> => Charging;
+                Charging + Full => Charged;
+            }
+            region Thermal {
+                state Cool;
+                state Hot;
+                <*> => Cool;
+                Cool + Heat => Hot;
+            }
+        }
+
+        <*> => Unplugged;
+        Unplugged + Plug => Plugged;
+        Plugged + Unplug => Unplugged;
+    }
+}
+
+use BatteryStatePath::*;
+
+#[test]
+fn is_in() {
+    let mut m = Battery::new();
+    assert!(!m.is_in(Unplugged));
+
+    m.enter();
+    assert!(m.is_in(Unplugged));
+    assert!(!m.is_in(PluggedCharge));
+
+    m.process(Plug);
+    m.process(Heat);
+    assert!(m.is_in(Plugged));
+    assert!(m.is_in(PluggedCharge));
+    assert!(m.is_in(PluggedChargeCharging));
+    assert!(m.is_in(PluggedThermalHot));
+    assert!(!m.is_in(PluggedThermalCool));
+    assert!(!m.is_in(Unplugged));
+}
+
+#[test]
+fn active_states() {
+    let mut m = Battery::new();
+    assert_eq!(m.active_states().count(), 0);
+
+    m.enter();
+    m.process(Plug);
+    m.process(Full);
+    assert_eq!(
+        m.active_states().collect::<Vec<_>>(),
+        [
+            PluggedChargeCharged,
+            PluggedCharge,
+            PluggedThermalCool,
+            PluggedThermal,
+            Plugged,
+        ]
+    );
+
+    m.process(Unplug);
+    assert_eq!(m.active_states().collect::<Vec<_>>(), [Unplugged]);
+}
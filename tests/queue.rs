@@ -0,0 +1,181 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use umlstate::*;
+
+#[derive(Clone)]
+struct Start;
+#[derive(Clone)]
+struct Step(u32);
+#[derive(Clone)]
+struct Stop;
+
+umlstate! {
+    machine Pump {
+        fn log(&self, msg: &'static str);
+
+        state Idle;
+
+        state Running {
+            entry / {
+                ctx.log("enter Running");
+                queue.post(Step(1));
+            };
+            exit / ctx.log("exit Running");
+
+            Step(_) / ctx.log("step");
+        }
+
+        state Stopped {
+            entry / ctx.log("enter Stopped");
+        }
+
+        <*> => Idle;
+        Idle + Start => Running / {
+            queue.post(Stop);
+            ctx.log("start");
+        };
+        Running + Stop => Stopped;
+    }
+}
+
+type Log = Rc<RefCell<Vec<&'static str>>>;
+
+impl PumpContext for Log {
+    fn log(&self, msg: &'static str) {
+        self.borrow_mut().push(msg);
+    }
+}
+
+#[test]
+fn run_to_completion() {
+    let log = Log::default();
+    let mut m = Pump::new(log.clone());
+    m.enter();
+
+    // Posted events are dispatched in order once the transition is done;
+    // `Step` is no longer handled after `Stop` left `Running`.
+    assert_eq!(m.process(Start), ProcessResult::Handled);
+    assert_eq!(
+        *log.borrow(),
+        ["start", "enter Running", "exit Running", "enter Stopped"]
+    );
+    assert!(m.state() == Some(PumpState::Stopped));
+}
+
+umlstate! {
+    machine Counter {
+        fn log(&self, msg: &'static str);
+
+        state Counting {
+            Step(n) / {
+                ctx.log("step");
+                if n < 3 {
+                    queue.post(Step(n + 1));
+                }
+            };
+        }
+
+        <*> => Counting;
+    }
+}
+
+impl CounterContext for Log {
+    fn log(&self, msg: &'static str) {
+        self.borrow_mut().push(msg);
+    }
+}
+
+#[test]
+fn post_from_internal_transition() {
+    let log = Log::default();
+    let mut m = Counter::new(log.clone());
+    m.enter();
+
+    m.process(Step(1));
+    assert_eq!(*log.borrow(), ["step", "step", "step"]);
+}
+
+umlstate! {
+    machine Flood {
+        queue_capacity = 4;
+
+        state Flooding {
+            Start / {
+                for n in 0..5 {
+                    queue.post(Step(n));
+                }
+            };
+            Stop / {
+                for n in 0..5 {
+                    if queue.try_post(Step(n)).is_err() {
+                        break;
+                    }
+                }
+            };
+            Step(_) / {};
+        }
+
+        <*> => Flooding;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+#[test]
+fn queue_capacity() {
+    let mut m = Flood::new();
+    m.enter();
+
+    // The fifth event does not fit, so it is dropped.
+    assert_eq!(m.process(Start), ProcessResult::Error(Error::QueueFull));
+    assert_eq!(m.try_process(Start), Err(Error::QueueFull));
+
+    // An action posting with `try_post` deals with a full queue itself.
+    assert_eq!(m.process(Stop), ProcessResult::Handled);
+}
+
+#[cfg(feature = "std")]
+#[test]
+fn queue_grows() {
+    let mut m = Flood::new();
+    m.enter();
+    assert_eq!(m.process(Start), ProcessResult::Handled);
+}
+
+umlstate! {
+    machine Burst {
+        queue_capacity = 2;
+
+        state Bursting {
+            entry / {
+                for n in 0..3 {
+                    queue.post(Step(n));
+                }
+            };
+            Step(_) / {};
+        }
+
+        <*> => Bursting;
+    }
+}
+
+umlstate! {
+    machine Spillway {
+        state Closed;
+        state Open: Burst;
+
+        <*> => Closed;
+        Closed + Start => Open;
+    }
+}
+
+#[cfg(not(feature = "std"))]
+#[test]
+fn overflow_on_submachine_entry() {
+    let mut m = Spillway::new(());
+    m.enter();
+
+    // The embedded machine drops the third event it posts on entry, which
+    // the embedding machine reports.
+    assert_eq!(m.try_process(Start), Err(Error::QueueFull));
+    assert!(m.state() == Some(SpillwayState::Open));
+}
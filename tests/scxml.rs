@@ -0,0 +1,87 @@
+use umlstate::*;
+
+struct Play;
+struct Pause;
+struct Finish;
+struct Stop;
+struct Tick;
+struct Resume;
+struct Record;
+struct Eject;
+
+umlstate_scxml!("tests/scxml/player.scxml");
+
+#[derive(Default)]
+struct Deck {
+    media: bool,
+    log: Vec<&'static str>,
+    ticks: u32,
+}
+
+impl PlayerContext for Deck {
+    fn rewind(&mut self) {
+        self.log.push("rewind");
+    }
+
+    fn has_media(&self) -> bool {
+        self.media
+    }
+
+    fn spin_up(&mut self) {
+        self.log.push("spin up");
+    }
+
+    fn count_tick(&mut self) {
+        self.ticks += 1;
+    }
+}
+
+#[test]
+fn imported_machine() {
+    let mut m = Player::new(Deck::default());
+    m.enter();
+    assert!(m.state() == Some(PlayerState::Stopped));
+
+    assert_eq!(m.process(Play), ProcessResult::Unhandled);
+    m.context_mut().media = true;
+    assert_eq!(m.process(Play), ProcessResult::Handled);
+    assert!(m.state() == Some(PlayerState::Active));
+
+    m.process(Tick);
+    m.process(Tick);
+    assert_eq!(m.context().ticks, 2);
+
+    // The raised `Stop` is handled in the same run-to-completion step.
+    assert_eq!(m.process(Finish), ProcessResult::Handled);
+    assert!(m.state() == Some(PlayerState::Stopped));
+    assert_eq!(m.context().log, ["rewind", "spin up", "rewind"]);
+}
+
+#[test]
+fn history_and_regions() {
+    let mut m = Player::new(Deck {
+        media: true,
+        ..Deck::default()
+    });
+    m.enter();
+
+    m.process(Play);
+    m.process(Pause);
+    m.process(Stop);
+    m.context_mut().log.clear();
+
+    // Resuming re-enters `paused` without the initial transition.
+    m.process(Resume);
+    assert!(m.context().log.is_empty());
+    assert_eq!(m.process(Pause), ProcessResult::Unhandled);
+    assert_eq!(m.process(Play), ProcessResult::Handled);
+
+    m.process(Stop);
+    assert_eq!(m.process(Record), ProcessResult::Handled);
+    assert!(m.state() == Some(PlayerState::Recording));
+    m.process(Stop);
+
+    m.process(Play);
+    m.process(Eject);
+    assert!(m.state() == Some(PlayerState::Ejected));
+}
@@ -0,0 +1,124 @@
+use umlstate::*;
+
+mod common;
+use common::Log;
+
+#[derive(Clone)]
+struct Next;
+#[derive(Clone)]
+struct Interrupt;
+#[derive(Clone)]
+struct ResumeDeep;
+
+umlstate! {
+    machine Recorder {
+        fn log(&mut self, msg: &'static str);
+
+        state Idle {
+            entry / ctx.log("enter Idle");
+        }
+
+        state Running {
+            entry / ctx.log("enter Running");
+
+            state Boot;
+            state Work {
+                state Step1;
+                state Step2 {
+                    entry / ctx.log("enter Step2");
+                }
+
+                <*> => Step1;
+                Step1 + Next => Step2;
+            }
+
+            <*> => Boot;
+            Boot + Next => Work;
+        }
+
+        state Paused {
+            region Lights {
+                state Off;
+                state On;
+                <*> => Off;
+                Off + Next => On;
+            }
+            region Fan {
+                state Slow;
+                <*> => Slow;
+            }
+        }
+
+        <*> => Idle;
+        Idle + Next => Running;
+        Running + Interrupt => Paused;
+        Paused + ResumeDeep => Running.<H*>;
+    }
+}
+
+common::log_context!(RecorderContext::log);
+
+fn paused() -> Recorder<Log> {
+    let mut m = Recorder::new(Log::default());
+    m.enter();
+    m.process(Next);
+    m.process(Next);
+    m.process(Next);
+    m.process(Interrupt);
+    m.process(Next);
+    m
+}
+
+#[test]
+fn snapshot_captures_configuration() {
+    let m = paused();
+    let snapshot = m.snapshot();
+
+    assert_eq!(snapshot.state, Some(RecorderState::Paused));
+    assert!(snapshot.state_running.state.is_none());
+    assert!(snapshot.state_running.history.is_some());
+    assert!(snapshot.state_paused.state.is_some());
+}
+
+#[test]
+fn restore_skips_entry_behavior() {
+    let snapshot = paused().snapshot();
+    let mut m = Recorder::restore(Log::default(), snapshot.clone()).unwrap();
+
+    assert!(m.context().0.is_empty());
+    assert!(m.state() == Some(RecorderState::Paused));
+    assert!(m.snapshot() == snapshot);
+
+    // Deep history survives the round trip.
+    m.process(ResumeDeep);
+    assert_eq!(m.context().0, ["enter Running", "enter Step2"]);
+    assert_eq!(m.process(Next), ProcessResult::Unhandled);
+}
+
+#[test]
+fn restore_rejects_invalid_configuration() {
+    // An active region without an active state.
+    let mut snapshot = paused().snapshot();
+    snapshot.state_paused.state_lights.state = None;
+    let m = Recorder::restore(Log::default(), snapshot);
+    assert!(matches!(m, Err(Error::InvalidSnapshot)));
+
+    // An inactive composite state with an active sub-state.
+    let mut snapshot = paused().snapshot();
+    snapshot.state_running.state = snapshot.state_running.history.clone();
+    let m = Recorder::restore(Log::default(), snapshot);
+    assert!(matches!(m, Err(Error::InvalidSnapshot)));
+
+    // A machine that was never entered is fine.
+    let snapshot = Recorder::new(Log::default()).snapshot();
+    assert!(Recorder::restore(Log::default(), snapshot).is_ok());
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn serde_round_trip() {
+    let snapshot = paused().snapshot();
+    let json = serde_json::to_string(&snapshot).unwrap();
+    let restored: RecorderSnapshot = serde_json::from_str(&json).unwrap();
+    assert!(restored == snapshot);
+}
@@ -0,0 +1,169 @@
+use umlstate::*;
+
+mod common;
+use common::Log;
+
+#[derive(Clone)]
+struct Plug;
+#[derive(Clone)]
+struct Unplug;
+#[derive(Clone)]
+struct Charge;
+#[derive(Clone)]
+struct Full;
+#[derive(Clone)]
+struct Toggle;
+
+umlstate! {
+    machine Power {
+        fn log(&mut self, line: &'static str);
+
+        state Idle {
+            entry / ctx.log("enter Idle");
+            exit / ctx.log("exit Idle");
+        }
+        state Charging {
+            entry / ctx.log("enter Charging");
+        }
+        state Charged final;
+
+        <*> => Idle;
+        Idle + Charge => Charging;
+        Charging + Full => Charged;
+    }
+}
+
+umlstate! {
+    machine Charger {
+        fn note(&mut self, line: &'static str);
+
+        state Unplugged;
+        state Powered: Power {
+            entry / ctx.note("enter Powered");
+            exit / ctx.note("exit Powered");
+            forward Charge;
+            forward Full;
+        }
+        state Done;
+
+        <*> => Unplugged;
+        Unplugged + Plug => Powered;
+        Powered + Unplug => Unplugged;
+        Powered => Done;
+    }
+}
+
+umlstate! {
+    machine Blink {
+        state Off;
+        state On;
+
+        <*> => Off;
+        Off + Toggle => On;
+        On + Toggle => Off;
+    }
+}
+
+umlstate! {
+    machine Lamp {
+        state Dark;
+        state Lit: Blink {
+            forward Toggle;
+        }
+
+        <*> => Dark;
+        Dark + Plug => Lit;
+        Lit + Unplug => Dark;
+    }
+}
+
+umlstate! {
+    machine Switch {
+        state Up;
+        state Down;
+
+        <*> => Up;
+        Up + Toggle => Down;
+    }
+
+    machine Panel {
+        state Closed;
+        state Open: Switch {
+            forward Toggle;
+        }
+
+        <*> => Closed;
+        Closed + Plug => Open;
+    }
+}
+
+common::log_context!(ChargerContext::note, PowerContext::log);
+
+#[test]
+fn submachine_enter_exit() {
+    let mut m = Charger::new(Log::default());
+    m.enter();
+    m.process(Plug);
+    assert_eq!(m.context().0, ["enter Powered", "enter Idle"]);
+
+    m.process(Unplug);
+    assert_eq!(
+        m.context().0,
+        ["enter Powered", "enter Idle", "exit Idle", "exit Powered"]
+    );
+    assert!(m.state() == Some(ChargerState::Unplugged));
+}
+
+#[test]
+fn submachine_forwards_events() {
+    let mut m = Charger::new(Log::default());
+    m.enter();
+    assert_eq!(m.process(Charge), ProcessResult::Unhandled);
+
+    m.process(Plug);
+    assert_eq!(m.process(Charge), ProcessResult::Handled);
+    assert_eq!(m.context().0.last(), Some(&"enter Charging"));
+    assert_eq!(m.process(Charge), ProcessResult::Unhandled);
+}
+
+#[test]
+fn submachine_completion() {
+    let mut m = Charger::new(Log::default());
+    m.enter();
+    m.process(Plug);
+    m.process(Charge);
+    assert!(m.state() == Some(ChargerState::Powered));
+
+    m.process(Full);
+    assert!(m.state() == Some(ChargerState::Done));
+}
+
+#[test]
+fn submachine_snapshot() {
+    let mut m = Charger::new(Log::default());
+    m.enter();
+    m.process(Plug);
+    m.process(Charge);
+
+    let mut restored = Charger::restore(Log::default(), m.snapshot()).unwrap();
+    restored.process(Full);
+    assert!(restored.state() == Some(ChargerState::Done));
+}
+
+#[test]
+fn context_less_submachine() {
+    // Any context will do for a machine embedding context-less ones.
+    let mut m = Lamp::new(());
+    m.enter();
+    assert_eq!(m.process(Toggle), ProcessResult::Unhandled);
+    m.process(Plug);
+    assert_eq!(m.process(Toggle), ProcessResult::Handled);
+    assert_eq!(m.process(Toggle), ProcessResult::Handled);
+
+    // Machines of the same invocation are known to need none.
+    let mut m = Panel::new();
+    m.enter();
+    m.process(Plug);
+    assert_eq!(m.process(Toggle), ProcessResult::Handled);
+    assert_eq!(m.process(Toggle), ProcessResult::Unhandled);
+}
@@ -0,0 +1,143 @@
+use std::time::Duration;
+use umlstate::testing::VirtualClock;
+use umlstate::*;
+
+#[derive(Clone)]
+struct ChargeActive;
+#[derive(Clone)]
+struct ChargeInactive;
+#[derive(Clone)]
+struct Start;
+
+umlstate! {
+    machine Charger {
+        state WaitCharge;
+        state Charging;
+        state Fault;
+
+        <*> => WaitCharge;
+        WaitCharge + ChargeActive => Charging;
+        WaitCharge + after(Duration::from_secs(30)) => Fault;
+        Charging + ChargeInactive => WaitCharge;
+        Charging + after(Duration::from_secs(3600)) => Fault;
+    }
+}
+
+umlstate! {
+    machine Pump {
+        state Idle;
+        state Running;
+        state Cooldown;
+
+        <*> => Idle;
+        Idle + Start => Running;
+        Idle + after(Duration::from_secs(30)) => Cooldown;
+        Running + after(Duration::from_secs(3600)) => Cooldown;
+        Cooldown + after(Duration::from_secs(5)) => Idle;
+    }
+}
+
+/// Records what the machine asks of its timer.
+#[derive(Default)]
+struct Calls(Vec<String>);
+
+impl Timer for Calls {
+    fn arm(&mut self, id: TimerId, duration: Duration) {
+        self.0.push(format!("arm {} {:?}", id.index, duration));
+    }
+
+    fn cancel(&mut self, id: TimerId) {
+        self.0.push(format!("cancel {}", id.index));
+    }
+}
+
+fn take(m: &mut Charger<Calls>) -> Vec<String> {
+    std::mem::take(&mut m.timer_mut().0)
+}
+
+#[test]
+fn timeout() {
+    let mut m = Charger::new(Calls::default());
+    m.enter();
+    assert_eq!(take(&mut m), ["arm 0 30s"]);
+
+    m.process(ChargeActive);
+    assert_eq!(take(&mut m), ["cancel 0", "arm 1 3600s"]);
+
+    // A timer that fired after its state was left is ignored, and so is
+    // one armed before its state was last entered.
+    let first = TimerId {
+        index: 0,
+        generation: 1,
+    };
+    assert_eq!(m.process(first), ProcessResult::Unhandled);
+
+    m.process(ChargeInactive);
+    assert_eq!(take(&mut m), ["cancel 1", "arm 0 30s"]);
+    assert_eq!(m.process(first), ProcessResult::Unhandled);
+
+    let second = TimerId {
+        index: 0,
+        generation: 2,
+    };
+    assert_eq!(m.process(second), ProcessResult::Handled);
+    assert_eq!(take(&mut m), ["cancel 0"]);
+    assert!(m.state() == Some(ChargerState::Fault));
+}
+
+#[test]
+fn virtual_clock() {
+    let clock = VirtualClock::new();
+    let mut m = Pump::new(&clock);
+    m.enter();
+
+    clock.advance(&mut m, Duration::from_secs(25));
+    assert!(m.state() == Some(PumpState::Idle));
+
+    // `Cooldown` is entered at 30s and left at 35s, its own timeout being
+    // due by then too.
+    clock.advance(&mut m, Duration::from_secs(10));
+    assert!(m.state() == Some(PumpState::Idle));
+    assert_eq!(clock.now(), Duration::from_secs(35));
+    let timeout = TimerId {
+        index: 0,
+        generation: 2,
+    };
+    assert_eq!(clock.deadline(timeout), Some(Duration::from_secs(65)));
+
+    m.process(Start);
+    assert_eq!(clock.deadline(timeout), None);
+    clock.advance(&mut m, Duration::from_secs(3599));
+    assert!(m.state() == Some(PumpState::Running));
+    clock.advance(&mut m, Duration::from_secs(1));
+    assert!(m.state() == Some(PumpState::Cooldown));
+}
+
+#[test]
+fn restore_rearms_timers() {
+    let mut m = Charger::new(Calls::default());
+    m.enter();
+    m.process(ChargeActive);
+
+    let mut m = Charger::restore(Calls::default(), m.snapshot()).unwrap();
+    assert_eq!(take(&mut m), ["arm 1 3600s"]);
+
+    // The timer armed before the snapshot was taken is stale.
+    let before = TimerId {
+        index: 1,
+        generation: 1,
+    };
+    assert_eq!(m.process(before), ProcessResult::Unhandled);
+    let after = TimerId {
+        index: 1,
+        generation: 2,
+    };
+    assert_eq!(m.process(after), ProcessResult::Handled);
+    assert!(m.state() == Some(ChargerState::Fault));
+}
+
+#[test]
+fn timeout_label() {
+    assert!(Charger::<Calls>::PLANTUML
+        .contains("WaitCharge --> Fault : after(Duration::from_secs(30))"));
+}
@@ -0,0 +1,93 @@
+#![cfg(feature = "tracing")]
+
+use std::fmt::{Debug, Write};
+use std::sync::{Arc, Mutex};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use umlstate::*;
+
+#[derive(Clone)]
+struct Start;
+#[derive(Clone)]
+struct Stop;
+
+umlstate! {
+    machine Lamp {
+        state Off;
+        state On;
+
+        <*> => Off;
+        Off + Start => On;
+        On + Stop => Off;
+    }
+}
+
+/// Records spans and events as `name field=value...` lines.
+#[derive(Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<String>>>);
+
+struct Fields(String);
+
+impl Visit for Fields {
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        write!(self.0, " {}={:?}", field.name(), value).unwrap();
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        write!(self.0, " {}={}", field.name(), value).unwrap();
+    }
+}
+
+impl Subscriber for Recorder {
+    fn enabled(&self, _: &Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &Attributes<'_>) -> Id {
+        let mut fields = Fields(span.metadata().name().to_owned());
+        span.record(&mut fields);
+        self.0.lock().unwrap().push(fields.0);
+        Id::from_u64(1)
+    }
+
+    fn record(&self, _: &Id, _: &Record<'_>) {}
+
+    fn record_follows_from(&self, _: &Id, _: &Id) {}
+
+    fn event(&self, event: &Event<'_>) {
+        let mut fields = Fields(String::new());
+        event.record(&mut fields);
+        self.0.lock().unwrap().push(fields.0.trim_start().to_owned());
+    }
+
+    fn enter(&self, _: &Id) {}
+
+    fn exit(&self, _: &Id) {}
+}
+
+#[test]
+fn tracing() {
+    let recorder = Recorder::default();
+    let mut lamp = Lamp::new();
+
+    tracing::subscriber::with_default(recorder.clone(), || {
+        lamp.enter();
+        lamp.process(Start);
+        lamp.process(Start);
+    });
+
+    assert_eq!(
+        *recorder.0.lock().unwrap(),
+        [
+            "message=entry machine=Lamp state=Off",
+            "process machine=Lamp event=Start",
+            "message=transition machine=Lamp source=Off target=On event=Start",
+            "message=exit machine=Lamp state=Off",
+            "message=entry machine=Lamp state=On",
+            "process machine=Lamp event=Start",
+            "message=unhandled event machine=Lamp event=Start",
+        ]
+    );
+}